@@ -1,6 +1,6 @@
 // Buffer cache.
 //
-// The buffer cache is a linked list of buf structures holding
+// The buffer cache is a hash table of buf structures holding
 // cached copies of disk block contents. Caching disk blocks
 // in memory reduces the number of disk blocks used by multiple processes.
 //
@@ -11,66 +11,113 @@
 // * Do not use the buffer after calling brelse.
 // * Only one process at a time can use a buffer,
 //     so do not keep them longer than necessary.
+//
+// Each hash bucket has its own lock, so lookups of different
+// blocks do not contend. Buffers are allocated as blocks are
+// first used, up to a capacity set from the size of memory;
+// past it, a miss recycles the least recently used buffer that
+// nobody holds. Buffers the log has changed stay pinned until
+// they are installed, so every buffer nobody holds is clean, and
+// shrink() can free such buffers when memory runs short.
+//
+// readahead() starts reading a block into the cache without
+// waiting. The buffer is marked valid at once and stays busy on
+// the disk until the read completes; read() waits for that, and
+// reads the block again if the read ahead failed.
 
+use crate::fs::BSIZE;
+#[cfg(target_os = "none")]
 use crate::{
-    array,
-    fs::BSIZE,
+    array, blockdev, kalloc,
     param::NBUF,
     sleeplock::{SleepLock, SleepLockGuard},
     spinlock::Mutex,
-    virtio_disk::DISK,
-};
-use alloc::{
-    rc::{Rc, Weak},
-    sync::Arc,
-};
-use core::{
-    cell::RefCell,
-    ops::{Deref, DerefMut},
 };
+#[cfg(target_os = "none")]
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::{Deref, DerefMut};
+#[cfg(target_os = "none")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(target_os = "none")]
 pub static BCACHE: BCache = BCache::new();
 
+#[cfg(target_os = "none")]
+const NBUCKET: usize = 61; // prime, to spread block numbers
+
+// share of memory the cache may grow to, at least NBUF buffers.
+#[cfg(target_os = "none")]
+const MEM_SHARE: usize = 32;
+
+#[cfg(target_os = "none")]
 pub struct BCache {
-    buf: [SleepLock<Data>; NBUF],
-    // Linked list of all buffers, sorted by how recently the
-    // buffer was used.
-    lru: Mutex<Lru>,
+    buckets: [Mutex<Vec<Arc<Buf>>>; NBUCKET],
+    nbuf: AtomicUsize,  // buffers allocated
+    cap: AtomicUsize,   // buffers to allocate before recycling
+    clock: AtomicUsize, // ticks on every use, for LRU
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    readaheads: AtomicUsize,
+}
+
+// Counters of the cache, for procdump.
+#[cfg(target_os = "none")]
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub readaheads: usize,
+    pub nbuf: usize,
+    pub cap: usize,
 }
 
+// data must come first and be aligned for align_to().
+#[repr(C, align(8))]
 pub struct Data {
     pub data: [u8; BSIZE],
-    pub disk: bool, // does disk "own" buf?
-    blockno: u32,   // sync with Meta
-    dev: u32,       // sync with Meta
-    valid: bool,    // has data been read from disk?
+    pub disk: bool,  // does disk "own" buf?
+    pub error: bool, // did the disk fail its last request?
+    blockno: u32,    // sync with Buf
+    dev: u32,        // sync with Buf
+    #[cfg(target_os = "none")]
+    valid: bool, // has data been read from disk?
 }
 
-pub struct Lru {
-    head: Option<Rc<Buf>>,
-    tail: Option<Weak<Buf>>,
-    n: usize,
+#[cfg(target_os = "none")]
+struct Buf {
+    // changed only while the buffer is in no bucket and unshared.
+    dev: u32,
+    blockno: u32,
+    last_use: AtomicUsize,
+    data: SleepLock<Data>,
 }
 
-pub struct Buf {
-    data: Arc<&'static SleepLock<Data>>,
-    meta: RefCell<Meta>,
-}
+// A buffer being read ahead, for the disk driver to keep alive
+// until the read completes.
+#[cfg(target_os = "none")]
+pub struct Ahead(Arc<Buf>);
 
-#[derive(Default)]
-struct Meta {
-    dev: u32,
-    blockno: u32,
-    next: Option<Rc<Buf>>,
-    prev: Option<Weak<Buf>>,
+#[cfg(target_os = "none")]
+impl Ahead {
+    pub fn data(&self) -> *mut Data {
+        self.0.data.as_ptr()
+    }
 }
 
+#[cfg(target_os = "none")]
 pub struct BufGuard {
     data_guard: Option<SleepLockGuard<'static, Data>>,
-    _ref: Option<Arc<&'static SleepLock<Data>>>,
-    _link: Option<Rc<Buf>>,
+    // keeps the buffer, and so data_guard's referent, alive;
+    // its count is the buffer's reference count.
+    buf: Option<Arc<Buf>>,
+    // wait for the disk on release; not when an Ahead owns the
+    // read, which the next read() waits for instead.
+    wait_on_drop: bool,
 }
 
+#[cfg(target_os = "none")]
 impl Deref for BufGuard {
     type Target = SleepLockGuard<'static, Data>;
     fn deref(&self) -> &Self::Target {
@@ -78,26 +125,61 @@ impl Deref for BufGuard {
     }
 }
 
+#[cfg(target_os = "none")]
 impl DerefMut for BufGuard {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.data_guard.as_mut().unwrap()
     }
 }
 
+#[cfg(target_os = "none")]
 impl BufGuard {
+    // Lock the buffer. Must not hold a bucket lock, as this may sleep.
+    fn new(buf: Arc<Buf>) -> Self {
+        // Safety: the guard is dropped before buf, see Drop.
+        let lock: &'static SleepLock<Data> = unsafe { &(*Arc::as_ptr(&buf)).data };
+        Self {
+            data_guard: Some(lock.lock()),
+            buf: Some(buf),
+            wait_on_drop: true,
+        }
+    }
+
     // Write buf's content to disk. Must be locked.
-    pub fn write(&mut self) {
-        if self.holding() {
+    pub fn write(&mut self) -> Result<(), ()> {
+        if !self.holding() {
+            panic!("bwrite");
+        }
+        blockdev::get(self.dev()).rw(self.data_guard.as_mut().unwrap(), true)
+    }
+
+    // Start writing buf's content to disk, and return without
+    // waiting for it. The write is done once wait() returns or
+    // the buffer is released.
+    pub fn start_write(&mut self) -> Result<(), ()> {
+        if !self.holding() {
             panic!("bwrite");
         }
-        self.data_guard = DISK.rw(self.data_guard.take(), true);
+        blockdev::get(self.dev()).start_rw(self.data_guard.as_mut().unwrap(), true)
+    }
+
+    // Wait for the disk to be done with the buffer. Fails if it
+    // failed the last request, even one done before.
+    pub fn wait(&self) -> Result<(), ()> {
+        if self.disk {
+            blockdev::get(self.dev()).wait(self)?;
+        }
+        if self.error {
+            return Err(());
+        }
+        Ok(())
     }
 
     pub fn pin(&self) {
-        unsafe { Arc::increment_strong_count(Arc::as_ptr(self._ref.as_ref().unwrap())) }
+        unsafe { Arc::increment_strong_count(Arc::as_ptr(self.buf.as_ref().unwrap())) }
     }
     pub fn unpin(&self) {
-        unsafe { Arc::decrement_strong_count(Arc::as_ptr(self._ref.as_ref().unwrap())) }
+        unsafe { Arc::decrement_strong_count(Arc::as_ptr(self.buf.as_ref().unwrap())) }
     }
 
     pub fn align_to<U>(&self) -> &[U] {
@@ -112,161 +194,53 @@ impl BufGuard {
     }
 }
 
+#[cfg(target_os = "none")]
 impl Drop for BufGuard {
     fn drop(&mut self) {
         if !self.holding() {
             panic!("drop - brelse");
         }
-        {
-            self.data_guard.take(); // unlock sleep
-            self._ref.take(); // Decrement refcnt
+        if self.wait_on_drop {
+            // a write started and not waited for is not checked.
+            self.wait().ok();
         }
-        BCACHE.lru.lock().relse(self._link.take().unwrap())
+        let buf = self.buf.take().unwrap();
+        buf.last_use.store(
+            BCACHE.clock.fetch_add(1, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.data_guard.take(); // unlock sleep
+        drop(buf); // Decrement refcnt
     }
 }
 
+#[cfg(target_os = "none")]
 impl Buf {
-    fn new(data: Arc<&'static SleepLock<Data>>) -> Self {
+    fn new() -> Self {
         Self {
-            data,
-            meta: Default::default(),
-        }
-    }
-}
-
-impl Lru {
-    const fn new() -> Self {
-        Self {
-            head: None,
-            tail: None,
-            n: 0,
-        }
-    }
-
-    fn add(&mut self, data: &'static SleepLock<Data>) {
-        let data = Arc::new(data);
-        let buf = Rc::new(Buf::new(data));
-        match self.tail.take() {
-            Some(old_tail) => {
-                old_tail.upgrade().unwrap().meta.borrow_mut().next = Some(Rc::clone(&buf));
-                buf.meta.borrow_mut().prev = Some(old_tail);
-            }
-            None => {
-                self.head = Some(Rc::clone(&buf));
-            }
-        }
-        self.tail = Some(Rc::downgrade(&buf));
-        self.n += 1;
-    }
-
-    fn get(&self, dev: u32, blockno: u32) -> BufGuard {
-        // Is the block already cached?
-        for b in self.iter() {
-            if b.meta.borrow().dev == dev && b.meta.borrow().blockno == blockno {
-                return BufGuard {
-                    data_guard: Some(b.data.lock()),
-                    _ref: Some(Arc::clone(&b.data)),
-                    _link: Some(b), // do not touch outside Mutex
-                };
-            }
-        }
-
-        // Not cached.
-        // Recycle the least recently used (LRU) unused buffer
-        for b in self.iter().rev() {
-            if Arc::strong_count(&b.data) == 1 {
-                b.meta.borrow_mut().dev = dev;
-                b.meta.borrow_mut().blockno = blockno;
-                let mut sleeplock = b.data.lock();
-                sleeplock.valid = false;
-                sleeplock.blockno = blockno;
-                sleeplock.dev = dev;
-                return BufGuard {
-                    data_guard: Some(sleeplock),
-                    _ref: Some(Arc::clone(&b.data)),
-                    _link: Some(b), // do not touch outside Mutex
-                };
-            }
-        }
-        panic!("no buffers");
-    }
-
-    fn relse(&mut self, buf: Rc<Buf>) {
-        if Arc::strong_count(&buf.data) == 1 {
-            // detach
-            let next = buf.meta.borrow_mut().next.take();
-            let prev = buf.meta.borrow_mut().prev.take();
-            if let Some(ref n) = next {
-                n.meta.borrow_mut().prev = prev.clone();
-            }
-            if let Some(ref p) = prev {
-                p.upgrade().unwrap().meta.borrow_mut().next = next.clone();
-            }
-
-            // attach
-            match self.head.take() {
-                Some(old_head) => {
-                    old_head.meta.borrow_mut().prev = Some(Rc::downgrade(&buf));
-                    buf.meta.borrow_mut().next = Some(old_head);
-                }
-                None => {
-                    self.tail = Some(Rc::downgrade(&buf));
-                }
-            }
-            self.head = Some(buf);
-        }
-    }
-
-    fn iter(&self) -> Iter {
-        Iter {
-            head: self.head.clone(),
-            tail: self.tail.as_ref().and_then(|tail| tail.upgrade()),
-        }
-    }
-}
-
-struct Iter {
-    head: Option<Rc<Buf>>,
-    tail: Option<Rc<Buf>>,
-}
-
-impl Iterator for Iter {
-    type Item = Rc<Buf>;
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.head.take() {
-            Some(old_head) => {
-                self.head = old_head.meta.borrow().next.clone();
-                Some(old_head)
-            }
-            None => None,
+            dev: 0,
+            blockno: 0,
+            last_use: AtomicUsize::new(0),
+            data: SleepLock::new(Data::new(0, 0), "buffer"),
         }
     }
-}
 
-impl DoubleEndedIterator for Iter {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        match self.tail.take() {
-            Some(old_tail) => {
-                self.tail = old_tail
-                    .meta
-                    .borrow()
-                    .prev
-                    .as_ref()
-                    .and_then(|p| p.upgrade());
-                Some(old_tail)
-            }
-            None => None,
-        }
+    // Nobody but the bucket holds it.
+    fn unused(self: &Arc<Self>) -> bool {
+        Arc::strong_count(self) == 1
     }
 }
 
 impl Data {
-    const fn new() -> Self {
+    // A buffer for block blockno of device dev, not yet read.
+    pub const fn new(dev: u32, blockno: u32) -> Self {
         Self {
             data: [0; BSIZE],
             disk: false,
-            blockno: 0,
-            dev: 0,
+            error: false,
+            blockno,
+            dev,
+            #[cfg(target_os = "none")]
             valid: false,
         }
     }
@@ -293,28 +267,196 @@ impl DerefMut for Data {
     }
 }
 
+#[cfg(target_os = "none")]
 impl BCache {
     const fn new() -> Self {
         Self {
-            buf: array![SleepLock::new(Data::new(), "buffer"); NBUF],
-            lru: Mutex::new(Lru::new(), "bcache"),
+            buckets: array![Mutex::new(Vec::new(), "bcache"); NBUCKET],
+            nbuf: AtomicUsize::new(0),
+            cap: AtomicUsize::new(NBUF),
+            clock: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+            readaheads: AtomicUsize::new(0),
         }
     }
 
+    fn bucket(dev: u32, blockno: u32) -> &'static Mutex<Vec<Arc<Buf>>> {
+        &BCACHE.buckets[(dev as usize * 31 + blockno as usize) % NBUCKET]
+    }
+
+    // Look through buffer cache for block on device dev.
+    // If not found, allocate a buffer.
+    // In either case, return locked buffer.
+    fn get(&self, dev: u32, blockno: u32) -> BufGuard {
+        let bucket = Self::bucket(dev, blockno);
+
+        // Is the block already cached?
+        let cached = |b: &&Arc<Buf>| b.dev == dev && b.blockno == blockno;
+        let hit = bucket.lock().iter().find(cached).cloned();
+        if let Some(b) = hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return BufGuard::new(b);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Not cached. Take a new buffer while under capacity, else
+        // recycle the least recently used unused one.
+        let mut buf = None;
+        if self.nbuf.load(Ordering::Relaxed) >= self.cap.load(Ordering::Relaxed) {
+            buf = self.recycle();
+        }
+        let mut buf = buf.unwrap_or_else(|| {
+            self.nbuf.fetch_add(1, Ordering::Relaxed);
+            Arc::new(Buf::new())
+        });
+        {
+            let b = Arc::get_mut(&mut buf).unwrap();
+            b.dev = dev;
+            b.blockno = blockno;
+            let data = b.data.get_mut();
+            data.valid = false;
+            data.error = false;
+            data.blockno = blockno;
+            data.dev = dev;
+        }
+
+        let mut guard = bucket.lock();
+        // Someone else may have cached it meanwhile.
+        if let Some(b) = guard.iter().find(cached).cloned() {
+            drop(guard);
+            self.nbuf.fetch_sub(1, Ordering::Relaxed);
+            drop(buf);
+            return BufGuard::new(b);
+        }
+        guard.push(Arc::clone(&buf));
+        drop(guard);
+        BufGuard::new(buf)
+    }
+
+    // Take the least recently used unused buffer out of the cache.
+    fn recycle(&self) -> Option<Arc<Buf>> {
+        for _ in 0..3 {
+            let mut victim = None;
+            for (i, bucket) in self.buckets.iter().enumerate() {
+                for b in bucket.lock().iter().filter(|b| b.unused()) {
+                    let t = b.last_use.load(Ordering::Relaxed);
+                    if victim.map_or(true, |(_, _, lt)| t < lt) {
+                        victim = Some((i, Arc::as_ptr(b), t));
+                    }
+                }
+            }
+            let (i, ptr, _) = victim?;
+            let mut bucket = self.buckets[i].lock();
+            // it may have been used since.
+            if let Some(pos) = bucket
+                .iter()
+                .position(|b| Arc::as_ptr(b) == ptr && b.unused())
+            {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                return Some(bucket.swap_remove(pos));
+            }
+        }
+        None
+    }
+
+    // Free up to n unused buffers, for when memory runs short.
+    // Skips busy buckets, so that it is safe to call from the
+    // allocator with a bucket lock held. Returns the number freed.
+    pub fn shrink(&self, n: usize) -> usize {
+        let mut count = 0;
+        for bucket in self.buckets.iter() {
+            if let Some(mut bucket) = bucket.try_lock() {
+                while count < n {
+                    match bucket.iter().position(|b| b.unused()) {
+                        Some(pos) => drop(bucket.swap_remove(pos)),
+                        None => break,
+                    }
+                    count += 1;
+                }
+            }
+        }
+        self.nbuf.fetch_sub(count, Ordering::Relaxed);
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+        count
+    }
+
+    // Drop the cached blocks of dev, for when it goes away. Fails
+    // if any of them is in use.
+    pub fn invalidate(&self, dev: u32) -> Result<(), ()> {
+        let mut count = 0;
+        let mut res = Ok(());
+        for bucket in self.buckets.iter() {
+            let mut bucket = bucket.lock();
+            let n = bucket.len();
+            bucket.retain(|b| b.dev != dev || !b.unused());
+            count += n - bucket.len();
+            if bucket.iter().any(|b| b.dev == dev) {
+                res = Err(());
+            }
+        }
+        self.nbuf.fetch_sub(count, Ordering::Relaxed);
+        res
+    }
+
     // Return a locked buf with the contents of the indicated block.
-    pub fn read(&self, dev: u32, blockno: u32) -> BufGuard {
-        let mut b = self.lru.lock().get(dev, blockno);
+    // Fails if the device cannot read it.
+    pub fn read(&self, dev: u32, blockno: u32) -> Result<BufGuard, ()> {
+        let mut b = self.get(dev, blockno);
+        // it may still be being read ahead; if that failed, the
+        // block is read again.
+        if b.wait().is_err() {
+            b.valid = false;
+        }
         if !b.valid {
-            b.data_guard = DISK.rw(b.data_guard.take(), false);
+            blockdev::get(dev).rw(b.data_guard.as_mut().unwrap(), false)?;
             b.valid = true;
         }
-        b
+        Ok(b)
+    }
+
+    // Start reading the indicated block into the cache, if it is
+    // not there yet, and return without waiting for the disk.
+    // Returns false if the disk cannot take another request.
+    pub fn readahead(&self, dev: u32, blockno: u32) -> bool {
+        let cached = |b: &Arc<Buf>| b.dev == dev && b.blockno == blockno;
+        if Self::bucket(dev, blockno).lock().iter().any(cached) {
+            return true;
+        }
+        let mut b = self.get(dev, blockno);
+        if b.valid {
+            return true;
+        }
+        b.disk = true;
+        b.error = false;
+        let ahead = Ahead(Arc::clone(b.buf.as_ref().unwrap()));
+        if blockdev::get(dev).start(ahead).is_err() {
+            b.disk = false;
+            return false;
+        }
+        b.valid = true;
+        b.wait_on_drop = false;
+        self.readaheads.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            readaheads: self.readaheads.load(Ordering::Relaxed),
+            nbuf: self.nbuf.load(Ordering::Relaxed),
+            cap: self.cap.load(Ordering::Relaxed),
+        }
     }
 }
 
+#[cfg(target_os = "none")]
 pub fn init() {
-    let mut lru = BCACHE.lru.lock();
-    for b in BCACHE.buf.iter() {
-        lru.add(b);
-    }
+    // buddy blocks are powers of two.
+    let bufsize = core::mem::size_of::<Buf>().next_power_of_two();
+    let cap = kalloc::heap_size() / MEM_SHARE / bufsize;
+    BCACHE.cap.store(cap.max(NBUF), Ordering::Relaxed);
 }
@@ -0,0 +1,113 @@
+// Block devices.
+//
+// The buffer cache reads and writes disk blocks through the
+// BlockDevice registered under their device number. Drivers
+// register their devices at boot, and numbers are handed out
+// from ROOTDEV on, so the first device registered holds the
+// root file system.
+
+use crate::bio::Data;
+#[cfg(target_os = "none")]
+use crate::{
+    bio::Ahead,
+    param::{NBDEV, ROOTDEV},
+    spinlock::Mutex,
+};
+
+#[allow(clippy::result_unit_err)]
+pub trait BlockDevice: Sync {
+    // Queue a read or write of the block in b, and return without
+    // waiting for it. b must stay locked until wait() returns.
+    // Fails for a block past the end, or a write to a read-only
+    // device.
+    fn start_rw(&self, b: &mut Data, write: bool) -> Result<(), ()>;
+
+    // Wait until the read or write of the block in data is done.
+    // Fails if the device failed it.
+    fn wait(&self, data: &Data) -> Result<(), ()>;
+
+    // Start reading the block of a buffer nobody holds, without
+    // waiting for it; see Bcache::readahead(). Fails if the
+    // device cannot take it.
+    #[cfg(target_os = "none")]
+    fn start(&self, ahead: Ahead) -> Result<(), Ahead> {
+        Err(ahead)
+    }
+
+    // Read or write the block in b, and wait for it.
+    fn rw(&self, b: &mut Data, write: bool) -> Result<(), ()> {
+        self.start_rw(b, write)?;
+        self.wait(b)
+    }
+
+    // Wait until the writes done so far are stable.
+    fn flush(&self) {}
+
+    // Tell the device that blocks [blockno, blockno + n) are not
+    // in use, if it cares. Fails if they are past the end.
+    fn discard(&self, _blockno: u32, _n: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    // Size of the device in blocks.
+    fn capacity(&self) -> u32;
+
+    fn readonly(&self) -> bool {
+        false
+    }
+
+    // Hold back and send requests; see Plug.
+    fn plug(&self) {}
+    fn unplug(&self) {}
+}
+
+// Holds back requests to a device while it lives, so that
+// adjacent blocks started one by one can be merged. Waiting
+// for one of them sends them all.
+#[cfg(target_os = "none")]
+pub struct Plug(&'static dyn BlockDevice);
+
+#[cfg(target_os = "none")]
+impl Drop for Plug {
+    fn drop(&mut self) {
+        self.0.unplug();
+    }
+}
+
+#[cfg(target_os = "none")]
+static DEVICES: Mutex<[Option<&'static dyn BlockDevice>; NBDEV]> =
+    Mutex::new([None; NBDEV], "blockdev");
+
+// Add a device; returns its device number, or None if there
+// are too many.
+#[cfg(target_os = "none")]
+pub fn register(bdev: &'static dyn BlockDevice) -> Option<u32> {
+    let mut devices = DEVICES.lock();
+    let i = devices.iter().position(|d| d.is_none())?;
+    devices[i] = Some(bdev);
+    Some(ROOTDEV + i as u32)
+}
+
+// The device with device number dev, if there is one.
+#[cfg(target_os = "none")]
+pub fn lookup(dev: u32) -> Option<&'static dyn BlockDevice> {
+    DEVICES
+        .lock()
+        .get(dev.wrapping_sub(ROOTDEV) as usize)
+        .copied()
+        .flatten()
+}
+
+// The device with device number dev.
+#[cfg(target_os = "none")]
+pub fn get(dev: u32) -> &'static dyn BlockDevice {
+    lookup(dev).unwrap_or_else(|| panic!("blockdev: no device {}", dev))
+}
+
+// Hold back requests to dev until the returned Plug is dropped.
+#[cfg(target_os = "none")]
+pub fn plug(dev: u32) -> Plug {
+    let bdev = get(dev);
+    bdev.plug();
+    Plug(bdev)
+}
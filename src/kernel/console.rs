@@ -46,8 +46,10 @@ impl Device for Mutex<Cons> {
     //
     // user read()s from the console go here.
     // copy (up to) a whole input line to dst.
+    // with nonblock, return what has been typed so far
+    // rather than waiting for the rest of the line.
     //
-    fn read(&self, mut dst: VirtAddr, mut n: usize) -> Result<usize, ()> {
+    fn read(&self, mut dst: VirtAddr, mut n: usize, nonblock: bool) -> Result<usize, ()> {
         let mut cons_guard = self.lock();
         let p = CPUS.my_proc().unwrap();
 
@@ -59,6 +61,9 @@ impl Device for Mutex<Cons> {
                 if p.inner.lock().killed {
                     return Err(());
                 }
+                if nonblock {
+                    return if n < target { Ok(target - n) } else { Err(()) };
+                }
                 cons_guard = p.sleep(&cons_guard.r as *const _ as usize, cons_guard);
             }
             let c = cons_guard.buf[cons_guard.r.0 % INPUT_BUF_SIZE];
@@ -95,7 +100,7 @@ impl Device for Mutex<Cons> {
     //
     // user write()s to the console go here.
     //
-    fn write(&self, src: VirtAddr, n: usize) -> Result<usize, ()> {
+    fn write(&self, src: VirtAddr, n: usize, _nonblock: bool) -> Result<usize, ()> {
         for i in 0..n {
             let p = CPUS.my_proc().unwrap();
             let mut c = 0;
@@ -0,0 +1,132 @@
+// Crash-consistency testing.
+//
+// Built with the crashtest feature, the kernel runs a fixed file
+// system workload in the first process instead of going to user
+// space, and counts the writes that reach the disk layer. The boot
+// block of fs.img, which is otherwise unused, tells it what to do:
+//
+//   MAGIC, CRASH, n: let n writes through, then power off as if
+//                    the power failed before write n + 1.
+//   MAGIC, RECOVER:  only recover the log, then power off.
+//   anything else:   run the whole workload, print the number of
+//                    writes it took, then power off.
+//
+// src/mkfs/crashtest.rs boots the kernel this way once for every
+// crash point, and checks the file system after each recovery.
+use crate::{
+    bio::BCACHE,
+    fs::{self, Path},
+    log::LOG,
+    memlayout::TEST,
+    param::ROOTDEV,
+    stat::IType,
+    vm::VirtAddr,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Layout of the boot block: MAGIC, mode: u32, n: u32 (little endian).
+pub const MAGIC: &[u8; 8] = b"crashtst";
+pub const CRASH: u32 = 1;
+pub const RECOVER: u32 = 2;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+// Called by the disk layer before it issues a write.
+// Does not return once the crash point is reached.
+pub fn before_write() {
+    if WRITES.fetch_add(1, Ordering::SeqCst) >= LIMIT.load(Ordering::SeqCst) {
+        poweroff();
+    }
+}
+
+// Stop qemu through its test device.
+fn poweroff() -> ! {
+    unsafe {
+        (TEST as *mut u32).write_volatile(0x5555); // FINISHER_PASS
+    }
+    loop {}
+}
+
+// Called by the first process once the file system is up.
+pub fn run() -> ! {
+    let bp = BCACHE.read(ROOTDEV, 0).unwrap();
+    let magic = &bp[0..8] == MAGIC;
+    let mode = u32::from_le_bytes(bp[8..12].try_into().unwrap());
+    let n = u32::from_le_bytes(bp[12..16].try_into().unwrap());
+    drop(bp);
+
+    match mode {
+        CRASH if magic => {
+            LIMIT.store(n as usize, Ordering::SeqCst);
+            workload();
+            LOG.sync();
+            println!("crashtest: crash point {} not reached", n);
+        }
+        RECOVER if magic => {
+            // the first use of the log recovers it.
+            LOG.sync();
+            println!("crashtest: recovered");
+        }
+        _ => {
+            workload();
+            LOG.sync();
+            println!("crashtest: {} writes", WRITES.load(Ordering::SeqCst));
+        }
+    }
+    poweroff()
+}
+
+// Run f as one file system operation.
+fn op<T>(f: impl FnOnce() -> T) -> T {
+    LOG.begin_op();
+    let res = f();
+    LOG.end_op();
+    res
+}
+
+fn create(path: &str, itype: IType) {
+    op(|| fs::create(Path::new(path), itype, 0, 0, true).map(|_| ())).expect("crashtest: create");
+}
+
+// Append len bytes of c to the file at path, a few blocks per operation.
+fn append(path: &str, len: usize, c: u8) {
+    let buf = [c; 512];
+    let mut done = 0;
+    while done < len {
+        let n = core::cmp::min(len - done, buf.len());
+        op(|| {
+            let (_, ip) = Path::new(path).namei().expect("crashtest: namei");
+            let mut guard = ip.lock();
+            let off = guard.size();
+            guard
+                .write(VirtAddr::Kernel(buf.as_ptr() as usize), off, n)
+                .expect("crashtest: write");
+        });
+        done += n;
+    }
+}
+
+// Exercise creation, growth, links, renames across directories,
+// truncation and removal, some of them in the same transaction.
+fn workload() {
+    create("/ct", IType::Dir);
+    for (i, name) in ["/ct/f0", "/ct/f1", "/ct/f2", "/ct/f3"].iter().enumerate() {
+        create(name, IType::File);
+        append(name, (i + 1) * 1500, b'a' + i as u8);
+    }
+    op(|| fs::link(Path::new("/ct/f0"), Path::new("/ct/l0"))).expect("crashtest: link");
+    op(|| fs::rename(Path::new("/ct/f1"), Path::new("/f1"))).expect("crashtest: rename");
+    create("/ct/sub", IType::Dir);
+    create("/ct/sub/g", IType::File);
+    append("/ct/sub/g", 3000, b'g');
+    op(|| fs::rename(Path::new("/ct/sub"), Path::new("/sub"))).expect("crashtest: rename dir");
+    op(|| fs::rename(Path::new("/ct/f0"), Path::new("/ct/f2"))).expect("crashtest: replace");
+    op(|| {
+        let (_, ip) = Path::new("/ct/f3").namei().expect("crashtest: namei");
+        ip.lock().truncate(700).expect("crashtest: truncate");
+    });
+    op(|| fs::unlink(Path::new("/ct/l0"))).expect("crashtest: unlink");
+    op(|| fs::unlink(Path::new("/sub/g"))).expect("crashtest: unlink");
+    op(|| fs::unlink(Path::new("/sub"))).expect("crashtest: unlink dir");
+}
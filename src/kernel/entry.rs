@@ -8,7 +8,11 @@ pub unsafe extern "C" fn _entry() -> ! {
     // stack0 is declared in kernel/src/start.rs
     // with a 4096-byte stack per CPU.
     // sp = stack0 + (hartid * 4096)
+    // qemu passes the device tree in a1; keep it in FDT
+    // (kernel/src/fdt.rs) before a1 is reused.
     asm!(
+        "la t0, FDT",
+        "sd a1, 0(t0)",
         "la sp, STACK0",
         "li a0, 4096 * 4",
         "csrr a1, mhartid",
@@ -1,6 +1,7 @@
 use crate::{
     elf::{self, ElfHdr, ProgHdr},
-    fs::{IData, Path},
+    fcntl::advice,
+    fs::{IData, Path, ReadAhead},
     log::LOG,
     param::MAXARG,
     proc::{Process, CPUS},
@@ -41,11 +42,13 @@ impl Uvm {
         }
 
         let mut i: usize = 0;
+        let mut ra = ReadAhead::new(advice::SEQUENTIAL);
 
         while i < sz {
             match self.walkaddr(va + i) {
                 Some(pa) => {
                     let n = if sz - i < PGSIZE { sz - i } else { PGSIZE };
+                    ip_guard.readahead(&mut ra, (offset + i) as u32, n);
                     if Ok(n) != ip_guard.read(From::from(pa), (offset + i) as u32, n) {
                         return Err(());
                     }
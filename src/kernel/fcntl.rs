@@ -1,9 +1,38 @@
-mod omode {
-    pub const _RDONLY: usize = 0x000;
+pub mod omode {
+    pub const RDONLY: usize = 0x000;
     pub const WRONLY: usize = 0x001;
     pub const RDWR: usize = 0x002;
+    pub const APPEND: usize = 0x004;
+    pub const EXCL: usize = 0x080;
     pub const CREATE: usize = 0x200;
     pub const TRUNC: usize = 0x400;
+    pub const NONBLOCK: usize = 0x800;
+    pub const DIRECTORY: usize = 0x1000;
+}
+
+// How a program will read a file, for fadvise.
+pub mod advice {
+    pub const NORMAL: usize = 0; // sequential reads are detected
+    pub const RANDOM: usize = 1; // no readahead
+    pub const SEQUENTIAL: usize = 2; // read ahead the largest window
+    pub const WILLNEED: usize = 3; // start reading the range now
+    pub const DONTNEED: usize = 4; // the range will not be read soon
+}
+
+// fcntl commands.
+pub mod fcntl {
+    pub const SETPIPE_SZ: usize = 1031; // set the capacity of a pipe to arg bytes; returns it
+    pub const GETPIPE_SZ: usize = 1032; // returns the capacity of a pipe
+}
+
+// A write of at most PIPE_BUF bytes to a pipe is atomic: it is not
+// interleaved with other writes.
+pub const PIPE_BUF: usize = 512;
+
+// ioctl requests to the loop control device.
+pub mod ioctl {
+    pub const LOOP_SET_FD: usize = 0x4c00; // bind the file open as arg; returns the device number
+    pub const LOOP_CLR_FD: usize = 0x4c01; // unbind the loop device numbered arg
 }
 
 pub struct OMode {
@@ -11,6 +40,10 @@ pub struct OMode {
     write: bool,
     truncate: bool,
     create: bool,
+    append: bool,
+    excl: bool,
+    directory: bool,
+    nonblock: bool,
 }
 
 impl OMode {
@@ -20,6 +53,10 @@ impl OMode {
             write: false,
             truncate: false,
             create: false,
+            append: false,
+            excl: false,
+            directory: false,
+            nonblock: false,
         }
     }
 
@@ -39,6 +76,22 @@ impl OMode {
         self.create = create;
         self
     }
+    fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+    fn excl(&mut self, excl: bool) -> &mut Self {
+        self.excl = excl;
+        self
+    }
+    fn directory(&mut self, directory: bool) -> &mut Self {
+        self.directory = directory;
+        self
+    }
+    pub fn nonblock(&mut self, nonblock: bool) -> &mut Self {
+        self.nonblock = nonblock;
+        self
+    }
 
     pub fn from_usize(bits: usize) -> Self {
         let mut mode = Self::new();
@@ -46,7 +99,11 @@ impl OMode {
             .read(bits & omode::WRONLY == 0)
             .write(bits & omode::WRONLY != 0 || bits & omode::RDWR != 0)
             .create(bits & omode::CREATE != 0)
-            .truncate(bits & omode::TRUNC != 0);
+            .truncate(bits & omode::TRUNC != 0)
+            .append(bits & omode::APPEND != 0)
+            .excl(bits & omode::EXCL != 0)
+            .directory(bits & omode::DIRECTORY != 0)
+            .nonblock(bits & omode::NONBLOCK != 0);
         mode
     }
 
@@ -69,4 +126,21 @@ impl OMode {
     pub fn is_rdonly(&self) -> bool {
         self.read && !self.write
     }
+
+    pub fn is_append(&self) -> bool {
+        self.append
+    }
+
+    // O_EXCL only has a meaning together with O_CREATE.
+    pub fn is_excl(&self) -> bool {
+        self.create && self.excl
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.directory
+    }
+
+    pub fn is_nonblock(&self) -> bool {
+        self.nonblock
+    }
 }
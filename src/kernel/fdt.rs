@@ -0,0 +1,98 @@
+// Flattened device tree, as qemu hands it over in a1 at boot.
+// Only what the kernel needs is looked up, and before paging
+// is on, while the tree is still where qemu put it.
+
+use core::{ptr, slice, str};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+// structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+// header offsets
+const OFF_DT_STRUCT: usize = 8;
+const OFF_DT_STRINGS: usize = 12;
+
+// Physical address of the tree, saved by _entry.
+#[no_mangle]
+pub static mut FDT: usize = 0;
+
+pub struct Fdt {
+    base: usize,
+}
+
+// big-endian word at addr
+fn be32(addr: usize) -> u32 {
+    u32::from_be(unsafe { ptr::read_unaligned(addr as *const u32) })
+}
+
+// NUL-terminated string at addr
+fn cstr(addr: usize) -> &'static [u8] {
+    let mut n = 0;
+    while unsafe { *((addr + n) as *const u8) } != 0 {
+        n += 1;
+    }
+    unsafe { slice::from_raw_parts(addr as *const u8, n) }
+}
+
+impl Fdt {
+    // The tree qemu passed, if there is a valid one.
+    pub fn get() -> Option<Self> {
+        let base = unsafe { FDT };
+        if base == 0 || be32(base) != FDT_MAGIC {
+            return None;
+        }
+        Some(Self { base })
+    }
+
+    // Value of property name of the top-level node, e.g. "chosen".
+    pub fn prop(&self, node: &str, name: &str) -> Option<&'static [u8]> {
+        let strings = self.base + be32(self.base + OFF_DT_STRINGS) as usize;
+        let mut pos = self.base + be32(self.base + OFF_DT_STRUCT) as usize;
+        let mut depth = 0;
+        let mut found = false;
+        loop {
+            let token = be32(pos);
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let s = cstr(pos);
+                    pos += (s.len() + 1).next_multiple_of(4);
+                    depth += 1;
+                    // the root is depth 1; node names may carry @unit.
+                    found = depth == 2
+                        && str::from_utf8(s).is_ok_and(|s| s.split('@').next() == Some(node));
+                }
+                FDT_END_NODE => {
+                    depth -= 1;
+                    found = false;
+                }
+                FDT_PROP => {
+                    let len = be32(pos) as usize;
+                    let nameoff = be32(pos + 4) as usize;
+                    pos += 8;
+                    if found && cstr(strings + nameoff) == name.as_bytes() {
+                        return Some(unsafe { slice::from_raw_parts(pos as *const u8, len) });
+                    }
+                    pos += len.next_multiple_of(4);
+                }
+                FDT_NOP => {}
+                // FDT_END, or a tree we don't understand
+                _ => return None,
+            }
+        }
+    }
+
+    // Property holding a 32- or 64-bit number.
+    pub fn prop_usize(&self, node: &str, name: &str) -> Option<usize> {
+        let v = self.prop(node, name)?;
+        match v.len() {
+            4 => Some(u32::from_be_bytes(v.try_into().unwrap()) as usize),
+            8 => Some(u64::from_be_bytes(v.try_into().unwrap()) as usize),
+            _ => None,
+        }
+    }
+}
@@ -1,13 +1,13 @@
 #[cfg(target_os = "none")]
 use crate::array;
 #[cfg(target_os = "none")]
-use crate::fcntl::OMode;
+use crate::fcntl::{advice, OMode};
 #[cfg(target_os = "none")]
-use crate::fs::{create, IData, Inode, Path, BSIZE};
+use crate::fs::{create, IData, Inode, Path, ReadAhead, BSIZE};
 #[cfg(target_os = "none")]
 use crate::log::LOG;
 #[cfg(target_os = "none")]
-use crate::param::{MAXOPBLOCKS, NDEV, NFILE};
+use crate::param::{NDEV, NFILE};
 #[cfg(target_os = "none")]
 use crate::pipe::Pipe;
 #[cfg(target_os = "none")]
@@ -15,6 +15,8 @@ use crate::proc::{CopyInOut, CPUS};
 #[cfg(target_os = "none")]
 use crate::sleeplock::{SleepLock, SleepLockGuard};
 #[cfg(target_os = "none")]
+use crate::sock::Socket;
+#[cfg(target_os = "none")]
 use crate::spinlock::Mutex;
 #[cfg(target_os = "none")]
 use crate::stat::{IType, Stat};
@@ -43,6 +45,7 @@ pub struct File {
     f: Option<Arc<VFile>>,
     readable: bool,
     writable: bool,
+    nonblock: bool,
 }
 
 #[cfg(target_os = "none")]
@@ -51,6 +54,7 @@ pub enum VFile {
     Device(DNod),
     Inode(FNod),
     Pipe(Pipe),
+    Socket(Socket),
     None,
 }
 
@@ -63,11 +67,16 @@ pub struct DNod {
 }
 
 // Device functions, map this trait using dyn
+// If nonblock is true, the device must return what it has
+// instead of sleeping, or Err(()) if nothing could be transferred.
 #[cfg(target_os = "none")]
 pub trait Device: Send + Sync {
-    fn read(&self, dst: VirtAddr, n: usize) -> Result<usize, ()>;
-    fn write(&self, src: VirtAddr, n: usize) -> Result<usize, ()>;
+    fn read(&self, dst: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()>;
+    fn write(&self, src: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()>;
     fn major(&self) -> Major;
+    fn ioctl(&self, _req: usize, _arg: usize) -> Result<usize, ()> {
+        Err(())
+    }
 }
 
 #[cfg(target_os = "none")]
@@ -89,22 +98,28 @@ impl Deref for DNod {
 #[cfg(target_os = "none")]
 #[derive(Debug)]
 pub struct FNod {
-    off: UnsafeCell<u32>, // Safety: If inode lock is obtained.
+    off: UnsafeCell<u32>,      // Safety: If inode lock is obtained.
+    ra: UnsafeCell<ReadAhead>, // Safety: If inode lock is obtained.
+    append: bool,              // O_APPEND: every write goes to the end of file
     ip: Inode,
 }
 
 #[cfg(target_os = "none")]
 impl FNod {
-    pub fn new(ip: Inode) -> Self {
+    pub fn new(ip: Inode, append: bool) -> Self {
         Self {
             off: UnsafeCell::new(0),
+            ra: UnsafeCell::new(ReadAhead::new(advice::NORMAL)),
+            append,
             ip,
         }
     }
     fn read(&self, dst: VirtAddr, n: usize) -> Result<usize, ()> {
         let mut ip = self.ip.lock();
         let off = unsafe { &mut *self.off.get() };
+        let ra = unsafe { &mut *self.ra.get() };
 
+        ip.readahead(ra, *off, n);
         match ip.read(dst, *off, n) {
             // inode lock is held
             Ok(r) => {
@@ -114,15 +129,58 @@ impl FNod {
             Err(_) => Err(()),
         }
     }
+    fn fadvise(&self, off: usize, len: usize, adv: usize) -> Result<(), ()> {
+        let mut ip = self.ip.lock();
+        let ra = unsafe { &mut *self.ra.get() };
+
+        match adv {
+            advice::NORMAL | advice::RANDOM | advice::SEQUENTIAL => ra.advise(adv),
+            advice::WILLNEED => ip.willneed(off, if len == 0 { usize::MAX } else { len }),
+            // unused buffers are recycled first anyway.
+            advice::DONTNEED => {}
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+    // Read or write at off, leaving the file offset alone.
+    // A write is one transaction, so n must be small.
+    fn read_at(&self, dst: VirtAddr, off: u32, n: usize) -> Result<usize, ()> {
+        self.ip.lock().read(dst, off, n).or(Err(()))
+    }
+    fn write_at(&self, src: VirtAddr, off: u32, n: usize) -> Result<usize, ()> {
+        LOG.begin_op();
+        let r = self.ip.lock().write(src, off, n).or(Err(()));
+        LOG.end_op();
+        r
+    }
+    fn getdents(&self, dst: VirtAddr, n: usize) -> Result<usize, ()> {
+        let mut ip = self.ip.lock();
+        let off = unsafe { &mut *self.off.get() };
+
+        ip.getdents(dst, off, n).or(Err(()))
+    }
+    fn truncate(&self, len: usize) -> Result<(), ()> {
+        let mut ip = self.ip.lock();
+        if ip.itype() != IType::File {
+            return Err(());
+        }
+        ip.truncate(u32::try_from(len).or(Err(()))?).or(Err(()))
+    }
     fn write(&self, src: VirtAddr, n: usize) -> Result<usize, ()> {
         // write a few blocks at a time to avoid exceeding the maximum
         // log transaction size, including i-node, indirect block,
         // allocation blocks, and 2 blocks of slop for non-aligned
         // writes. this really belongs lower down, since inode write()
         // might be writing a device like the console.
-        let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+        let nblocks = LOG.op_blocks();
+        let max = ((nblocks - 1 - 1 - 2) / 2) * BSIZE;
         let mut i: usize = 0;
         let off = unsafe { &mut *self.off.get() };
+
+        // appends are atomic: no other append can slip in between
+        // the transactions of this write.
+        let _append = self.append.then(|| self.ip.append_lock());
+
         while i < n {
             let mut r: usize = 0;
             let mut n1 = n - i;
@@ -131,13 +189,17 @@ impl FNod {
             }
 
             {
-                LOG.begin_op();
+                LOG.begin_op_n(nblocks);
                 let mut guard = self.ip.lock();
-                if let Ok(wbytes) = guard.write(src, *off, n1) {
+                if self.append {
+                    *off = guard.size();
+                }
+                if let Ok(wbytes) = guard.write(src + i, *off, n1) {
                     *off += wbytes as u32;
                     r = wbytes;
                 }
-                LOG.end_op();
+                drop(guard);
+                LOG.end_op_n(nblocks);
             }
 
             if r != n1 {
@@ -157,19 +219,21 @@ impl FNod {
 
 #[cfg(target_os = "none")]
 impl VFile {
-    fn read(&self, dst: VirtAddr, n: usize) -> Result<usize, ()> {
+    fn read(&self, dst: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()> {
         match self {
-            VFile::Device(d) => d.read(dst, n),
+            VFile::Device(d) => d.read(dst, n, nonblock),
             VFile::Inode(f) => f.read(dst, n),
-            VFile::Pipe(p) => p.read(dst, n),
+            VFile::Pipe(p) => p.read(dst, n, nonblock),
+            VFile::Socket(s) => s.recv(dst, n, nonblock, None),
             _ => panic!("file read"),
         }
     }
-    fn write(&self, src: VirtAddr, n: usize) -> Result<usize, ()> {
+    fn write(&self, src: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()> {
         match self {
-            VFile::Device(d) => d.write(src, n),
+            VFile::Device(d) => d.write(src, n, nonblock),
             VFile::Inode(f) => f.write(src, n),
-            VFile::Pipe(p) => p.write(src, n),
+            VFile::Pipe(p) => p.write(src, n, nonblock),
+            VFile::Socket(s) => s.send(src, n, nonblock, None),
             _ => panic!("file write"),
         }
     }
@@ -180,12 +244,16 @@ impl VFile {
         let mut stat: Stat = Default::default();
 
         match self {
-            VFile::Device(DNod { driver: _, ref ip }) | VFile::Inode(FNod { off: _, ref ip }) => {
+            VFile::Device(DNod { driver: _, ref ip }) | VFile::Inode(FNod { ref ip, .. }) => {
                 {
                     ip.lock().stat(&mut stat);
                 }
                 unsafe { p.either_copyout(addr, &stat) }
             }
+            VFile::Pipe(pi) => {
+                pi.ip().ok_or(())?.lock().stat(&mut stat);
+                unsafe { p.either_copyout(addr, &stat) }
+            }
             _ => Err(()),
         }
     }
@@ -198,7 +266,7 @@ impl File {
         if !self.readable {
             return Err(());
         }
-        self.f.as_ref().unwrap().read(dst, n)
+        self.f.as_ref().unwrap().read(dst, n, self.nonblock)
     }
 
     // Write to file.
@@ -206,7 +274,105 @@ impl File {
         if !self.writable {
             return Err(());
         }
-        self.f.as_ref().unwrap().write(src, n)
+        self.f.as_ref().unwrap().write(src, n, self.nonblock)
+    }
+
+    // Shrink or grow file to len bytes.
+    // Must be called inside transaction.
+    pub fn truncate(&self, len: usize) -> Result<(), ()> {
+        if !self.writable {
+            return Err(());
+        }
+        match self.f.as_deref().unwrap() {
+            VFile::Inode(f) => f.truncate(len),
+            _ => Err(()),
+        }
+    }
+
+    // Tell how the file will be read, for readahead.
+    // len 0 means to the end of the file.
+    pub fn fadvise(&self, off: usize, len: usize, advice: usize) -> Result<(), ()> {
+        match self.f.as_deref().unwrap() {
+            VFile::Inode(f) => f.fadvise(off, len, advice),
+            _ => Err(()),
+        }
+    }
+
+    // Read from or write to a regular file at off, without moving
+    // the file offset. Not for use inside a transaction.
+    pub fn read_at(&self, dst: VirtAddr, off: u32, n: usize) -> Result<usize, ()> {
+        if !self.readable {
+            return Err(());
+        }
+        match self.f.as_deref().unwrap() {
+            VFile::Inode(f) => f.read_at(dst, off, n),
+            _ => Err(()),
+        }
+    }
+    pub fn write_at(&self, src: VirtAddr, off: u32, n: usize) -> Result<usize, ()> {
+        if !self.writable {
+            return Err(());
+        }
+        match self.f.as_deref().unwrap() {
+            VFile::Inode(f) => f.write_at(src, off, n),
+            _ => Err(()),
+        }
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn is_nonblock(&self) -> bool {
+        self.nonblock
+    }
+
+    // Finish an open: a FIFO waits for its other side, unless
+    // nonblocking. Not for use inside a transaction.
+    pub fn wait_open(&self) -> Result<(), ()> {
+        match self.f.as_deref().unwrap() {
+            VFile::Pipe(pi) if !self.nonblock => pi.wait_open(),
+            _ => Ok(()),
+        }
+    }
+
+    // The socket, if the file is one.
+    pub fn socket(&self) -> Option<&Socket> {
+        match self.f.as_deref().unwrap() {
+            VFile::Socket(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // File control; only the capacity of pipes so far.
+    pub fn fcntl(&self, cmd: usize, arg: usize) -> Result<usize, ()> {
+        match self.f.as_deref().unwrap() {
+            VFile::Pipe(pi) => pi.fcntl(cmd, arg),
+            _ => Err(()),
+        }
+    }
+
+    // Device specific request.
+    pub fn ioctl(&self, req: usize, arg: usize) -> Result<usize, ()> {
+        match self.f.as_deref().unwrap() {
+            VFile::Device(d) => d.ioctl(req, arg),
+            _ => Err(()),
+        }
+    }
+
+    // Read directory records from file.
+    pub fn getdents(&self, dst: VirtAddr, n: usize) -> Result<usize, ()> {
+        if !self.readable {
+            return Err(());
+        }
+        match self.f.as_deref().unwrap() {
+            VFile::Inode(f) => f.getdents(dst, n),
+            _ => Err(()),
+        }
     }
 }
 
@@ -241,11 +407,16 @@ impl Drop for File {
 
         // if ref count == 1
         match Arc::try_unwrap(f) {
-            Ok(VFile::Inode(FNod { off: _, ip }) | VFile::Device(DNod { driver: _, ip })) => {
+            Ok(VFile::Inode(FNod { ip, .. }) | VFile::Device(DNod { driver: _, ip })) => {
                 LOG.begin_op();
                 drop(ip);
                 LOG.end_op();
             }
+            Ok(VFile::Pipe(pi)) if pi.ip().is_some() => {
+                LOG.begin_op();
+                drop(pi);
+                LOG.end_op();
+            }
             _ => (),
         }
     }
@@ -256,6 +427,7 @@ impl Drop for File {
 pub enum FType<'a> {
     Node(&'a Path),
     Pipe(Pipe),
+    Socket(Socket),
 }
 
 #[cfg(target_os = "none")]
@@ -269,7 +441,11 @@ impl FTable {
                 let mut ip_guard: SleepLockGuard<'_, IData>;
 
                 if opts.is_create() {
-                    ip = create(path, IType::File, 0, 0)?;
+                    // O_DIRECTORY never creates anything.
+                    if opts.is_directory() {
+                        return None;
+                    }
+                    ip = create(path, IType::File, 0, 0, opts.is_excl())?;
                     ip_guard = ip.lock();
                 } else {
                     (_, ip) = path.namei()?;
@@ -278,6 +454,9 @@ impl FTable {
                         return None;
                     }
                 }
+                if opts.is_directory() && ip_guard.itype() != IType::Dir {
+                    return None;
+                }
                 // ?
                 match ip_guard.itype() {
                     IType::Device
@@ -289,16 +468,26 @@ impl FTable {
                         VFile::Device(DNod { driver, ip })
                     }
                     IType::Dir | IType::File => {
+                        if (opts.is_write() || opts.is_trunc()) && ip_guard.readonly() {
+                            return None;
+                        }
                         if opts.is_trunc() && ip_guard.itype() == IType::File {
                             ip_guard.trunc();
                         }
                         SleepLock::unlock(ip_guard);
-                        VFile::Inode(FNod::new(ip))
+                        VFile::Inode(FNod::new(ip, opts.is_append()))
+                    }
+                    IType::Fifo => {
+                        let mut st = Stat::default();
+                        ip_guard.stat(&mut st);
+                        SleepLock::unlock(ip_guard);
+                        VFile::Pipe(Pipe::fifo(ip, (st.dev, st.ino), &opts)?)
                     }
                     _ => return None,
                 }
             }
             FType::Pipe(pi) => VFile::Pipe(pi),
+            FType::Socket(s) => VFile::Socket(s),
         });
 
         let mut guard = self.lock();
@@ -320,6 +509,7 @@ impl FTable {
             f: f.clone(), // ref count = 2
             readable: opts.is_read(),
             writable: opts.is_write(),
+            nonblock: opts.is_nonblock(),
         })
     }
 }
@@ -376,6 +566,7 @@ impl DevSW {
 pub enum Major {
     Null = 0,
     Console = 1,
+    Loop = 2, // loop control; see loopdev.rs
     Invalid,
 }
 impl Default for Major {
@@ -389,6 +580,7 @@ impl Major {
         match bits {
             0 => Major::Null,
             1 => Major::Console,
+            2 => Major::Loop,
             _ => Major::Invalid,
         }
     }
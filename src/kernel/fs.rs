@@ -1,7 +1,11 @@
 #[cfg(target_os = "none")]
 use crate::array;
 #[cfg(target_os = "none")]
-use crate::bio::BCACHE;
+use crate::bio::{BufGuard, BCACHE};
+use crate::defs::as_bytes;
+#[cfg(target_os = "none")]
+use crate::fcntl::advice;
+#[cfg(target_os = "none")]
 use crate::file::Major;
 #[cfg(target_os = "none")]
 use crate::log::LOG;
@@ -12,14 +16,14 @@ use crate::proc::{CopyInOut, CPUS};
 use crate::sleeplock::{SleepLock, SleepLockGuard};
 #[cfg(target_os = "none")]
 use crate::spinlock::Mutex;
-use crate::stat::{IType, Stat};
 #[cfg(target_os = "none")]
-use crate::{
-    sync::{LazyLock, OnceLock},
-    vm::VirtAddr,
-};
+use crate::stat::IType;
+#[cfg(target_os = "none")]
+use crate::stat::{Dirent, Stat};
 #[cfg(target_os = "none")]
-use alloc::sync::Arc;
+use crate::{blockdev, loopdev, param::NBDEV, sync::LazyLock, vm::VirtAddr};
+#[cfg(target_os = "none")]
+use alloc::{sync::Arc, vec::Vec};
 #[cfg(target_os = "none")]
 use core::mem::size_of;
 #[cfg(target_os = "none")]
@@ -39,10 +43,20 @@ use core::ops::Deref;
 pub const ROOTINO: u32 = 1; // root i-number
 pub const BSIZE: usize = 1024; // block size
 
-// there should be one superblock per disk device, but we run with
-// only one device
+// superblocks of the file systems in use: the root and those
+// mounted, by device number from ROOTDEV.
 #[cfg(target_os = "none")]
-pub static SB: OnceLock<SuperBlock> = OnceLock::new();
+static SBS: Mutex<[Option<SuperBlock>; NBDEV]> = Mutex::new([None; NBDEV], "sb");
+
+// The superblock of the file system on dev, which must be in use.
+#[cfg(target_os = "none")]
+pub fn sb(dev: u32) -> SuperBlock {
+    SBS.lock()
+        .get(dev.wrapping_sub(ROOTDEV) as usize)
+        .copied()
+        .flatten()
+        .unwrap_or_else(|| panic!("fs: no file system on {}", dev))
+}
 
 // Disk layout:
 // [ root block | super block | log | inode blocks |
@@ -73,8 +87,8 @@ pub const MAXFILE: usize = NDIRECT + NINDIRECT;
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default)]
 struct DInode {
-    itype: IType,              // File type
-    major: Major,              // Major Device Number (T_DEVICE only)
+    itype: u16,                // File type, an IType
+    major: u16,                // Major Device Number (T_DEVICE only)
     minor: u16,                // Minor Device Number (T_DEVICE only)
     nlink: u16,                // Number of links to inode in file system
     size: u32,                 // Size of data (bytes)
@@ -97,11 +111,85 @@ pub struct DirEnt {
     pub name: [u8; DIRSIZ],
 }
 
+// Log header block, see log.rs.
+// Max data blocks in one transaction: as many as the header block can list.
+pub const LOGMAXBLOCKS: usize = (BSIZE - 16) / (2 * core::mem::size_of::<u32>());
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LogHeader {
+    pub n: u32,
+    pub checksum: u32, // of the header, computed with checksum = 0
+    pub seq: u64,      // sequence number of the transaction
+    pub block: [u32; LOGMAXBLOCKS],
+    pub sums: [u32; LOGMAXBLOCKS], // checksum of each logged block
+}
+
+impl Default for LogHeader {
+    fn default() -> Self {
+        Self {
+            n: 0,
+            checksum: 0,
+            seq: 0,
+            block: [0; LOGMAXBLOCKS],
+            sums: [0; LOGMAXBLOCKS],
+        }
+    }
+}
+
+impl LogHeader {
+    pub fn checksum(&self) -> u32 {
+        let mut lh = *self;
+        lh.checksum = 0;
+        crc32(self.seq as u32, unsafe { as_bytes(&lh) })
+    }
+
+    // checksum of the contents of the ith logged block
+    pub fn block_sum(&self, i: usize, data: &[u8]) -> u32 {
+        crc32(self.seq as u32 ^ self.block[i], data)
+    }
+}
+
+// CRC-32 (IEEE 802.3), seeded so that the same data
+// in another context gets another checksum.
+fn crc32(seed: u32, data: &[u8]) -> u32 {
+    let mut crc = !seed;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
 impl SuperBlock {
+    // Read the superblock of the file system on dev, and check
+    // that it is one, fits the disk, and has its log, inodes and
+    // bitmap inside it.
     #[cfg(target_os = "none")]
-    fn read(dev: u32) -> Self {
-        let bp = BCACHE.read(dev, 1);
-        *bp.align_to::<SuperBlock>().get(0).unwrap()
+    fn read(dev: u32) -> Result<Self, &'static str> {
+        let disk = blockdev::lookup(dev).ok_or("no such device")?;
+        let bp = BCACHE.read(dev, 1).or(Err("cannot read superblock"))?;
+        let sb = *bp.align_to::<SuperBlock>().get(0).unwrap();
+        if sb.magic != FSMAGIC {
+            return Err("invalid file system");
+        }
+        if sb.size > disk.capacity() {
+            return Err("file system larger than its disk");
+        }
+        // n blocks from start, past the superblock and in the file system
+        let inside = |start: u32, n: u32| {
+            start > 1 && start.checked_add(n).map_or(false, |end| end <= sb.size)
+        };
+        if sb.ninodes <= ROOTINO
+            || !inside(sb.logstart, sb.nlog)
+            || !inside(sb.inodestart, (sb.ninodes - 1) / IPB as u32 + 1)
+            || !inside(sb.bmapstart, sb.size.saturating_sub(1) / BPB + 1)
+        {
+            return Err("bad file system layout");
+        }
+        Ok(sb)
     }
 
     // Block containing inode i
@@ -111,39 +199,54 @@ impl SuperBlock {
 
     // Block of free map containing bit for block b
     pub fn bblock(&self, b: u32) -> u32 {
-        b + BPB + self.bmapstart
+        b / BPB + self.bmapstart
     }
 }
 
 // Init fs
 #[cfg(target_os = "none")]
 pub fn init(dev: u32) {
-    SB.set(SuperBlock::read(dev)).unwrap();
-    let sb = SB.get().unwrap();
-    assert!(sb.magic != FSMAGIC, "invalid file system");
+    let sb = SuperBlock::read(dev).unwrap_or_else(|e| panic!("fs: {}", e));
+    // the log writes the disk from the start.
+    assert!(!blockdev::get(dev).readonly(), "read-only disk");
+    SBS.lock()[(dev - ROOTDEV) as usize] = Some(sb);
     LOG.init();
 }
 
+// Read a block of the file system's own: the bitmap, an inode, or
+// a block about to be zeroed. SuperBlock::read() has checked that
+// they are all on the disk, so failing to read one leaves no way
+// on. Only the writable root uses it: a mounted file system, which
+// may be anything, fails the operation instead.
+#[cfg(target_os = "none")]
+fn bread(dev: u32, bno: u32) -> BufGuard {
+    BCACHE
+        .read(dev, bno)
+        .unwrap_or_else(|_| panic!("fs: cannot read block {}", bno))
+}
+
 // Zero a block
 #[cfg(target_os = "none")]
 fn bzero(dev: u32, bno: u32) {
-    let mut bp = BCACHE.read(dev, bno);
+    let mut bp = bread(dev, bno);
     bp.copy_from_slice(&[0; BSIZE]);
     LOG.write(bp);
 }
 
 // Blocks.
 
+// Contents of a hole.
+#[cfg(target_os = "none")]
+static ZEROS: [u8; BSIZE] = [0; BSIZE];
+
 // Allocate a zeroed disk block.
 #[cfg(target_os = "none")]
 fn balloc(dev: u32) -> u32 {
-    let sb = SB.get().unwrap();
+    let sb = sb(dev);
     let mut bp;
     for b in (0..sb.size).step_by(BPB as usize) {
-        bp = BCACHE.read(dev, sb.bblock(b));
-        let mut bi = 0;
-        while bi < BPB && b + bi < sb.size {
-            bi += 1;
+        bp = bread(dev, sb.bblock(b));
+        for bi in 0..BPB.min(sb.size - b) {
             let m = 1 << (bi % 8);
             if bp.get((bi / 8) as usize).unwrap() & m == 0 {
                 // Is block free?
@@ -160,8 +263,8 @@ fn balloc(dev: u32) -> u32 {
 // Free a disk block
 #[cfg(target_os = "none")]
 fn bfree(dev: u32, b: u32) {
-    let sb = SB.get().unwrap();
-    let mut bp = BCACHE.read(dev, sb.bblock(b));
+    let sb = sb(dev);
+    let mut bp = bread(dev, sb.bblock(b));
     let bi = b % BPB;
     let m = 1 << (bi % 8);
     if bp.get((bi / 8) as usize).unwrap() & m == 0 {
@@ -169,6 +272,16 @@ fn bfree(dev: u32, b: u32) {
     }
     *bp.get_mut((bi / 8) as usize).unwrap() &= !m;
     LOG.write(bp);
+    LOG.free(b);
+}
+
+// Is block b free in the bitmap?
+#[cfg(target_os = "none")]
+pub fn block_free(dev: u32, b: u32) -> bool {
+    let sb = sb(dev);
+    let bp = bread(dev, sb.bblock(b));
+    let bi = b % BPB;
+    bp[(bi / 8) as usize] & (1 << (bi % 8)) == 0
 }
 
 // Inodes.
@@ -259,6 +372,7 @@ pub struct MInode {
     dev: u32,
     inum: u32,
     data: SleepLock<IData>,
+    append: SleepLock<()>, // serializes O_APPEND writers across transactions
 }
 
 #[cfg(target_os = "none")]
@@ -275,6 +389,41 @@ pub struct IData {
     addrs: [u32; NDIRECT + 1],
 }
 
+// Readahead window, in blocks.
+#[cfg(target_os = "none")]
+const RA_MIN: u32 = 4;
+#[cfg(target_os = "none")]
+const RA_MAX: u32 = 32;
+
+// Readahead state of an open file.
+// A read that starts where the last one ended is sequential,
+// and doubles the window of blocks read ahead of it.
+#[cfg(target_os = "none")]
+#[derive(Debug, Clone, Copy)]
+pub struct ReadAhead {
+    advice: usize, // one of fcntl::advice
+    next: u32,     // block a sequential read starts at
+    ahead: u32,    // first block not read ahead yet
+    window: u32,
+}
+
+#[cfg(target_os = "none")]
+impl ReadAhead {
+    pub const fn new(advice: usize) -> Self {
+        Self {
+            advice,
+            next: 0,
+            ahead: 0,
+            window: 0,
+        }
+    }
+
+    pub fn advise(&mut self, advice: usize) {
+        self.advice = advice;
+        self.window = 0;
+    }
+}
+
 #[cfg(target_os = "none")]
 impl IData {
     fn new(dev: u32, inum: u32) -> Self {
@@ -293,19 +442,28 @@ impl IData {
         self.major
     }
 
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    // is the inode on a read-only file system?
+    pub fn readonly(&self) -> bool {
+        readonly(self.dev)
+    }
+
     // Copy a modified in-memory inode to disk.
     // Must be called after every change to an inode field
     // that lives on disk.
     // Caller must hold inode sleeplock.
     fn update(&self) {
-        let sb = SB.get().unwrap();
-        let mut bp = BCACHE.read(self.dev, sb.iblock(self.inum));
+        let sb = sb(self.dev);
+        let mut bp = bread(self.dev, sb.iblock(self.inum));
         let dip = bp
             .align_to_mut::<DInode>()
             .get_mut(self.inum as usize % IPB)
             .unwrap();
-        dip.itype = self.itype;
-        dip.major = self.major;
+        dip.itype = self.itype as u16;
+        dip.major = self.major as u16;
         dip.minor = self.minor;
         dip.nlink = self.nlink;
         dip.size = self.size;
@@ -316,29 +474,80 @@ impl IData {
     // Trancate inode (discard contents).
     // Caller must hold inode sleeplock.
     pub fn trunc(&mut self) {
-        for addr in self.addrs.iter_mut().take(NDIRECT) {
-            if *addr > 0 {
-                bfree(self.dev, *addr);
-                *addr = 0;
-            }
+        self.truncate(0).unwrap();
+    }
+
+    // Shrink or grow the inode to len bytes.
+    // Shrinking frees the blocks past the new end and zeroes the
+    // rest of the last block, so that growing the file again reads
+    // zeros there. Growing only sets the size; the new blocks are
+    // holes until they are written.
+    // Caller must hold inode sleeplock.
+    pub fn truncate(&mut self, len: u32) -> Result<(), &'static str> {
+        if len as usize > MAXFILE * BSIZE {
+            return Err("truncate: too large");
+        }
+        if readonly(self.dev) {
+            return Err("truncate: read-only file system");
         }
+        if loopdev::backs(self.dev, self.inum) {
+            return Err("truncate: file backs a loop device");
+        }
+
+        if len < self.size {
+            // first block to free
+            let nb = (len as usize).div_ceil(BSIZE);
+            // read before anything is freed, so a failure changes nothing.
+            let dev = self.dev;
+            let read = |b| BCACHE.read(dev, b).or(Err("truncate: read error"));
+            let off = len as usize % BSIZE;
+            let tail = match off {
+                0 => 0,
+                _ => self.bmap((len as usize / BSIZE) as u32, false)?,
+            };
+            let tail = match tail {
+                0 => None,
+                b => Some(read(b)?),
+            };
+            let naddr = self.addrs[NDIRECT];
+            let indirect = match naddr {
+                0 => None,
+                b => Some(read(b)?),
+            };
+            for addr in self.addrs.iter_mut().take(NDIRECT).skip(nb) {
+                if *addr > 0 {
+                    bfree(self.dev, *addr);
+                    *addr = 0;
+                }
+            }
 
-        let naddr = self.addrs.get_mut(NDIRECT).unwrap();
-        if *naddr > 0 {
-            let bp = BCACHE.read(self.dev, *naddr);
-            let a = bp.align_to::<u32>();
-            for &addr in a.iter() {
-                // 0 .. NINDIRECT = BISIZE / u32
-                if addr > 0 {
-                    bfree(self.dev, addr);
+            if let Some(mut bp) = indirect {
+                let a = bp.align_to_mut::<u32>();
+                for addr in a.iter_mut().skip(nb.saturating_sub(NDIRECT)) {
+                    // 0 .. NINDIRECT = BISIZE / u32
+                    if *addr > 0 {
+                        bfree(self.dev, *addr);
+                        *addr = 0;
+                    }
+                }
+                if nb <= NDIRECT {
+                    drop(bp);
+                    bfree(self.dev, naddr);
+                    self.addrs[NDIRECT] = 0;
+                } else {
+                    LOG.write(bp);
                 }
             }
-            drop(bp);
-            bfree(self.dev, *naddr);
-            *naddr = 0;
+
+            // zero the tail of the new last block
+            if let Some(mut bp) = tail {
+                bp[off..].fill(0);
+                LOG.write(bp);
+            }
         }
-        self.size = 0;
+        self.size = len;
         self.update();
+        Ok(())
     }
 
     // Inode content
@@ -349,14 +558,15 @@ impl IData {
     // are listed in block idata.addrs[NDIRECT].
     //
     // Retun the disk block address of the nth block in inode ip.
-    // If there is no such block, bmap allocates one.
-    pub fn bmap(&mut self, bn: u32) -> Result<u32, &'static str> {
+    // If there is no such block, bmap allocates one if alloc is true,
+    // or returns 0: blocks that were never written are holes.
+    pub fn bmap(&mut self, bn: u32, alloc: bool) -> Result<u32, &'static str> {
         let mut addr;
         let mut bn = bn as usize;
 
         if bn < NDIRECT {
             addr = self.addrs[bn];
-            if addr == 0 {
+            if addr == 0 && alloc {
                 addr = balloc(self.dev);
                 self.addrs[bn] = addr;
             }
@@ -368,13 +578,16 @@ impl IData {
             // Load indirect block, allocating if necessary.
             addr = self.addrs[NDIRECT];
             if addr == 0 {
+                if !alloc {
+                    return Ok(0);
+                }
                 addr = balloc(self.dev);
                 self.addrs[NDIRECT] = addr;
             }
-            let mut bp = BCACHE.read(self.dev, addr);
+            let mut bp = BCACHE.read(self.dev, addr).or(Err("bmap: read error"))?;
             let a = bp.align_to_mut::<u32>();
             addr = a[bn];
-            if addr == 0 {
+            if addr == 0 && alloc {
                 addr = balloc(self.dev);
                 a[bn] = addr;
                 LOG.write(bp);
@@ -415,14 +628,22 @@ impl IData {
         }
 
         while tot < n {
-            let bp = BCACHE.read(self.dev, self.bmap((off / BSIZE) as u32)?);
+            let addr = self.bmap((off / BSIZE) as u32, false)?;
             let m = core::cmp::min(n - tot, BSIZE - off % BSIZE);
-            if unsafe {
-                CPUS.my_proc()
-                    .unwrap()
-                    .either_copyout(dst, &bp[(off % BSIZE)..m])
-                    .is_err()
-            } {
+            let res = if addr == 0 {
+                // a hole reads as zeros
+                unsafe { CPUS.my_proc().unwrap().either_copyout(dst, &ZEROS[..m]) }
+            } else {
+                let bp = BCACHE
+                    .read(self.dev, addr)
+                    .or(Err("inode read: read error"))?;
+                unsafe {
+                    CPUS.my_proc()
+                        .unwrap()
+                        .either_copyout(dst, &bp[(off % BSIZE)..(off % BSIZE + m)])
+                }
+            };
+            if res.is_err() {
                 return Err("inode read: Failed to copyout");
             }
             tot += m;
@@ -432,6 +653,62 @@ impl IData {
         Ok(tot)
     }
 
+    // Start reading the blocks of a read of n bytes at off, and
+    // the window after them, without waiting for the disk.
+    // Caller must hold sleeplock.
+    pub fn readahead(&mut self, ra: &mut ReadAhead, off: u32, n: usize) {
+        if off >= self.size || n == 0 {
+            return;
+        }
+        let first = off / BSIZE as u32;
+        let last = ((off as usize + n).min(self.size as usize) - 1) / BSIZE;
+        let last = last as u32;
+
+        // small reads may start in the block the last one ended in.
+        let sequential = first == ra.next || first + 1 == ra.next;
+        ra.window = match ra.advice {
+            advice::RANDOM => 0,
+            advice::SEQUENTIAL => RA_MAX,
+            _ if sequential => (ra.window * 2).clamp(RA_MIN, RA_MAX),
+            _ => 0,
+        };
+        if !sequential || ra.ahead < first {
+            ra.ahead = first;
+        }
+        ra.next = last + 1;
+
+        let nblocks = (self.size as usize).div_ceil(BSIZE) as u32;
+        let end = (last + 1 + ra.window).min(nblocks);
+        ra.ahead = self.start_reads(ra.ahead, end);
+    }
+
+    // Start reading the blocks of n bytes at off, for a
+    // program that says it will need them soon.
+    // Caller must hold sleeplock.
+    pub fn willneed(&mut self, off: usize, n: usize) {
+        let end = off
+            .saturating_add(n)
+            .min(self.size as usize)
+            .div_ceil(BSIZE);
+        self.start_reads((off / BSIZE) as u32, end as u32);
+    }
+
+    // Start reading blocks [bn, end) of the file; holes are skipped.
+    // Stops early if the disk cannot take more requests; returns
+    // the first block not started.
+    fn start_reads(&mut self, bn: u32, end: u32) -> u32 {
+        // let adjacent blocks merge into one disk request.
+        let _plug = blockdev::plug(self.dev);
+        for bn in bn..end {
+            match self.bmap(bn, false) {
+                Ok(0) => continue,
+                Ok(addr) if BCACHE.readahead(self.dev, addr) => continue,
+                _ => return bn,
+            }
+        }
+        end.max(bn)
+    }
+
     // Write data to inode.
     // Caller must hold sleeplock.
     // dst is UVAddr or KVAddr
@@ -448,14 +725,20 @@ impl IData {
         if off + n > MAXFILE * BSIZE {
             return Err("inode write");
         }
+        if readonly(self.dev) {
+            return Err("inode write: read-only file system");
+        }
 
         while tot < n {
-            let mut bp = BCACHE.read(self.dev, self.bmap((off / BSIZE) as u32)?);
+            let addr = self.bmap((off / BSIZE) as u32, true)?;
+            let mut bp = BCACHE
+                .read(self.dev, addr)
+                .or(Err("inode write: read error"))?;
             let m = core::cmp::min(n - tot, BSIZE - off % BSIZE);
             if unsafe {
                 CPUS.my_proc()
                     .unwrap()
-                    .either_copyin(&mut bp[(off % BSIZE)..m], src)
+                    .either_copyin(&mut bp[(off % BSIZE)..(off % BSIZE + m)], src)
                     .is_err()
             } {
                 return Err("inode write: Failed to copyin");
@@ -494,20 +777,17 @@ impl IData {
                 off,
                 size_of::<DirEnt>(),
             )
-            .expect("dirlookup read");
+            .ok()?;
             if de.inum == 0 {
                 continue;
             }
-            if name
-                == core::str::from_utf8(&de.name)
-                    .unwrap()
-                    .trim_matches(char::from(0))
-            {
+            let len = de.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+            if name.as_bytes() == &de.name[..len] {
                 // entry matches path element
                 if let Some(poff) = poff {
                     *poff = off;
                 }
-                return Some(ITABLE.get(self.dev, de.inum as u32));
+                return iget(self.dev, de.inum as u32);
             }
         }
         None
@@ -523,7 +803,7 @@ impl IData {
         }
 
         // Look for an empty dirent
-        let mut offset = 0;
+        let mut offset = self.size;
         for off in (0..self.size).step_by(size_of::<DirEnt>()) {
             self.read(
                 VirtAddr::Kernel(&mut de as *mut _ as usize),
@@ -531,22 +811,109 @@ impl IData {
                 size_of::<DirEnt>(),
             )
             .unwrap();
-            offset += size_of::<DirEnt>() as u32;
             if de.inum == 0 {
+                offset = off;
                 break;
             }
         }
 
-        de.name.copy_from_slice(&name.as_bytes()[0..DIRSIZ]);
+        self.dirent_write(offset, name, inum)
+    }
+
+    // Look for the entry of inum in the directory, other than "." and "..".
+    // If found, copy its name into name and return the length of the name.
+    pub fn dirname(&mut self, inum: u32, name: &mut [u8; DIRSIZ]) -> Option<usize> {
+        let mut de: DirEnt = Default::default();
+        if self.itype != IType::Dir {
+            panic!("dirname not DIR");
+        }
+
+        for off in (0..self.size).step_by(size_of::<DirEnt>()) {
+            self.read(
+                VirtAddr::Kernel(&mut de as *mut _ as usize),
+                off,
+                size_of::<DirEnt>(),
+            )
+            .ok()?;
+            if de.inum as u32 != inum || de.name.starts_with(b".\0") || de.name.starts_with(b"..\0")
+            {
+                continue;
+            }
+            name.copy_from_slice(&de.name);
+            return Some(de.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ));
+        }
+        None
+    }
+
+    // Overwrite the directory entry at byte offset off.
+    // An inum of 0 marks the slot as free.
+    fn dirent_write(&mut self, off: u32, name: &str, inum: u32) -> Result<(), &'static str> {
+        let mut de: DirEnt = Default::default();
+        let len = core::cmp::min(name.len(), DIRSIZ);
+        de.name[..len].copy_from_slice(&name.as_bytes()[..len]);
         de.inum = inum as u16;
         self.write(
             VirtAddr::Kernel(&mut de as *mut _ as usize),
-            offset,
+            off,
             size_of::<DirEnt>(),
         )
-        .unwrap();
+        .and(Ok(()))
+    }
 
-        Ok(())
+    // Copy getdents() records for the entries from *off on to dst,
+    // skipping free slots. Stops before the first record that would
+    // not fit in n bytes, and advances *off past what was copied.
+    // Returns the number of bytes copied, 0 at the end of directory.
+    // Caller must hold sleeplock.
+    pub fn getdents(
+        &mut self,
+        dst: VirtAddr,
+        off: &mut u32,
+        n: usize,
+    ) -> Result<usize, &'static str> {
+        let mut de: DirEnt = Default::default();
+        let mut tot = 0;
+        if self.itype != IType::Dir {
+            return Err("getdents: not a directory");
+        }
+
+        while *off < self.size {
+            self.read(
+                VirtAddr::Kernel(&mut de as *mut _ as usize),
+                *off,
+                size_of::<DirEnt>(),
+            )?;
+            if de.inum != 0 {
+                let name = core::str::from_utf8(&de.name)
+                    .or(Err("getdents: bad name"))?
+                    .trim_end_matches(char::from(0));
+                // "." and ".." are directories, and locking them here
+                // would deadlock or break the parent-first lock order.
+                let itype = if name == "." || name == ".." || de.inum as u32 == self.inum {
+                    IType::Dir
+                } else {
+                    iget(self.dev, de.inum as u32).map_or(IType::None, |ip| ip.lock().itype)
+                };
+                let rec = Dirent::new(de.inum as u32, itype, name.len());
+                if tot + rec.reclen as usize > n {
+                    if tot == 0 {
+                        return Err("getdents: buffer too small");
+                    }
+                    break;
+                }
+                let p = CPUS.my_proc().unwrap();
+                unsafe {
+                    p.either_copyout(dst + tot, &rec)
+                        .and_then(|_| {
+                            p.either_copyout(dst + tot + size_of::<Dirent>(), name.as_bytes())
+                        })
+                        .or(Err("getdents: Failed to copyout"))?;
+                }
+                tot += rec.reclen as usize;
+            }
+            *off += size_of::<DirEnt>() as u32;
+        }
+        Ok(tot)
     }
 
     // Is the directory dp empty except for "." and ".." ?
@@ -578,35 +945,53 @@ impl MInode {
             dev,
             inum,
             data: SleepLock::new(IData::new(dev, inum), "inode"),
+            append: SleepLock::new((), "append"),
         }
     }
 
+    // Serialize appending writers.
+    // A large write is split into several log transactions, so the
+    // inode lock alone cannot keep two appends from interleaving.
+    // Must be acquired before begin_op() and the inode lock.
+    pub fn append_lock(&self) -> SleepLockGuard<'_, ()> {
+        self.append.lock()
+    }
+
     // unlock function is no need.
     // because SleepLockGuard impl Drop trait.
 
     // Lock the inode
     // Reads the inode from disk if necessary.
     pub fn lock(&self) -> SleepLockGuard<IData> {
-        let sb = SB.get().unwrap();
+        let sb = sb(self.dev);
         let mut guard = self.data.lock();
         if !guard.valid {
-            let bp = BCACHE.read(self.dev, sb.iblock(self.inum));
-            let dip = bp
-                .align_to::<DInode>()
-                .get(self.inum as usize % IPB)
-                .unwrap();
-            guard.itype = dip.itype;
-            guard.major = dip.major;
+            let dip = match BCACHE.read(self.dev, sb.iblock(self.inum)) {
+                Ok(bp) => *bp
+                    .align_to::<DInode>()
+                    .get(self.inum as usize % IPB)
+                    .unwrap(),
+                Err(_) if readonly(self.dev) => DInode::default(),
+                Err(_) => panic!("fs: cannot read block {}", sb.iblock(self.inum)),
+            };
+            guard.itype = IType::from_u16(dip.itype).unwrap_or(IType::None);
+            guard.major = Major::from_u16(dip.major);
             guard.minor = dip.minor;
             guard.nlink = dip.nlink;
             guard.size = dip.size;
             guard.addrs.copy_from_slice(&dip.addrs);
-            drop(bp);
             guard.valid = true;
             guard.dev = self.dev;
             guard.inum = self.inum;
             if guard.itype == IType::None {
-                panic!("ilock: no type");
+                if !readonly(self.dev) {
+                    panic!("ilock: no type");
+                }
+                // an inode of a mounted file system that cannot be
+                // read, or makes no sense, is left empty, which no
+                // operation takes.
+                guard.size = 0;
+                guard.addrs = [0; NDIRECT + 1];
             }
         }
         guard
@@ -659,17 +1044,17 @@ impl ITable {
     // Mark it as allocated by giving it type.
     // Returns an unlocked but allocated and referenced inode.
     fn alloc(&self, dev: u32, itype: IType) -> Option<Inode> {
-        let sb = SB.get().unwrap();
+        let sb = sb(dev);
         for inum in 1..sb.ninodes {
-            let mut bp = BCACHE.read(dev, sb.iblock(inum));
+            let mut bp = bread(dev, sb.iblock(inum));
             let dip = bp
                 .align_to_mut::<DInode>()
                 .get_mut(inum as usize % IPB)
                 .unwrap();
-            if dip.itype == IType::None {
+            if dip.itype == IType::None as u16 {
                 // a free inode
                 *dip = Default::default();
-                dip.itype = itype;
+                dip.itype = itype as u16;
                 LOG.write(bp);
                 return Some(self.get(dev, inum));
             }
@@ -725,7 +1110,7 @@ impl ITable {
             let mut idata = inode.data.lock();
             let itable = Mutex::unlock(guard);
 
-            if idata.valid && idata.nlink == 0 {
+            if idata.valid && idata.nlink == 0 && !readonly(idata.dev) {
                 // inode has no links and no other references: truncate and free.
                 idata.trunc();
                 idata.itype = IType::None;
@@ -747,6 +1132,13 @@ impl ITable {
     }
 }
 
+// The inode inum on dev, found in a directory entry; None if the
+// file system has no such inode.
+#[cfg(target_os = "none")]
+fn iget(dev: u32, inum: u32) -> Option<Inode> {
+    (inum != 0 && inum < sb(dev).ninodes).then(|| ITABLE.get(dev, inum))
+}
+
 // Create the path new as a link to the same inode as old.
 #[cfg(target_os = "none")]
 pub fn link(old: &Path, new: &Path) -> Result<(), ()> {
@@ -761,7 +1153,7 @@ pub fn link(old: &Path, new: &Path) -> Result<(), ()> {
 
     let (name, dp) = new.nameiparent().ok_or(())?;
     let mut dp_guard = dp.lock();
-    if dp.dev != ip.dev || dp_guard.dirlink(name, ip.inum).is_err() {
+    if dp.dev != ip.dev || readonly(dp.dev) || dp_guard.dirlink(name, ip.inum).is_err() {
         return Err(());
     }
 
@@ -773,12 +1165,139 @@ pub fn link(old: &Path, new: &Path) -> Result<(), ()> {
     Ok(())
 }
 
+// Serializes rename() so that a directory cannot move while
+// another rename is checking its ancestry.
+#[cfg(target_os = "none")]
+static RENAME_LOCK: SleepLock<()> = SleepLock::new((), "rename");
+
+// Is the directory ip an ancestor of (or the same as) dp?
+// Walks ".." from dp up to the root.
+#[cfg(target_os = "none")]
+fn is_ancestor(ip: &Inode, dp: &Inode) -> Result<bool, ()> {
+    let mut cur = dp.dup();
+    loop {
+        if cur.dev == ip.dev && cur.inum == ip.inum {
+            return Ok(true);
+        }
+        if cur.inum == ROOTINO {
+            return Ok(false);
+        }
+        let parent = cur.lock().dirlookup("..", None).ok_or(())?;
+        cur = parent;
+    }
+}
+
+// Move the directory entry old to new.
+// An existing new is replaced, as long as both are files or new is an
+// empty directory. Moving a directory into another parent also fixes
+// its ".." entry and the link counts of both parents.
+// Must be called inside a transaction, so that either all or none
+// of the directory updates reach the disk.
+#[cfg(target_os = "none")]
+pub fn rename(old: &Path, new: &Path) -> Result<(), ()> {
+    let _rename = RENAME_LOCK.lock();
+
+    let (oname, odp) = old.nameiparent().ok_or(())?;
+    let (nname, ndp) = new.nameiparent().ok_or(())?;
+
+    // Cannot rename "." or ".."
+    if oname == "." || oname == ".." || nname == "." || nname == ".." {
+        return Err(());
+    }
+    if odp.dev != ndp.dev || readonly(odp.dev) {
+        return Err(());
+    }
+
+    let ip = odp.lock().dirlookup(oname, None).ok_or(())?;
+    if mounted_on(&ip) {
+        return Err(());
+    }
+    let is_dir = ip.lock().itype == IType::Dir;
+    let moving = odp.inum != ndp.inum;
+
+    // Refuse to move a directory into its own subtree.
+    if is_dir && moving && is_ancestor(&ip, &ndp)? {
+        return Err(());
+    }
+
+    // Lock both parents, the ancestor first, like namex() does.
+    let mut ndp_guard;
+    let mut odp_guard = None;
+    if moving && is_ancestor(&odp, &ndp)? {
+        odp_guard = Some(odp.lock());
+        ndp_guard = ndp.lock();
+    } else {
+        ndp_guard = ndp.lock();
+        if moving {
+            odp_guard = Some(odp.lock());
+        }
+    }
+    macro_rules! odp_guard {
+        () => {
+            match odp_guard.as_mut() {
+                Some(guard) => &mut **guard,
+                None => &mut *ndp_guard,
+            }
+        };
+    }
+
+    // old may have changed while nothing was locked.
+    let mut ooff = 0;
+    match odp_guard!().dirlookup(oname, Some(&mut ooff)) {
+        Some(oip) if oip.inum == ip.inum => (),
+        _ => return Err(()),
+    }
+    let mut ip_guard = ip.lock();
+
+    let mut noff = 0;
+    match ndp_guard.dirlookup(nname, Some(&mut noff)) {
+        // old and new are links to the same inode: nothing to do.
+        Some(tip) if tip.inum == ip.inum => return Ok(()),
+        // the target is locked already, and not empty anyway.
+        Some(tip) if tip.inum == odp.inum => return Err(()),
+        Some(tip) if mounted_on(&tip) => return Err(()),
+        Some(tip) => {
+            let mut tip_guard = tip.lock();
+            match (is_dir, tip_guard.itype == IType::Dir) {
+                (true, true) if tip_guard.is_dir_empty() => (),
+                (false, false) => (),
+                _ => return Err(()),
+            }
+            // replace the target entry in place.
+            ndp_guard.dirent_write(noff, nname, ip.inum).or(Err(()))?;
+            if tip_guard.itype == IType::Dir {
+                ndp_guard.nlink -= 1; // the target's ".."
+            }
+            tip_guard.nlink -= 1;
+            tip_guard.update();
+        }
+        None => ndp_guard.dirlink(nname, ip.inum).or(Err(()))?,
+    }
+
+    odp_guard!().dirent_write(ooff, "", 0).or(Err(()))?;
+
+    if is_dir && moving {
+        let mut off = 0;
+        ip_guard.dirlookup("..", Some(&mut off)).ok_or(())?;
+        ip_guard.dirent_write(off, "..", ndp.inum).or(Err(()))?;
+        odp_guard!().nlink -= 1;
+        ndp_guard.nlink += 1;
+    }
+    odp_guard!().update();
+    ndp_guard.update();
+
+    Ok(())
+}
+
 #[cfg(target_os = "none")]
 pub fn unlink(path: &Path) -> Result<(), ()> {
     let de: DirEnt = Default::default();
     let mut off: u32 = 0;
 
     let (name, dp) = path.nameiparent().ok_or(())?;
+    if readonly(dp.dev) {
+        return Err(());
+    }
     let mut dp_guard = dp.lock();
 
     // Cannot unlink "." or ".."
@@ -787,6 +1306,9 @@ pub fn unlink(path: &Path) -> Result<(), ()> {
     }
 
     let ip = dp_guard.dirlookup(name, Some(&mut off)).ok_or(())?;
+    if mounted_on(&ip) {
+        return Err(());
+    }
     let mut ip_guard = ip.lock();
 
     if ip_guard.nlink < 1 {
@@ -814,8 +1336,11 @@ pub fn unlink(path: &Path) -> Result<(), ()> {
     Ok(())
 }
 
+// Create a new inode at path, or return the existing one when
+// opening a file. If excl is true, an existing entry is an error,
+// which makes create() usable for lock files (O_CREATE | O_EXCL).
 #[cfg(target_os = "none")]
-pub fn create(path: &Path, type_: IType, major: u16, minor: u16) -> Option<Inode> {
+pub fn create(path: &Path, type_: IType, major: u16, minor: u16, excl: bool) -> Option<Inode> {
     let (name, dp) = path.nameiparent()?;
     let ip: Inode;
     {
@@ -823,9 +1348,14 @@ pub fn create(path: &Path, type_: IType, major: u16, minor: u16) -> Option<Inode
 
         if let Some(ip) = dp_guard.dirlookup(name, None) {
             SleepLock::unlock(dp_guard);
+            if excl {
+                return None;
+            }
             let ip_guard = ip.lock();
             match type_ {
-                IType::File if ip_guard.itype == IType::File || ip_guard.itype == IType::Device => {
+                IType::File
+                    if matches!(ip_guard.itype, IType::File | IType::Device | IType::Fifo) =>
+                {
                     SleepLock::unlock(ip_guard);
                     return Some(ip);
                 }
@@ -833,6 +1363,9 @@ pub fn create(path: &Path, type_: IType, major: u16, minor: u16) -> Option<Inode
             }
         }
 
+        if readonly(dp.dev) {
+            return None;
+        }
         ip = ITABLE.alloc(dp.dev, type_)?;
         let mut ip_guard = ip.lock();
         ip_guard.major = Major::from_u16(major);
@@ -862,6 +1395,138 @@ pub fn create(path: &Path, type_: IType, major: u16, minor: u16) -> Option<Inode
     Some(ip)
 }
 
+// Mounts.
+//
+// The file system on another block device can be mounted on a
+// directory of the root file system, which then leads to its root
+// until it is unmounted. Only the root file system has a log, so
+// the others are mounted read-only.
+
+// Can the file system on dev not be written?
+#[cfg(target_os = "none")]
+pub fn readonly(dev: u32) -> bool {
+    dev != ROOTDEV
+}
+
+#[cfg(target_os = "none")]
+struct Mount {
+    dev: u32,
+    on: Inode, // the directory mounted on
+}
+
+#[cfg(target_os = "none")]
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new(), "mounts");
+
+// Mount the file system on dev on the directory path.
+// Must be called inside a transaction.
+#[cfg(target_os = "none")]
+pub fn mount(dev: u32, path: &Path) -> Result<(), ()> {
+    let (_, ip) = path.namei().ok_or(())?;
+    if ip.dev != ROOTDEV || ip.inum == ROOTINO || ip.lock().itype != IType::Dir {
+        return Err(());
+    }
+    if dev == ROOTDEV {
+        return Err(());
+    }
+    let sb = SuperBlock::read(dev).or(Err(()))?;
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.dev == dev || m.on.inum == ip.inum) {
+        return Err(());
+    }
+    SBS.lock()[(dev - ROOTDEV) as usize] = Some(sb);
+    mounts.push(Mount { dev, on: ip });
+    Ok(())
+}
+
+// Unmount the file system mounted on path. Fails while any of its
+// inodes is in use, e.g. as a working directory.
+// Must be called inside a transaction.
+#[cfg(target_os = "none")]
+pub fn umount(path: &Path) -> Result<(), ()> {
+    let (_, ip) = path.namei().ok_or(())?;
+    let dev = ip.dev;
+    if dev == ROOTDEV || ip.inum != ROOTINO {
+        return Err(());
+    }
+    drop(ip);
+
+    let m = {
+        let mut mounts = MOUNTS.lock();
+        let i = mounts.iter().position(|m| m.dev == dev).ok_or(())?;
+        // nobody can cross into it while MOUNTS is held.
+        if ITABLE.lock().iter().flatten().any(|ip| ip.dev == dev) {
+            return Err(());
+        }
+        SBS.lock()[(dev - ROOTDEV) as usize] = None;
+        mounts.swap_remove(i)
+    };
+    // nothing holds them; what is left goes when the device does.
+    BCACHE.invalidate(dev).ok();
+    drop(m); // the directory mounted on, out of the lock
+    Ok(())
+}
+
+// Is the file system on dev mounted?
+#[cfg(target_os = "none")]
+pub fn is_mounted(dev: u32) -> bool {
+    MOUNTS.lock().iter().any(|m| m.dev == dev)
+}
+
+// Is something mounted on ip?
+#[cfg(target_os = "none")]
+fn mounted_on(ip: &Inode) -> bool {
+    MOUNTS
+        .lock()
+        .iter()
+        .any(|m| m.on.dev == ip.dev && m.on.inum == ip.inum)
+}
+
+// The directory the file system on dev is mounted on.
+#[cfg(target_os = "none")]
+fn covered(dev: u32) -> Option<Inode> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|m| m.dev == dev)
+        .map(|m| m.on.dup())
+}
+
+// The root of what is mounted on ip, or ip if nothing is.
+// Must be called inside a transaction.
+#[cfg(target_os = "none")]
+fn cross(ip: Inode) -> Inode {
+    let mounts = MOUNTS.lock();
+    let Some(m) = mounts
+        .iter()
+        .find(|m| m.on.dev == ip.dev && m.on.inum == ip.inum)
+    else {
+        return ip;
+    };
+    let root = ITABLE.get(m.dev, ROOTINO);
+    drop(mounts);
+    drop(ip);
+    root
+}
+
+// Look up name in the directory ip, locked as guard, and unlock
+// it. A directory something is mounted on leads to the mounted
+// root, and ".." of a mounted root to the parent of the directory
+// it is mounted on.
+// Must be called inside a transaction.
+#[cfg(target_os = "none")]
+fn lookup(ip: &Inode, mut guard: SleepLockGuard<'_, IData>, name: &str) -> Option<Inode> {
+    if name == ".." && ip.inum == ROOTINO && ip.dev != ROOTDEV {
+        SleepLock::unlock(guard);
+        let on = covered(ip.dev)?;
+        let parent = on.lock().dirlookup("..", None);
+        return parent;
+    }
+    let nip = guard.dirlookup(name, None);
+    SleepLock::unlock(guard);
+    nip.map(cross)
+}
+
 // Paths
 // A slice of a path (akin to str)
 #[cfg(target_os = "none")]
@@ -928,26 +1593,20 @@ impl Path {
 
         let mut path = path;
         loop {
-            let mut guard = ip.lock();
+            let guard = ip.lock();
             if guard.itype != IType::Dir {
                 return None;
             }
             match path.skip_elem() {
-                (Some(name), Some(npath)) => {
-                    if let Some(nip) = guard.dirlookup(name, None) {
-                        SleepLock::unlock(guard);
+                (Some(name), Some(npath)) => match lookup(&ip, guard, name) {
+                    Some(nip) => {
                         ip = nip;
                         path = npath;
-                        continue;
                     }
-                    break None;
-                }
+                    None => break None,
+                },
                 (Some(name), None) if !parent => {
-                    if let Some(ip) = guard.dirlookup(name, None) {
-                        SleepLock::unlock(guard);
-                        break Some((name, ip));
-                    }
-                    break None;
+                    break lookup(&ip, guard, name).map(|ip| (name, ip));
                 }
                 (Some(name), None) => {
                     SleepLock::unlock(guard);
@@ -966,3 +1625,45 @@ impl Path {
         Self::namex(self, true)
     }
 }
+
+// Reconstruct the absolute path of the directory dp into buf,
+// by walking ".." up to the root and looking up the name of each
+// child in its parent. The root is the root inode of ROOTDEV;
+// from the root of a mounted file system the walk goes on from
+// the directory it is mounted on.
+// Returns the path, or Err if it doesn't fit in buf.
+// Must be called inside a transaction.
+#[cfg(target_os = "none")]
+pub fn getcwd<'a>(dp: &Inode, buf: &'a mut [u8]) -> Result<&'a str, ()> {
+    let mut name = [0u8; DIRSIZ];
+    let mut end = buf.len();
+    let mut ip = dp.dup();
+
+    while !(ip.dev == ROOTDEV && ip.inum == ROOTINO) {
+        if ip.inum == ROOTINO {
+            ip = covered(ip.dev).ok_or(())?;
+            continue;
+        }
+        let parent = ip.lock().dirlookup("..", None).ok_or(())?;
+        let len = parent.lock().dirname(ip.inum, &mut name).ok_or(())?;
+        if len + 1 > end {
+            return Err(());
+        }
+        buf[end - len..end].copy_from_slice(&name[..len]);
+        buf[end - len - 1] = b'/';
+        end -= len + 1;
+        ip = parent;
+    }
+
+    if end == buf.len() {
+        // cwd is the root
+        if end == 0 {
+            return Err(());
+        }
+        end -= 1;
+        buf[end] = b'/';
+    }
+    let len = buf.len() - end;
+    buf.copy_within(end.., 0);
+    core::str::from_utf8(&buf[..len]).or(Err(()))
+}
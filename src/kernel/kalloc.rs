@@ -2,11 +2,13 @@
 // kernel stacks, page-tables,
 // and pipe buffers. Allocates whole 4096-byte pages.
 
+use crate::bio::BCACHE;
 use crate::buddy::BuddyAllocator;
 use crate::memlayout::PHYSTOP;
 use crate::spinlock::Mutex;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 extern "C" {
     // first address after kernel.
@@ -14,6 +16,12 @@ extern "C" {
     static mut end: [u8; 0];
 }
 
+// buffers to free at a time when memory runs out.
+const RECLAIM: usize = 32;
+
+// end of the memory managed; below PHYSTOP if a RAM disk sits on top.
+static TOP: AtomicUsize = AtomicUsize::new(PHYSTOP);
+
 #[global_allocator]
 pub static KMEM: Kmem = Kmem(Mutex::new(BuddyAllocator::new(), "kmem"));
 
@@ -26,18 +34,30 @@ pub struct Kmem(Mutex<BuddyAllocator>);
 
 unsafe impl GlobalAlloc for Kmem {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0
-            .lock()
-            .alloc(layout)
-            .map_or(ptr::null_mut(), |p| p.as_ptr())
+        loop {
+            let p = self.0.lock().alloc(layout);
+            match p {
+                Some(p) => return p.as_ptr(),
+                // out of memory: give back cached disk blocks and retry.
+                None if BCACHE.shrink(RECLAIM) > 0 => continue,
+                None => return ptr::null_mut(),
+            }
+        }
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.0.lock().dealloc(ptr, layout)
     }
 }
 
-pub fn init() {
+// Manage the memory from the end of the kernel to top.
+pub fn init(top: usize) {
+    TOP.store(top, Ordering::Relaxed);
     unsafe {
-        KMEM.0.lock().init(end.as_ptr() as usize, PHYSTOP).unwrap();
+        KMEM.0.lock().init(end.as_ptr() as usize, top).unwrap();
     }
 }
+
+// Bytes of memory the allocator manages.
+pub fn heap_size() -> usize {
+    TOP.load(Ordering::Relaxed) - ptr::addr_of!(end) as usize
+}
@@ -21,8 +21,6 @@ pub mod entry;
 pub mod kernelvec;
 #[cfg(target_os = "none")]
 pub mod memlayout;
-#[cfg(target_os = "none")]
-pub mod mpmc;
 pub mod param;
 #[cfg(target_os = "none")]
 pub mod proc;
@@ -41,10 +39,12 @@ pub mod uart;
 #[cfg(target_os = "none")]
 #[macro_use]
 pub mod printf;
-#[cfg(target_os = "none")]
 pub mod bio;
+pub mod blockdev;
 #[cfg(target_os = "none")]
 pub mod buddy;
+#[cfg(all(target_os = "none", feature = "crashtest"))]
+pub mod crashtest;
 pub mod defs;
 #[cfg(target_os = "none")]
 pub mod elf;
@@ -52,6 +52,8 @@ pub mod elf;
 pub mod exec;
 #[cfg(target_os = "none")]
 pub mod fcntl;
+#[cfg(target_os = "none")]
+pub mod fdt;
 pub mod file;
 pub mod fs;
 #[cfg(target_os = "none")]
@@ -61,11 +63,20 @@ pub mod list;
 #[cfg(target_os = "none")]
 pub mod log;
 #[cfg(target_os = "none")]
+pub mod loopdev;
+#[cfg(target_os = "none")]
+pub mod net;
+#[cfg(target_os = "none")]
 pub mod pipe;
 #[cfg(target_os = "none")]
 pub mod plic;
+pub mod ramdisk;
 #[cfg(target_os = "none")]
 pub mod riscv;
+#[cfg(target_os = "none")]
+pub mod sock;
+#[cfg(target_os = "none")]
+pub mod socket;
 pub mod stat;
 #[cfg(target_os = "none")]
 pub mod swtch;
@@ -74,12 +85,20 @@ pub mod sync;
 #[cfg(target_os = "none")]
 pub mod syscall;
 #[cfg(target_os = "none")]
+pub mod tcp;
+#[cfg(target_os = "none")]
 pub mod trampoline;
 #[cfg(target_os = "none")]
 pub mod trap;
 #[cfg(target_os = "none")]
+pub mod unix;
+#[cfg(target_os = "none")]
+pub mod virtio;
+#[cfg(target_os = "none")]
 pub mod virtio_disk;
 #[cfg(target_os = "none")]
+pub mod virtio_net;
+#[cfg(target_os = "none")]
 pub mod vm;
 
 #[macro_export]
@@ -1,18 +1,21 @@
 use crate::{
     bio::{BufGuard, BCACHE},
-    fs::{BSIZE, SB},
-    param::{LOGSIZE, MAXOPBLOCKS, ROOTDEV},
-    proc::{Process, CPUS, PROCS},
-    spinlock::Mutex,
+    blockdev,
+    fs::{self, LogHeader, BSIZE, LOGMAXBLOCKS},
+    param::{COMMIT_INTERVAL, MAXOPBLOCKS, NBUF, ROOTDEV},
+    proc::{self, Process, CPUS, PROCS},
+    spinlock::{Mutex, MutexGuard},
     sync::LazyLock,
+    trap::TICKS,
 };
-use core::ops::{Deref, DerefMut};
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::Deref;
 
 // Simple logging that allows concurrent FS system calls.
 //
 // A log transaction contains the updates of multiple FS system
-// calls. The logging system only commits when there are
-// no FS system calls active. Thus there is never
+// calls. The logging system only closes a transaction when there
+// are no FS system calls active. Thus there is never
 // any reasoning required about whether a commit might
 // write an uncommitted system call's updates to disk.
 //
@@ -22,6 +25,24 @@ use core::ops::{Deref, DerefMut};
 // But if it thinks the log is close to running out, it
 // sleeps until the last outstanding end_op() commits.
 //
+// Commits are deferred, so that one transaction groups the
+// updates of many system calls. The last outstanding end_op()
+// commits only if the log is nearly full, if the transaction
+// has been open for COMMIT_INTERVAL ticks, or if a commit was
+// asked for: by begin_op() running out of space, or by sync()
+// on behalf of fsync/fdatasync/sync. The flusher, a kernel
+// process woken by every clock tick, commits transactions that
+// nobody ends, so none stays open much longer than COMMIT_INTERVAL
+// even while no process runs.
+//
+// There are two transactions in memory: the open one, which
+// system calls add blocks to, and the closed one, which is being
+// committed. Closing a transaction copies its blocks, so that
+// system calls of the next transaction can go on changing them
+// in the cache while the copies are written to the log and then
+// to their home locations. Only one transaction is committed at
+// a time, since there is only one log on disk.
+//
 // The log is a physical re-do log containing disk blocks.
 // The on-disk log format:
 //   header block, containing block #s for block A, B, C, ...
@@ -29,68 +50,144 @@ use core::ops::{Deref, DerefMut};
 //   block B
 //   block C
 //   ...
-// Log appends are synchronous.
+// Log appends are synchronous. The size of the log is
+// read from the superblock.
+//
+// The header carries the sequence number of its transaction, a
+// checksum of each logged block and a checksum of itself. Block
+// checksums are seeded with the sequence number and the home block
+// number, so a stale log block left by an earlier transaction does
+// not match either. recover() replays a transaction only if all of
+// them match; otherwise the header or some log block was torn by a
+// crash before the commit point, and the transaction is dropped.
+//
+// The disk may keep writes in a cache of its own, so a commit flushes
+// it before the header is written, before the blocks are installed,
+// and before the header is cleared. Blocks the transaction freed are
+// discarded once it is on disk, if they have not been allocated again.
 
-pub static LOG: LazyLock<Mutex<Log>> = LazyLock::new(|| Mutex::new(Log::new(ROOTDEV), "log"));
+// clock ticks not yet seen by the flusher
+static FLUSH: Mutex<usize> = Mutex::new(0, "flush");
 
-// Contents of the header block, used for both the on-disk header block
-// and to keep track in memory of logged block# before commit.
-#[repr(C)]
-#[derive(Default, Debug, Clone, Copy)]
-struct LogHeader {
-    n: u32,
-    block: [u32; LOGSIZE],
-}
+pub static LOG: LazyLock<Mutex<Log>> = LazyLock::new(|| Mutex::new(Log::new(ROOTDEV), "log"));
 
 pub struct Log {
     start: u32,
-    size: u32,
+    cap: usize, // max blocks of a transaction
     dev: u32,
     outstanding: u32,
-    committing: bool,
+    reserved: usize,  // blocks reserved by outstanding operations
+    committing: bool, // the closed transaction is being committed
+    closing: bool,    // the open transaction is being copied; wait
+    force: bool,      // commit as soon as outstanding drops to 0
+    seq: usize,       // sequence number of the open transaction
+    committed: usize, // sequence number of the last committed transaction
+    opened: usize,    // ticks when the open transaction logged its first block
+    lh: LogHeader,    // the open transaction
+    freed: Vec<u32>,  // blocks the open transaction frees
+}
+
+// A closed transaction. Its committer owns it,
+// and writes it to disk without holding the log lock.
+struct Trans {
+    dev: u32,
+    start: u32,
     lh: LogHeader,
+    data: Vec<Box<[u8; BSIZE]>>, // copies of the blocks, taken at close
+    freed: Vec<u32>,             // blocks to discard once committed
 }
 
-impl Log {
-    fn new(dev: u32) -> Self {
-        let sb = SB.get().unwrap();
-        let mut log = Self {
-            start: sb.logstart,
-            size: sb.nlog,
+impl Trans {
+    // The log and the blocks it installs are on the disk, as
+    // fs::init() has checked, and it is writable. Failing to
+    // read or write one leaves the commit half done, with no
+    // way on.
+    fn read(&self, blockno: u32) -> BufGuard {
+        BCACHE
+            .read(self.dev, blockno)
+            .unwrap_or_else(|_| panic!("log: cannot read block {}", blockno))
+    }
+
+    fn write(b: &mut BufGuard) {
+        if b.write().is_err() {
+            panic!("log: cannot write block {}", b.blockno());
+        }
+    }
+
+    fn new(dev: u32, start: u32) -> Self {
+        Self {
             dev,
-            outstanding: 0,
-            committing: false,
-            lh: LogHeader {
-                n: 0,
-                block: [0; LOGSIZE],
-            },
-        };
-        log.recover();
-        log
+            start,
+            lh: Default::default(),
+            data: Vec::new(),
+            freed: Vec::new(),
+        }
     }
 
-    fn recover(&mut self) {
-        self.read_head();
-        self.install_trans(true); // if committed, copy from log to disk
-        self.lh.n = 0;
-        self.write_head(); // clear the log
+    // Does the log hold a transaction that was completely written
+    // before the crash? An empty log has nothing to replay.
+    fn verify(&self, cap: usize) -> bool {
+        if self.lh.n == 0 {
+            return false;
+        }
+        if self.lh.n as usize > cap {
+            println!("log: bad header (n = {}), not replaying", self.lh.n);
+            return false;
+        }
+        if self.lh.checksum != self.lh.checksum() {
+            println!(
+                "log: torn header of transaction {}, not replaying",
+                self.lh.seq
+            );
+            return false;
+        }
+        for tail in 0..self.lh.n {
+            let lbuf = self.read(self.start + tail + 1);
+            if self.lh.sums[tail as usize] != self.lh.block_sum(tail as usize, &lbuf) {
+                println!(
+                    "log: torn block {} of transaction {}, not replaying",
+                    self.lh.block[tail as usize], self.lh.seq
+                );
+                return false;
+            }
+        }
+        true
     }
 
     // Read the log header from disk into in-memory log header
     fn read_head(&mut self) {
-        let buf = BCACHE.read(self.dev, self.start);
+        let buf = self.read(self.start);
         let lh = buf.align_to::<LogHeader>().get(0).unwrap();
         self.lh = *lh;
     }
 
-    // Copy comitted blocks from log to their home location
-    fn install_trans(&self, recovering: bool) {
+    // Copy the logged blocks out of the cache.
+    // Must be called before any later operation changes them.
+    fn snapshot(&mut self) {
+        for tail in 0..self.lh.n {
+            let from = self.read(self.lh.block[tail as usize]); // cache block
+            let mut data = Box::new([0; BSIZE]);
+            data.copy_from_slice(&from);
+            self.data.push(data);
+        }
+    }
+
+    // Copy comitted blocks to their home location:
+    // from the log when recovering, else from the copies.
+    fn install(&mut self, recovering: bool) {
         for tail in 0..self.lh.n {
-            let lbuf = BCACHE.read(self.dev, self.start + tail + 1); // read log block
-            let mut dbuf = BCACHE.read(self.dev, self.lh.block[tail as usize]); // read dst
-            dbuf.copy_from_slice(&lbuf); // copy block to dst
-            dbuf.write(); // write dst to disk
-            if !recovering {
+            let mut dbuf = self.read(self.lh.block[tail as usize]); // read dst
+            if recovering {
+                let lbuf = self.read(self.start + tail + 1); // read log block
+                dbuf.copy_from_slice(&lbuf); // copy block to dst
+                Self::write(&mut dbuf); // write dst to disk
+            } else {
+                // the cache may already hold a newer version
+                // of the block; write the copy in its place.
+                let data = &mut self.data[tail as usize][..];
+                dbuf.swap_with_slice(data);
+                Self::write(&mut dbuf); // write dst to disk
+                dbuf.swap_with_slice(data);
                 dbuf.unpin();
             }
         }
@@ -99,95 +196,285 @@ impl Log {
     // Write in-memory log header to disk.
     // This is the true point at which the
     // current transaction commits.
-    fn write_head(&self) {
-        let mut buf = BCACHE.read(self.dev, self.start);
+    fn write_head(&mut self) {
+        self.lh.checksum = self.lh.checksum();
+        let mut buf = self.read(self.start);
         let hb = buf.align_to_mut::<LogHeader>().get_mut(0).unwrap();
         *hb = self.lh;
-        buf.write();
+        Self::write(&mut buf);
     }
 
-    // Copy modified blocks from cache to log.
+    // Copy the blocks to log.
+    // The log blocks are written at once, merged into few requests,
+    // and all are on disk before the header is written.
     fn write_log(&mut self) {
+        let mut bufs = Vec::new();
+        let plug = blockdev::plug(self.dev);
         for tail in 0..self.lh.n {
-            let mut to = BCACHE.read(self.dev, self.start + tail + 1); // log block
-            let from = BCACHE.read(self.dev, self.lh.block[tail as usize]); // cache block
-            to.copy_from_slice(from.deref().deref());
-            to.write(); // write the log
+            let mut to = self.read(self.start + tail + 1); // log block
+            to.copy_from_slice(&self.data[tail as usize][..]);
+            self.lh.sums[tail as usize] = self.lh.block_sum(tail as usize, &to);
+            // write the log
+            if to.start_write().is_err() {
+                panic!("log: cannot write block {}", to.blockno());
+            }
+            bufs.push(to);
+        }
+        drop(plug);
+        for b in bufs.iter() {
+            if b.wait().is_err() {
+                panic!("log: cannot write block {}", b.blockno());
+            }
         }
     }
 
     fn commit(&mut self) {
         if self.lh.n > 0 {
-            self.write_log(); // Write modified blocks from cache to log
+            let disk = blockdev::get(self.dev);
+            self.write_log(); // Write the copied blocks to log
+            disk.flush();
             self.write_head(); // Wrtie header to disk -- the real commit
-            self.install_trans(false); // Now install writes to home locations
+            disk.flush();
+            self.install(false); // Now install writes to home locations
+            disk.flush();
             self.lh.n = 0;
             self.write_head();
+            self.discard();
+        }
+    }
+
+    // Discard the runs of freed blocks that are still free. Blocks
+    // allocated again since are skipped: whatever the transactions
+    // after this one write to them is installed after this commit.
+    fn discard(&mut self) {
+        let disk = blockdev::get(self.dev);
+        self.freed.sort_unstable();
+        self.freed.dedup();
+        let mut i = 0;
+        while i < self.freed.len() {
+            let start = self.freed[i];
+            let mut n = 0;
+            while i < self.freed.len()
+                && self.freed[i] == start + n
+                && fs::block_free(self.dev, self.freed[i])
+            {
+                n += 1;
+                i += 1;
+            }
+            if n == 0 {
+                i += 1;
+            } else {
+                // only a hint; the blocks are free either way.
+                let _ = disk.discard(start, n);
+            }
         }
     }
 }
 
+impl Log {
+    fn new(dev: u32) -> Self {
+        let sb = fs::sb(dev);
+        let mut log = Self {
+            start: sb.logstart,
+            // one block of the log is the header. Blocks of both
+            // transactions stay pinned in the buffer cache until
+            // they are installed, so leave room there for others.
+            cap: (sb.nlog as usize - 1)
+                .min(LOGMAXBLOCKS)
+                .min((NBUF - MAXOPBLOCKS * 3) / 2),
+            dev,
+            outstanding: 0,
+            reserved: 0,
+            committing: false,
+            closing: false,
+            force: false,
+            seq: 1,
+            committed: 0,
+            opened: 0,
+            lh: Default::default(),
+            freed: Vec::new(),
+        };
+        assert!(log.cap >= MAXOPBLOCKS, "initlog: too small log");
+        log.recover();
+        log
+    }
+
+    fn recover(&mut self) {
+        let mut trans = Trans::new(self.dev, self.start);
+        trans.read_head();
+        if trans.verify(self.cap) {
+            trans.install(true); // if committed, copy from log to disk
+            blockdev::get(self.dev).flush();
+        }
+        // go on numbering after the last transaction
+        self.seq = trans.lh.seq as usize + 1;
+        self.committed = trans.lh.seq as usize;
+        trans.lh.n = 0;
+        trans.write_head(); // clear the log
+    }
+
+    // Does the open transaction have to be committed
+    // once outstanding drops to 0?
+    fn should_commit(&self) -> bool {
+        self.lh.n > 0
+            && (self.force
+                || self.lh.n as usize + MAXOPBLOCKS > self.cap
+                || *TICKS.lock() - self.opened >= COMMIT_INTERVAL)
+    }
+
+    // Can the open transaction be closed and committed now?
+    fn can_commit(&self) -> bool {
+        !self.committing && self.outstanding == 0
+    }
+}
+
 impl Mutex<Log> {
     pub fn init(&self) {
         // SyncLazy initialization
         assert!(
-            core::mem::size_of::<LogHeader>() >= BSIZE,
+            core::mem::size_of::<LogHeader>() <= BSIZE,
             "initlog: too big log header"
         );
     }
+
+    // Blocks that a big operation, like a chunk of a file write,
+    // may reserve with begin_op_n(): a share of the log, so that
+    // several of them fit in one transaction.
+    pub fn op_blocks(&self) -> usize {
+        core::cmp::max(MAXOPBLOCKS, self.lock().cap / 4)
+    }
+
     // called at the start of each FS system call.
     pub fn begin_op(&self) {
+        self.begin_op_n(MAXOPBLOCKS)
+    }
+
+    // called at the start of an FS operation that
+    // writes at most nblocks blocks.
+    pub fn begin_op_n(&self, nblocks: usize) {
         let mut guard = self.lock();
         let p = CPUS.my_proc().unwrap();
+        assert!(nblocks <= guard.cap, "begin_op: too big an operation");
         loop {
-            if guard.committing {
+            if guard.closing {
                 guard = p.sleep(guard.deref() as *const _ as usize, guard);
-            } else if (guard.lh.n as usize + (guard.outstanding + 1) as usize * MAXOPBLOCKS)
-                > LOGSIZE
-            {
+            } else if guard.lh.n as usize + guard.reserved + nblocks > guard.cap {
                 // this op might exhaust log space; wait for commit.
-                guard = p.sleep(guard.deref() as *const _ as usize, guard);
+                if guard.can_commit() {
+                    guard = self.commit_trans(guard);
+                } else {
+                    guard.force = true;
+                    guard = p.sleep(guard.deref() as *const _ as usize, guard);
+                }
             } else {
                 guard.outstanding += 1;
+                guard.reserved += nblocks;
                 break;
             }
         }
     }
 
     // called at the end of each FS system call.
-    // commits if this was the last outstanding operation.
+    // commits if this was the last outstanding operation
+    // and the transaction should not stay open any longer.
     pub fn end_op(&self) {
-        let mut log: Option<*mut Log> = None;
+        self.end_op_n(MAXOPBLOCKS)
+    }
 
-        {
-            let mut guard = self.lock();
-            guard.outstanding -= 1;
-            if guard.committing {
-                panic!("log.commiting");
-            }
-            if guard.outstanding == 0 {
-                log.replace(guard.deref_mut() as *mut _);
-                guard.committing = true;
+    // called at the end of an operation started by begin_op_n(nblocks).
+    pub fn end_op_n(&self, nblocks: usize) {
+        let mut guard = self.lock();
+        guard.outstanding -= 1;
+        guard.reserved -= nblocks;
+        if guard.closing {
+            panic!("log.closing");
+        }
+        if guard.can_commit() && guard.should_commit() {
+            guard = self.commit_trans(guard);
+        }
+        // begin_op() may be waiting for log space,
+        // and decrementing log.outstanding has decreased
+        // the amount of reserved space.
+        PROCS.wakeup(guard.deref() as *const _ as usize);
+    }
+
+    // Commit the open transaction if it has been open for too long
+    // and no FS system call is active. Called by the flusher.
+    pub fn commit_expired(&self) {
+        let guard = self.lock();
+        if guard.can_commit() && guard.should_commit() {
+            self.commit_trans(guard);
+        }
+    }
+
+    // Force a commit of everything logged so far and wait until
+    // it is on disk. Must not be called inside a transaction.
+    // The log holds data and metadata blocks alike, so this is
+    // all that fsync, fdatasync and sync need.
+    pub fn sync(&self) {
+        let mut guard = self.lock();
+        let p = CPUS.my_proc().unwrap();
+        // the transaction holding the caller's updates:
+        // the open one, or else the one being committed.
+        let target = if guard.lh.n > 0 {
+            guard.seq
+        } else {
+            guard.seq - 1
+        };
+        while guard.committed < target {
+            if guard.can_commit() {
+                guard = self.commit_trans(guard);
             } else {
-                // begin_op() may be waiting for log space,
-                // and decrementing log.outstanding has decreased
-                // the amount of reserved space.
-                PROCS.wakeup(guard.deref() as *const _ as usize);
+                guard.force = true;
+                guard = p.sleep(guard.deref() as *const _ as usize, guard);
             }
         }
+    }
+
+    // Close the open transaction and commit it, then the next one
+    // too if it has become due in the meantime.
+    // Caller holds the log lock, and can_commit() is true.
+    // Returns with the log lock held again.
+    fn commit_trans<'a>(&'a self, mut guard: MutexGuard<'a, Log>) -> MutexGuard<'a, Log> {
+        loop {
+            let seq = guard.seq;
+            let mut trans = Trans::new(guard.dev, guard.start);
+            trans.lh = core::mem::take(&mut guard.lh);
+            trans.freed = core::mem::take(&mut guard.freed);
+            trans.lh.seq = seq as u64;
+            guard.committing = true;
+            guard.closing = true;
+            guard.force = false;
+            guard.seq += 1;
+            Mutex::unlock(guard);
 
-        if let Some(log) = log {
-            // call commit w/o holding locks, since not allowed
+            // copy and commit w/o holding locks, since not allowed
             // to sleep with locks.
-            unsafe {
-                log.as_mut().unwrap().commit();
+            trans.snapshot();
+            {
+                // the next transaction may start now.
+                let mut guard = self.lock();
+                guard.closing = false;
+                PROCS.wakeup(guard.deref() as *const _ as usize);
             }
-            let mut guard = self.lock();
+            trans.commit();
+
+            guard = self.lock();
             guard.committing = false;
+            guard.committed = seq;
             PROCS.wakeup(guard.deref() as *const _ as usize);
+            if !(guard.can_commit() && guard.should_commit()) {
+                break guard;
+            }
         }
     }
 
+    // Record that the open transaction frees block b, so that
+    // the disk is told once the transaction is committed.
+    pub fn free(&self, b: u32) {
+        self.lock().freed.push(b);
+    }
+
     // Caller has modified b->data and is done with the buffer.
     // Record the block number and pin in the cache by increasing refcnt.
     // commit()/write() will do the disk write.
@@ -198,12 +485,16 @@ impl Mutex<Log> {
     // LOG.write(bp)
     pub fn write(&self, b: BufGuard) {
         let mut guard = self.lock();
-        if guard.lh.n as usize >= LOGSIZE || guard.lh.n >= guard.size - 1 {
+        if guard.lh.n as usize >= guard.cap {
             panic!("too big a transaction");
         }
         if guard.outstanding < 1 {
             panic!("LOG.write outside of trans");
         }
+        // install() would write it to the log's own disk.
+        if b.dev() != guard.dev {
+            panic!("LOG.write of another device");
+        }
 
         let blockno = b.blockno();
         for i in 0..guard.lh.n {
@@ -213,8 +504,34 @@ impl Mutex<Log> {
             }
         }
         let n = guard.lh.n as usize;
+        if n == 0 {
+            // the first block of the transaction starts its interval
+            guard.opened = *TICKS.lock();
+        }
         guard.lh.block[n] = blockno;
         b.pin();
         guard.lh.n += 1;
     }
 }
+
+// Called by clockintr() on every tick; must not sleep.
+pub fn timer() {
+    *FLUSH.lock() += 1;
+    PROCS.wakeup(&FLUSH as *const _ as usize);
+}
+
+// The flusher: commits expired transactions as the clock ticks.
+pub extern "C" fn flusher() -> ! {
+    proc::kthread_ret();
+    let p = CPUS.my_proc().unwrap();
+    loop {
+        {
+            let mut ticks = FLUSH.lock();
+            while *ticks == 0 {
+                ticks = p.sleep(&FLUSH as *const _ as usize, ticks);
+            }
+            *ticks = 0;
+        }
+        LOG.commit_expired();
+    }
+}
@@ -0,0 +1,193 @@
+// Loop devices.
+//
+// A loop device presents a regular file as a block device, so a
+// file system image kept in a file can be used like a disk. They
+// are set up through the loop control device, a device file made
+// with mknod(path, Major::Loop, 0):
+//   ioctl(ctl, LOOP_SET_FD, fd) binds the file open as fd to a
+//   free loop device and returns its device number;
+//   ioctl(ctl, LOOP_CLR_FD, dev) unbinds it again.
+// A loop device is registered as a block device the first time
+// it is bound, and keeps its number when unbound. The file system
+// in the file can then be mounted, read-only (see fs::mount()).
+// A loop device cannot be unbound while it is mounted, and its
+// file cannot be truncated while it is bound.
+//
+// Blocks are copied to or from the file by the time they are
+// started. A write is a transaction of the file system holding
+// the file, so none may be started inside a transaction.
+
+use crate::{
+    array,
+    bio::{Data, BCACHE},
+    blockdev::{self, BlockDevice},
+    fcntl::ioctl,
+    file::{Device, File, Major, DEVSW},
+    fs::{self, BSIZE},
+    param::NLOOP,
+    proc::CPUS,
+    sleeplock::SleepLock,
+    spinlock::Mutex,
+    stat::{IType, Stat},
+    vm::VirtAddr,
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub static LOOPS: [Loop; NLOOP] = array![Loop::new(); NLOOP];
+
+static LOOPCTL: LoopControl = LoopControl;
+
+struct Backing {
+    file: File,
+    nblocks: u32,
+}
+
+pub struct Loop {
+    backing: SleepLock<Option<Backing>>,
+    dev: AtomicU32, // 0 until first bound
+    // device and inode number of the file bound, apart from
+    // backing so that truncate can check it with the inode locked.
+    file: Mutex<Option<(u32, u32)>>,
+}
+
+impl Loop {
+    const fn new() -> Self {
+        Self {
+            backing: SleepLock::new(None, "loop"),
+            dev: AtomicU32::new(0),
+            file: Mutex::new(None, "loopfile"),
+        }
+    }
+
+    // Bind file, if the device is free; returns its device number.
+    fn bind(&'static self, file: &File) -> Option<Result<u32, ()>> {
+        let mut backing = self.backing.lock();
+        if backing.is_some() {
+            return None;
+        }
+        let mut st = Stat::default();
+        if file
+            .stat(VirtAddr::Kernel(&mut st as *mut _ as usize))
+            .is_err()
+            || st.itype != IType::File
+            || !file.is_readable()
+        {
+            return Some(Err(()));
+        }
+        let Ok(nblocks) = u32::try_from(st.size / BSIZE) else {
+            return Some(Err(()));
+        };
+        if self.dev.load(Ordering::Relaxed) == 0 {
+            let Some(dev) = blockdev::register(self) else {
+                return Some(Err(()));
+            };
+            self.dev.store(dev, Ordering::Relaxed);
+        }
+        backing.replace(Backing {
+            file: file.clone(),
+            nblocks,
+        });
+        self.file.lock().replace((st.dev, st.ino));
+        Some(Ok(self.dev.load(Ordering::Relaxed)))
+    }
+
+    // Unbind the file. Fails if the device is mounted, or blocks
+    // of it are in use.
+    fn unbind(&self) -> Result<(), ()> {
+        let mut backing = self.backing.lock();
+        let dev = self.dev.load(Ordering::Relaxed);
+        if backing.is_none() || fs::is_mounted(dev) {
+            return Err(());
+        }
+        BCACHE.invalidate(dev)?;
+        self.file.lock().take();
+        backing.take(); // closes the file
+        Ok(())
+    }
+}
+
+impl BlockDevice for Loop {
+    // Fails if the device is not bound, or the file cannot be
+    // read or written there.
+    fn start_rw(&self, b: &mut Data, write: bool) -> Result<(), ()> {
+        let backing = self.backing.lock();
+        let backing = backing.as_ref().ok_or(())?;
+        let blockno = b.blockno();
+        if blockno >= backing.nblocks {
+            return Err(());
+        }
+        let off = blockno * BSIZE as u32;
+        let addr = VirtAddr::Kernel(b.data.as_mut_ptr() as usize);
+        let r = if write {
+            backing.file.write_at(addr, off, BSIZE)
+        } else {
+            backing.file.read_at(addr, off, BSIZE)
+        };
+        match r {
+            Ok(BSIZE) => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    // nothing is ever in flight.
+    fn wait(&self, _data: &Data) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn capacity(&self) -> u32 {
+        self.backing.lock().as_ref().map_or(0, |b| b.nblocks)
+    }
+
+    fn readonly(&self) -> bool {
+        self.backing
+            .lock()
+            .as_ref()
+            .map_or(true, |b| !b.file.is_writable())
+    }
+}
+
+// Does the file with inode number inum on dev back a loop device?
+pub fn backs(dev: u32, inum: u32) -> bool {
+    LOOPS.iter().any(|l| *l.file.lock() == Some((dev, inum)))
+}
+
+struct LoopControl;
+
+impl Device for LoopControl {
+    fn read(&self, _dst: VirtAddr, _n: usize, _nonblock: bool) -> Result<usize, ()> {
+        Err(())
+    }
+
+    fn write(&self, _src: VirtAddr, _n: usize, _nonblock: bool) -> Result<usize, ()> {
+        Err(())
+    }
+
+    fn major(&self) -> Major {
+        Major::Loop
+    }
+
+    fn ioctl(&self, req: usize, arg: usize) -> Result<usize, ()> {
+        match req {
+            ioctl::LOOP_SET_FD => {
+                let data = CPUS.my_proc().unwrap().data();
+                let file = data.ofile.get(arg).and_then(|f| f.as_ref()).ok_or(())?;
+                LOOPS
+                    .iter()
+                    .find_map(|l| l.bind(file))
+                    .unwrap_or(Err(()))
+                    .map(|dev| dev as usize)
+            }
+            ioctl::LOOP_CLR_FD => LOOPS
+                .iter()
+                .find(|l| arg != 0 && l.dev.load(Ordering::Relaxed) as usize == arg)
+                .ok_or(())?
+                .unbind()
+                .and(Ok(0)),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn init() {
+    DEVSW.set(Major::Loop, &LOOPCTL).unwrap();
+}
@@ -5,9 +5,9 @@ extern crate alloc;
 
 use core::sync::atomic::{AtomicBool, Ordering};
 use kernel::{
-    bio, console, kalloc, kmain, plic, print, println,
+    bio, console, kalloc, kmain, loopdev, plic, print, println,
     proc::{self, scheduler, Cpus},
-    trap, virtio_disk, vm,
+    ramdisk, trap, virtio_disk, virtio_net, vm,
 };
 
 static STARTED: AtomicBool = AtomicBool::new(false);
@@ -21,7 +21,8 @@ extern "C" fn main() -> ! {
         println!("");
         println!("octox kernel is booting");
         println!("");
-        kalloc::init(); // physical memory allocator
+        let top = ramdisk::init(); // keep the initrd, if any
+        kalloc::init(top); // physical memory allocator
         vm::kinit(); // create kernel page table
         vm::kinithart(); // turn on paging
         proc::init(); // process table
@@ -30,6 +31,8 @@ extern "C" fn main() -> ! {
         plic::inithart(); // ask PLIC for device interrupts
         bio::init(); // buffer cache
         virtio_disk::init(); // emulated hard disk
+        loopdev::init(); // loop devices
+        virtio_net::init(); // emulated network card
         STARTED.store(true, Ordering::SeqCst);
     } else {
         while !STARTED.load(Ordering::SeqCst) {}
@@ -6,6 +6,7 @@ use crate::vm::{KVAddr, VAddr};
 // based on qemu's hw/riscv/virt.c:
 //
 // 00001000 -- boot ROM, provided by qemu
+// 00100000 -- test device
 // 02000000 -- CLINT
 // 0C000000 -- PLIC
 // 10000000 -- uart0
@@ -19,13 +20,21 @@ use crate::vm::{KVAddr, VAddr};
 // end -- start of kernel page allocation area
 // PHYSTOP -- end RAM used by the kernel
 
+// qemu's test device; writing to it powers the machine off.
+pub const TEST: usize = 0x10_0000;
+
 // qemu puts UART registers here in physical memory.
 pub const UART0: usize = 0x1000_0000;
 pub const UART0_IRQ: u32 = 10;
 
-// virtio mmio interface
+// virtio mmio interface: NVIRTIO slots of a page each,
+// slot i interrupting with VIRTIO0_IRQ + i.
 pub const VIRTIO0: usize = 0x1000_1000;
 pub const VIRTIO0_IRQ: u32 = 1;
+pub const NVIRTIO: usize = 8;
+pub const fn virtio(slot: usize) -> usize {
+    VIRTIO0 + 0x1000 * slot
+}
 
 // core local interrupter (CLINT), which contains the timer
 pub const CLINT: usize = 0x2000000;
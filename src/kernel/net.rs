@@ -0,0 +1,439 @@
+// A small network stack: Ethernet, ARP, IPv4, ICMP echo and UDP,
+// over the virtio network device (virtio_net.rs). TCP is in tcp.rs,
+// and the sockets of user programs in sock.rs.
+//
+// The configuration is fixed for qemu's user-mode network (slirp):
+// octox is 10.0.2.15 on 10.0.2.0/24, and reaches any other address
+// through the gateway at 10.0.2.2.
+//
+// Received frames are handled in the device interrupt: ARP requests
+// and ICMP echo requests are answered there, and UDP datagrams are
+// queued on the socket bound to their port. Packets for an address
+// whose Ethernet address is not known yet wait for the ARP reply.
+// IP fragments and options are not supported; such packets are
+// dropped.
+
+use crate::{
+    proc::{Process, CPUS, PROCS},
+    spinlock::Mutex,
+    tcp, virtio_net,
+};
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+pub const fn ip(a: u8, b: u8, c: u8, d: u8) -> u32 {
+    u32::from_be_bytes([a, b, c, d])
+}
+
+pub const LOCAL: u32 = ip(10, 0, 2, 15);
+pub const GATEWAY: u32 = ip(10, 0, 2, 2);
+pub const NETMASK: u32 = ip(255, 255, 255, 0);
+pub const BROADCAST: u32 = ip(255, 255, 255, 255);
+
+pub const MTU: usize = 1500; // largest IP packet
+
+// Ethernet
+pub const ETH_ALEN: usize = 6;
+pub const ETH_HLEN: usize = 14;
+const ETH_BROADCAST: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+const ETHERTYPE_IP: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+// ARP, for IPv4 over Ethernet
+const ARP_LEN: usize = 28;
+const ARP_HRD_ETHER: u16 = 1;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+const NARP: usize = 16; // cached addresses
+const NPENDING: usize = 16; // packets waiting for ARP replies
+
+// IPv4
+pub const IP_HLEN: usize = 20;
+const IP_TTL: u8 = 64;
+const IP_DF: u16 = 0x4000; // don't fragment
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+// ICMP
+const ICMP_HLEN: usize = 8;
+const ICMP_ECHOREPLY: u8 = 0;
+const ICMP_ECHO: u8 = 8;
+
+// UDP
+const UDP_HLEN: usize = 8;
+pub const UDP_MAXDATA: usize = MTU - IP_HLEN - UDP_HLEN;
+const UDP_MAXQUEUE: usize = 64; // datagrams queued per socket
+pub const EPHEMERAL: u16 = 49152; // ports given out from here on
+
+// big-endian fields
+pub fn get16(b: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([b[off], b[off + 1]])
+}
+pub fn get32(b: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(b[off..off + 4].try_into().unwrap())
+}
+pub fn put16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_be_bytes());
+}
+pub fn put32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+// The Internet checksum of data, continuing from sum.
+// A packet with its checksum in place sums to 0.
+fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [b] = chunks.remainder() {
+        sum += (*b as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// The sum of the pseudo header of a UDP or TCP segment.
+pub fn pseudo_sum(src: u32, dst: u32, proto: u8, len: usize) -> u32 {
+    (src >> 16) + (src & 0xffff) + (dst >> 16) + (dst & 0xffff) + proto as u32 + len as u32
+}
+
+pub fn transport_checksum(src: u32, dst: u32, proto: u8, segment: &[u8]) -> u16 {
+    checksum(segment, pseudo_sum(src, dst, proto, segment.len()))
+}
+
+// ARP cache and the packets waiting for it.
+struct Arp {
+    cache: [(u32, [u8; ETH_ALEN]); NARP],
+    next: usize, // entry to replace next
+    pending: VecDeque<(u32, Vec<u8>)>,
+}
+
+static ARP: Mutex<Arp> = Mutex::new(
+    Arp {
+        cache: [(0, [0; ETH_ALEN]); NARP],
+        next: 0,
+        pending: VecDeque::new(),
+    },
+    "arp",
+);
+
+impl Arp {
+    fn lookup(&self, addr: u32) -> Option<[u8; ETH_ALEN]> {
+        self.cache
+            .iter()
+            .find(|(a, _)| *a == addr && addr != 0)
+            .map(|&(_, mac)| mac)
+    }
+
+    fn insert(&mut self, addr: u32, mac: [u8; ETH_ALEN]) {
+        if let Some(e) = self.cache.iter_mut().find(|(a, _)| *a == addr) {
+            e.1 = mac;
+            return;
+        }
+        self.cache[self.next] = (addr, mac);
+        self.next = (self.next + 1) % NARP;
+    }
+}
+
+fn send_eth(dst: [u8; ETH_ALEN], ethertype: u16, payload: &[u8]) -> Result<(), ()> {
+    let mac = virtio_net::mac().ok_or(())?;
+    let mut frame = vec![0u8; ETH_HLEN + payload.len()];
+    frame[0..6].copy_from_slice(&dst);
+    frame[6..12].copy_from_slice(&mac);
+    put16(&mut frame, 12, ethertype);
+    frame[ETH_HLEN..].copy_from_slice(payload);
+    virtio_net::transmit(&frame)
+}
+
+fn send_arp(op: u16, tha: [u8; ETH_ALEN], tpa: u32) -> Result<(), ()> {
+    let mac = virtio_net::mac().ok_or(())?;
+    let mut arp = [0u8; ARP_LEN];
+    put16(&mut arp, 0, ARP_HRD_ETHER);
+    put16(&mut arp, 2, ETHERTYPE_IP);
+    arp[4] = ETH_ALEN as u8;
+    arp[5] = 4;
+    put16(&mut arp, 6, op);
+    arp[8..14].copy_from_slice(&mac);
+    put32(&mut arp, 14, LOCAL);
+    arp[18..24].copy_from_slice(&tha);
+    put32(&mut arp, 24, tpa);
+    let dst = if op == ARP_OP_REQUEST {
+        ETH_BROADCAST
+    } else {
+        tha
+    };
+    send_eth(dst, ETHERTYPE_ARP, &arp)
+}
+
+fn arp_receive(arp: &[u8]) {
+    if arp.len() < ARP_LEN
+        || get16(arp, 0) != ARP_HRD_ETHER
+        || get16(arp, 2) != ETHERTYPE_IP
+        || arp[4] != ETH_ALEN as u8
+        || arp[5] != 4
+    {
+        return;
+    }
+    let op = get16(arp, 6);
+    let sha: [u8; ETH_ALEN] = arp[8..14].try_into().unwrap();
+    let spa = get32(arp, 14);
+    let tpa = get32(arp, 24);
+
+    // learn the sender, and send what waited for it.
+    let mut ready = Vec::new();
+    if spa != 0 {
+        let mut guard = ARP.lock();
+        guard.insert(spa, sha);
+        let mut i = 0;
+        while i < guard.pending.len() {
+            if guard.pending[i].0 == spa {
+                ready.push(guard.pending.remove(i).unwrap().1);
+            } else {
+                i += 1;
+            }
+        }
+    }
+    for pkt in ready {
+        let _ = send_eth(sha, ETHERTYPE_IP, &pkt);
+    }
+
+    if op == ARP_OP_REQUEST && tpa == LOCAL {
+        let _ = send_arp(ARP_OP_REPLY, sha, spa);
+    }
+}
+
+// Send an IPv4 packet carrying payload to dst.
+pub fn send_ip(dst: u32, proto: u8, payload: &[u8]) -> Result<(), ()> {
+    static ID: AtomicU16 = AtomicU16::new(1);
+
+    let total = IP_HLEN + payload.len();
+    if total > MTU {
+        return Err(());
+    }
+    let mut pkt = vec![0u8; total];
+    pkt[0] = 0x45; // version 4, 5 words of header
+    put16(&mut pkt, 2, total as u16);
+    put16(&mut pkt, 4, ID.fetch_add(1, Ordering::Relaxed));
+    put16(&mut pkt, 6, IP_DF);
+    pkt[8] = IP_TTL;
+    pkt[9] = proto;
+    put32(&mut pkt, 12, LOCAL);
+    put32(&mut pkt, 16, dst);
+    let sum = checksum(&pkt[..IP_HLEN], 0);
+    put16(&mut pkt, 10, sum);
+    pkt[IP_HLEN..].copy_from_slice(payload);
+
+    if dst == BROADCAST {
+        return send_eth(ETH_BROADCAST, ETHERTYPE_IP, &pkt);
+    }
+    let hop = if dst & NETMASK == LOCAL & NETMASK {
+        dst
+    } else {
+        GATEWAY
+    };
+    let mut guard = ARP.lock();
+    match guard.lookup(hop) {
+        Some(mac) => {
+            drop(guard);
+            send_eth(mac, ETHERTYPE_IP, &pkt)
+        }
+        None => {
+            // send it once hop answers.
+            if guard.pending.len() >= NPENDING {
+                guard.pending.pop_front();
+            }
+            guard.pending.push_back((hop, pkt));
+            drop(guard);
+            send_arp(ARP_OP_REQUEST, [0; ETH_ALEN], hop)
+        }
+    }
+}
+
+fn ip_receive(pkt: &[u8]) {
+    if pkt.len() < IP_HLEN || pkt[0] >> 4 != 4 {
+        return;
+    }
+    let hlen = (pkt[0] & 0xf) as usize * 4;
+    let total = get16(pkt, 2) as usize;
+    if hlen != IP_HLEN || total < hlen || total > pkt.len() || checksum(&pkt[..hlen], 0) != 0 {
+        return;
+    }
+    // no fragments.
+    if get16(pkt, 6) & 0x3fff != 0 {
+        return;
+    }
+    let src = get32(pkt, 12);
+    let dst = get32(pkt, 16);
+    if dst != LOCAL && dst != BROADCAST {
+        return;
+    }
+    let data = &pkt[hlen..total];
+    match pkt[9] {
+        IPPROTO_ICMP => icmp_receive(src, data),
+        IPPROTO_TCP => tcp::receive(src, dst, data),
+        IPPROTO_UDP => udp_receive(src, dst, data),
+        _ => {}
+    }
+}
+
+fn icmp_receive(src: u32, msg: &[u8]) {
+    if msg.len() < ICMP_HLEN || checksum(msg, 0) != 0 {
+        return;
+    }
+    if msg[0] == ICMP_ECHO {
+        // send the same back, as a reply.
+        let mut reply = msg.to_vec();
+        reply[0] = ICMP_ECHOREPLY;
+        put16(&mut reply, 2, 0);
+        let sum = checksum(&reply, 0);
+        put16(&mut reply, 2, sum);
+        let _ = send_ip(src, IPPROTO_ICMP, &reply);
+    }
+}
+
+// A datagram received on a UDP socket.
+pub struct Datagram {
+    pub src: u32,
+    pub sport: u16,
+    pub data: Vec<u8>,
+}
+
+// A UDP port bound for receiving, and the datagrams that came.
+pub struct UdpSocket {
+    port: u16,
+    queue: Mutex<VecDeque<Datagram>>,
+}
+
+// bound ports
+static UDP: Mutex<Vec<(u16, Weak<UdpSocket>)>> = Mutex::new(Vec::new(), "udp");
+
+impl UdpSocket {
+    // Bind port, or a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<Arc<Self>, ()> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+
+        let mut guard = UDP.lock();
+        let used = |p: u16| guard.iter().any(|(q, w)| *q == p && w.strong_count() > 0);
+        let port = if port != 0 {
+            if used(port) {
+                return Err(());
+            }
+            port
+        } else {
+            let n = (u16::MAX - EPHEMERAL) as u32 + 1;
+            (0..n)
+                .map(|_| EPHEMERAL + (NEXT.fetch_add(1, Ordering::Relaxed) % n) as u16)
+                .find(|&p| !used(p))
+                .ok_or(())?
+        };
+        let sock = Arc::new(Self {
+            port,
+            queue: Mutex::new(VecDeque::new(), "udpsock"),
+        });
+        guard.push((port, Arc::downgrade(&sock)));
+        Ok(sock)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Send data to dport at dst, from this socket's port.
+    pub fn send_to(&self, dst: u32, dport: u16, data: &[u8]) -> Result<usize, ()> {
+        if data.len() > UDP_MAXDATA {
+            return Err(());
+        }
+        let len = UDP_HLEN + data.len();
+        let mut seg = vec![0u8; len];
+        put16(&mut seg, 0, self.port);
+        put16(&mut seg, 2, dport);
+        put16(&mut seg, 4, len as u16);
+        seg[UDP_HLEN..].copy_from_slice(data);
+        let sum = match transport_checksum(LOCAL, dst, IPPROTO_UDP, &seg) {
+            0 => 0xffff, // 0 means none
+            sum => sum,
+        };
+        put16(&mut seg, 6, sum);
+        send_ip(dst, IPPROTO_UDP, &seg)?;
+        Ok(data.len())
+    }
+
+    // Take the next datagram, waiting for one unless nonblock.
+    pub fn recv_from(&self, nonblock: bool) -> Result<Datagram, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut queue = self.queue.lock();
+        loop {
+            if let Some(d) = queue.pop_front() {
+                return Ok(d);
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            queue = p.sleep(&self.queue as *const _ as usize, queue);
+        }
+    }
+
+    fn deliver(&self, d: Datagram) {
+        let mut queue = self.queue.lock();
+        if queue.len() < UDP_MAXQUEUE {
+            queue.push_back(d);
+            PROCS.wakeup(&self.queue as *const _ as usize);
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        UDP.lock().retain(|(_, w)| w.strong_count() > 0);
+    }
+}
+
+fn udp_receive(src: u32, dst: u32, seg: &[u8]) {
+    if seg.len() < UDP_HLEN {
+        return;
+    }
+    let len = get16(seg, 4) as usize;
+    if len < UDP_HLEN || len > seg.len() {
+        return;
+    }
+    let seg = &seg[..len];
+    if get16(seg, 6) != 0 && transport_checksum(src, dst, IPPROTO_UDP, seg) != 0 {
+        return;
+    }
+    let dport = get16(seg, 2);
+    let sock = UDP
+        .lock()
+        .iter()
+        .filter(|(p, _)| *p == dport)
+        .find_map(|(_, w)| w.upgrade());
+    // dropped out of the lock; it may be the last reference.
+    if let Some(sock) = sock {
+        sock.deliver(Datagram {
+            src,
+            sport: get16(seg, 0),
+            data: seg[UDP_HLEN..].to_vec(),
+        });
+    }
+}
+
+// Handle a frame the device received.
+pub fn receive(frame: &[u8]) {
+    if frame.len() < ETH_HLEN {
+        return;
+    }
+    let payload = &frame[ETH_HLEN..];
+    match get16(frame, 12) {
+        ETHERTYPE_ARP => arp_receive(payload),
+        ETHERTYPE_IP => ip_receive(payload),
+        _ => {}
+    }
+}
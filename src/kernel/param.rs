@@ -5,9 +5,12 @@ pub const NFILE: usize = 100; // open files per system
 pub const NINODE: usize = 50; // maximum number of active i-nodes
 pub const NDEV: usize = 10; // maximum major device number
 pub const ROOTDEV: u32 = 1; // device number of file system root disk
+pub const NBDEV: usize = 1 + 8 + NLOOP; // block devices: a RAM disk, a virtio disk per mmio slot, the loop devices
+pub const NLOOP: usize = 4; // number of loop devices
 pub const MAXARG: usize = 32; // max exec arguments
 pub const MAXOPBLOCKS: usize = 10; // max # of blocks any FS op writes
-pub const LOGSIZE: usize = MAXOPBLOCKS * 3; // max data blocks in on-disk log
-pub const NBUF: usize = MAXOPBLOCKS * 3; // size of disk block cache
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3; // default data blocks in on-disk log, made by mkfs
+pub const NBUF: usize = MAXOPBLOCKS * 30; // size of disk block cache
 pub const FSSIZE: usize = 1000; // size of file system in blocks
 pub const MAXPATH: usize = 128; // maximum file path name
+pub const COMMIT_INTERVAL: usize = 10; // max ticks a log transaction stays open before commit
@@ -1,71 +1,355 @@
+// Pipes: a ring buffer shared by the read and write ends.
+//
+// Data is copied between user memory and the ring in chunks of at
+// most a page, with the lock let go between them so that interrupts
+// are not held off for long, and the other side is woken once per
+// chunk. A write of at most PIPE_BUF bytes waits until all of it
+// fits and goes in one chunk, so it is never interleaved with other
+// writes. The capacity can be changed with fcntl(SETPIPE_SZ).
+
 use crate::{
-    fcntl::OMode,
+    fcntl::{fcntl, OMode, PIPE_BUF},
     file::{FType, File, FTABLE},
-    mpmc::*,
-    proc::{CopyInOut, CPUS},
+    fs::Inode,
+    proc::{CopyInOut, Process, CPUS, PROCS},
+    riscv::PGSIZE,
+    spinlock::Mutex,
     vm::VirtAddr,
 };
+use alloc::{sync::Arc, vec, vec::Vec};
+
+const PIPESIZE: usize = 4096; // capacity of a new pipe
+const MAXPIPESIZE: usize = 1024 * 1024;
+const CHUNK: usize = PGSIZE; // most copied under the lock at once
 
 #[derive(Debug)]
 pub struct Pipe {
-    rx: Option<SyncReceiver<u8>>,
-    tx: Option<SyncSender<u8>>,
+    ring: Arc<Mutex<Ring>>,
+    read: bool,
+    write: bool,
+    fifo: Option<FifoEnd>,
+}
+
+#[derive(Debug)]
+struct Ring {
+    buf: Vec<u8>, // as long as the capacity
+    head: usize,  // where the next read starts
+    len: usize,
+    readers: usize, // ends open
+    writers: usize,
+    ropens: usize, // opens so far, for FIFOs
+    wopens: usize,
+}
+
+impl Ring {
+    fn new(cap: usize) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(
+            Self {
+                buf: vec![0; cap],
+                head: 0,
+                len: 0,
+                readers: 0,
+                writers: 0,
+                ropens: 0,
+                wopens: 0,
+            },
+            "pipe",
+        ))
+    }
+
+    fn cap(&self) -> usize {
+        self.buf.len()
+    }
+
+    // Copy m bytes at src to the end; m must fit.
+    unsafe fn put(&mut self, src: VirtAddr, m: usize) -> Result<(), ()> {
+        let p = CPUS.my_proc().unwrap();
+        let tail = (self.head + self.len) % self.cap();
+        let k = m.min(self.cap() - tail);
+        p.either_copyin(&mut self.buf[tail..tail + k], src)?;
+        p.either_copyin(&mut self.buf[..m - k], src + k)?;
+        self.len += m;
+        Ok(())
+    }
+
+    // Copy m bytes from the head to dst; m must be there.
+    unsafe fn get(&mut self, dst: VirtAddr, m: usize) -> Result<(), ()> {
+        let p = CPUS.my_proc().unwrap();
+        let k = m.min(self.cap() - self.head);
+        p.either_copyout(dst, &self.buf[self.head..self.head + k])?;
+        p.either_copyout(dst + k, &self.buf[..m - k])?;
+        self.head = (self.head + m) % self.cap();
+        self.len -= m;
+        Ok(())
+    }
+}
+
+// A named pipe: the pipe of a FIFO inode, shared by every open of
+// it while it is open anywhere. What is left in it when the last
+// end is closed is thrown away.
+struct Fifo {
+    key: (u32, u32), // device and inode number
+    ring: Arc<Mutex<Ring>>,
+}
+
+// open FIFOs
+static FIFOS: Mutex<Vec<Fifo>> = Mutex::new(Vec::new(), "fifos");
+
+// An open of a FIFO. The inode is held so that it is not reused
+// while the FIFO is open.
+#[derive(Debug)]
+struct FifoEnd {
+    key: (u32, u32),
+    ip: Inode,
+    seen: usize, // opens of the other side when this one was made
 }
 
 impl Pipe {
-    const PIPESIZE: usize = 512;
+    // A new end of ring.
+    fn end(ring: &Arc<Mutex<Ring>>, read: bool, write: bool) -> Self {
+        let mut r = ring.lock();
+        if read {
+            r.readers += 1;
+            r.ropens += 1;
+        }
+        if write {
+            r.writers += 1;
+            r.wopens += 1;
+        }
+        drop(r);
+        Self {
+            ring: Arc::clone(ring),
+            read,
+            write,
+            fifo: None,
+        }
+    }
 
-    pub fn new(rx: Option<SyncReceiver<u8>>, tx: Option<SyncSender<u8>>) -> Self {
-        Self { rx, tx }
+    fn chan(&self) -> usize {
+        Arc::as_ptr(&self.ring) as usize
     }
 
     pub fn get_mode(&self) -> OMode {
         let mut omode = OMode::new();
-        omode.read(self.rx.is_some()).write(self.tx.is_some());
+        omode.read(self.read).write(self.write);
         omode
     }
 
     pub fn alloc() -> Option<(File, File)> {
-        let (tx, rx) = sync_channel::<u8>(Self::PIPESIZE, "pipe");
+        let ring = Ring::new(PIPESIZE);
 
-        let p0 = Self::new(Some(rx), None);
-        let p1 = Self::new(None, Some(tx));
+        let p0 = Self::end(&ring, true, false);
+        let p1 = Self::end(&ring, false, true);
         let f0 = FTABLE.alloc(p0.get_mode(), FType::Pipe(p0))?;
         let f1 = FTABLE.alloc(p1.get_mode(), FType::Pipe(p1))?;
 
         Some((f0, f1))
     }
 
-    pub fn write(&self, src: VirtAddr, n: usize) -> Result<usize, ()> {
+    // Open the FIFO ip, whose device and inode number are key.
+    // Fails for a write-only nonblocking open with no reader, as
+    // ENXIO does. Must be called inside a transaction, since ip is
+    // dropped on failure.
+    pub fn fifo(ip: Inode, key: (u32, u32), opts: &OMode) -> Option<Self> {
+        let (read, write) = (opts.is_read(), opts.is_write());
+        let opened = {
+            let mut fifos = FIFOS.lock();
+            let i = fifos.iter().position(|f| f.key == key);
+            let readers = i.map_or(0, |i| fifos[i].ring.lock().readers);
+            if opts.is_nonblock() && !read && readers == 0 {
+                None
+            } else {
+                let i = i.unwrap_or_else(|| {
+                    fifos.push(Fifo {
+                        key,
+                        ring: Ring::new(PIPESIZE),
+                    });
+                    fifos.len() - 1
+                });
+                let pi = Self::end(&fifos[i].ring, read, write);
+                let r = pi.ring.lock();
+                let seen = if read { r.wopens } else { r.ropens };
+                drop(r);
+                Some((pi, seen))
+            }
+        };
+        // ip is dropped out of the lock on failure.
+        let (mut pi, seen) = opened?;
+        PROCS.wakeup(pi.chan());
+        pi.fifo = Some(FifoEnd { key, ip, seen });
+        Some(pi)
+    }
+
+    pub fn ip(&self) -> Option<&Inode> {
+        self.fifo.as_ref().map(|f| &f.ip)
+    }
+
+    // Wait until the FIFO has been opened by the other side: a
+    // reader for a writer, a writer for a reader. An open of both
+    // sides does not wait.
+    pub fn wait_open(&self) -> Result<(), ()> {
+        let Some(end) = self.fifo.as_ref() else {
+            return Ok(());
+        };
+        if self.read && self.write {
+            return Ok(());
+        }
+        let p = CPUS.my_proc().unwrap();
+        let mut r = self.ring.lock();
+        loop {
+            let ready = if self.read {
+                r.writers > 0 || r.wopens != end.seen
+            } else {
+                r.readers > 0 || r.ropens != end.seen
+            };
+            if ready {
+                return Ok(());
+            }
+            if p.inner.lock().killed {
+                return Err(());
+            }
+            r = p.sleep(self.chan(), r);
+        }
+    }
+
+    // With nonblock, write as much as fits without waiting.
+    // Err if the pipe is full, or if it has no reader (EPIPE;
+    // there are no signals for SIGPIPE).
+    pub fn write(&self, src: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()> {
         let p = CPUS.my_proc().unwrap();
 
-        let tx = self.tx.as_ref().ok_or(())?;
+        if !self.write {
+            return Err(());
+        }
 
+        let mut r = self.ring.lock();
         let mut i = 0;
         while i < n {
-            let mut ch: u8 = 0;
-            if unsafe { p.either_copyin(&mut ch, src) }.is_err() {
+            if r.readers == 0 {
+                break;
+            }
+            let space = r.cap() - r.len;
+            // a small write goes in whole.
+            let need = if n <= PIPE_BUF { n } else { 1 };
+            if space < need {
+                if nonblock || p.inner.lock().killed {
+                    break;
+                }
+                r = p.sleep(self.chan(), r);
+                continue;
+            }
+            let m = space.min(n - i).min(CHUNK);
+            if unsafe { r.put(src + i, m) }.is_err() {
                 break;
             }
-            tx.send(ch);
-            i += 1;
+            i += m;
+            PROCS.wakeup(self.chan());
+            drop(r);
+            r = self.ring.lock();
+        }
+        if i == 0 && n > 0 {
+            return Err(());
         }
         Ok(i)
     }
 
-    pub fn read(&self, dst: VirtAddr, n: usize) -> Result<usize, ()> {
+    // Read what is in the pipe, up to n bytes; 0 at the end, once
+    // the pipe is empty and has no writer. With nonblock, Err if
+    // the pipe is empty but has a writer.
+    pub fn read(&self, dst: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()> {
         let p = CPUS.my_proc().unwrap();
 
-        let rx = self.rx.as_ref().ok_or(())?;
+        if !self.read {
+            return Err(());
+        }
 
+        let mut r = self.ring.lock();
+        while r.len == 0 {
+            if r.writers == 0 {
+                return Ok(0);
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            r = p.sleep(self.chan(), r);
+        }
+        // take what is there, a chunk at a time.
         let mut i = 0;
-        while i < n {
-            let ch = rx.recv();
-            if unsafe { p.either_copyout(dst, &ch) }.is_err() {
+        while i < n && r.len > 0 {
+            let m = (n - i).min(r.len).min(CHUNK);
+            if unsafe { r.get(dst + i, m) }.is_err() {
                 break;
             }
-            i += 1;
+            i += m;
+            PROCS.wakeup(self.chan());
+            drop(r);
+            r = self.ring.lock();
+        }
+        if i == 0 {
+            return Err(());
         }
         Ok(i)
     }
+
+    pub fn fcntl(&self, cmd: usize, arg: usize) -> Result<usize, ()> {
+        match cmd {
+            fcntl::GETPIPE_SZ => Ok(self.ring.lock().cap()),
+            fcntl::SETPIPE_SZ => self.resize(arg),
+            _ => Err(()),
+        }
+    }
+
+    // Change the capacity to cap bytes; Err if what is in the
+    // pipe does not fit.
+    fn resize(&self, cap: usize) -> Result<usize, ()> {
+        if !(PIPE_BUF..=MAXPIPESIZE).contains(&cap) {
+            return Err(());
+        }
+        let mut buf = vec![0u8; cap];
+        let mut r = self.ring.lock();
+        if r.len > cap {
+            drop(r);
+            return Err(());
+        }
+        for (j, b) in buf[..r.len].iter_mut().enumerate() {
+            *b = r.buf[(r.head + j) % r.cap()];
+        }
+        let old = core::mem::replace(&mut r.buf, buf);
+        r.head = 0;
+        drop(r);
+        drop(old);
+        PROCS.wakeup(self.chan());
+        Ok(cap)
+    }
+}
+
+// Closing an end wakes the other side, to see the end or the broken
+// pipe. The inode of a FIFO is dropped after, so closing one must
+// be inside a transaction.
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        let close = |r: &mut Ring| {
+            if self.read {
+                r.readers -= 1;
+            }
+            if self.write {
+                r.writers -= 1;
+            }
+            r.readers == 0 && r.writers == 0
+        };
+        let gone = match self.fifo.as_ref() {
+            Some(end) => {
+                let mut fifos = FIFOS.lock();
+                let i = fifos.iter().position(|f| f.key == end.key).unwrap();
+                let last = close(&mut self.ring.lock());
+                last.then(|| fifos.swap_remove(i))
+            }
+            None => {
+                close(&mut self.ring.lock());
+                None
+            }
+        };
+        PROCS.wakeup(self.chan());
+        drop(gone);
+    }
 }
@@ -1,5 +1,7 @@
 use crate::{
-    memlayout::{PLIC_PRIORITY, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY, UART0_IRQ, VIRTIO0_IRQ},
+    memlayout::{
+        NVIRTIO, PLIC_PRIORITY, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY, UART0_IRQ, VIRTIO0_IRQ,
+    },
     proc::Cpus,
 };
 
@@ -9,7 +11,10 @@ pub fn init() {
     let priority = PLIC_PRIORITY as *mut u32;
     unsafe {
         priority.add(UART0_IRQ as usize).write_volatile(1);
-        priority.add(VIRTIO0_IRQ as usize).write_volatile(1);
+        // every virtio slot; devintr() ignores empty ones.
+        for irq in VIRTIO0_IRQ..VIRTIO0_IRQ + NVIRTIO as u32 {
+            priority.add(irq as usize).write_volatile(1);
+        }
     }
 }
 
@@ -19,7 +24,8 @@ pub fn inithart() {
 
         // set uart's enable bit for this hart's S-mode.
         let senable = PLIC_SENABLE(hart) as *mut u32;
-        senable.write_volatile((1 << UART0_IRQ) | (1 << VIRTIO0_IRQ));
+        let virtio = ((1 << NVIRTIO) - 1) << VIRTIO0_IRQ;
+        senable.write_volatile((1 << UART0_IRQ) | virtio);
 
         // set this hart's S-mode priority threshold to 0.
         let spriority = PLIC_SPRIORITY(hart) as *mut u32;
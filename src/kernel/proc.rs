@@ -417,7 +417,7 @@ impl Procs {
                     data.context.sp = data.kstack.into_usize() + PGSIZE;
                     return Some((p, lock));
                 }
-                _ => return None,
+                _ => continue,
             }
         }
         None
@@ -440,15 +440,17 @@ impl Procs {
     // Wake up all processes sleeping on chan.
     // Must be called without any "proc" lock.
     pub fn wakeup(&self, chan: usize) {
+        // skip the caller, which is running. An interrupt taken in
+        // the scheduler has no process to skip: all sleepers on
+        // chan must still be woken, not none.
+        let me = CPUS.my_proc();
         for p in self.pool.iter() {
-            match CPUS.my_proc() {
-                Some(mp) if !Arc::ptr_eq(p, mp) => {
-                    let mut guard = p.inner.lock();
-                    if guard.state == ProcState::SLEEPING && guard.chan == chan {
-                        guard.state = ProcState::RUNNABLE;
-                    }
-                }
-                _ => (),
+            if me.is_some_and(|mp| Arc::ptr_eq(p, mp)) {
+                continue;
+            }
+            let mut guard = p.inner.lock();
+            if guard.state == ProcState::SLEEPING && guard.chan == chan {
+                guard.state = ProcState::RUNNABLE;
             }
         }
     }
@@ -805,6 +807,24 @@ pub fn user_init() {
     guard.state = ProcState::RUNNABLE;
 }
 
+// Start a kernel process running entry: work that has to sleep
+// but belongs to no user process. It has no user memory, and
+// never returns to user space. entry must call kthread_ret()
+// before anything else.
+pub fn kthread(name: &str, entry: extern "C" fn() -> !) {
+    let (p, ref mut guard) = PROCS.alloc_proc().expect("kthread");
+    let data = unsafe { &mut *p.data.get() };
+    data.context.ra = entry as usize;
+    data.name.push_str(name);
+    guard.state = ProcState::RUNNABLE;
+}
+
+// Release the "proc" lock that scheduler() holds when it first
+// switches to a kernel process, as fork_ret() does.
+pub fn kthread_ret() {
+    drop(unsafe { CPUS.my_cpu() }.proc_lock.take());
+}
+
 impl ProcInner {
     pub const fn new() -> Self {
         Self {
@@ -846,11 +866,19 @@ pub unsafe extern "C" fn fork_ret() -> ! {
         // be run from main().
         FIRST = false;
         fs::init(ROOTDEV);
+
+        #[cfg(feature = "crashtest")]
+        crate::crashtest::run();
+
+        // crash tests count writes, which commits at any tick
+        // would change from run to run.
+        #[cfg(not(feature = "crashtest"))]
+        kthread("flusher", crate::log::flusher);
     }
     usertrap_ret()
 }
 
-// Print a process listing to console. For debugging.
+// Print a process listing and the buffer cache counters to console. For debugging.
 // Runs when user types ^P on console.
 // No lock to avoid wedging a stuck machine further.
 pub fn procdump() {
@@ -864,6 +892,7 @@ pub fn procdump() {
             );
         }
     }
+    println!("bcache: {:?}", crate::bio::BCACHE.stats());
 }
 
 // Per-CPU process scheduler.
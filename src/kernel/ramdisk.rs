@@ -0,0 +1,105 @@
+// RAM disk.
+//
+// Holds the file system image qemu loads with
+// qemu ... -initrd fs.img
+// so that octox can boot without a virtio disk. It is registered
+// before any virtio disk, so it becomes the root.
+//
+// qemu puts the image in the middle of RAM and says where in the
+// device tree; init() moves it to the top of RAM, which the page
+// allocator then leaves alone. Reads and writes are copies, done
+// by the time they are started.
+//
+// A RamDisk can also be made over any memory, e.g. to test the
+// code above the block device on the host.
+
+use crate::{bio::Data, blockdev::BlockDevice, fs::BSIZE};
+#[cfg(target_os = "none")]
+use crate::{blockdev, fdt::Fdt, memlayout::PHYSTOP, riscv::pgrounddown, sync::OnceLock};
+use core::ptr;
+
+#[cfg(target_os = "none")]
+extern "C" {
+    // first address after kernel.
+    // defined by kernel.ld
+    static end: [u8; 0];
+}
+
+#[cfg(target_os = "none")]
+static RAMDISK: OnceLock<RamDisk> = OnceLock::new();
+
+pub struct RamDisk {
+    base: usize,
+    nblocks: u32,
+}
+
+impl RamDisk {
+    // A RAM disk of the whole blocks in mem.
+    pub fn new(mem: &'static mut [u8]) -> Self {
+        Self {
+            base: mem.as_mut_ptr() as usize,
+            nblocks: (mem.len() / BSIZE) as u32,
+        }
+    }
+
+    fn block(&self, blockno: u32) -> Result<*mut u8, ()> {
+        if blockno >= self.nblocks {
+            return Err(());
+        }
+        Ok((self.base + blockno as usize * BSIZE) as *mut u8)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn start_rw(&self, b: &mut Data, write: bool) -> Result<(), ()> {
+        let block = self.block(b.blockno())?;
+        unsafe {
+            if write {
+                ptr::copy_nonoverlapping(b.data.as_ptr(), block, BSIZE);
+            } else {
+                ptr::copy_nonoverlapping(block, b.data.as_mut_ptr(), BSIZE);
+            }
+        }
+        Ok(())
+    }
+
+    // nothing is ever in flight.
+    fn wait(&self, _data: &Data) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn capacity(&self) -> u32 {
+        self.nblocks
+    }
+}
+
+// Move the initrd, if qemu loaded one, to the top of RAM and
+// register it. Must run before kalloc::init(), while the device
+// tree is intact; returns the end of memory left to the allocator.
+#[cfg(target_os = "none")]
+pub fn init() -> usize {
+    let Some(fdt) = Fdt::get() else {
+        return PHYSTOP;
+    };
+    let (Some(start), Some(stop)) = (
+        fdt.prop_usize("chosen", "linux,initrd-start"),
+        fdt.prop_usize("chosen", "linux,initrd-end"),
+    ) else {
+        return PHYSTOP;
+    };
+    let size = stop.saturating_sub(start);
+    let base = pgrounddown(PHYSTOP.saturating_sub(size));
+    if start < ptr::addr_of!(end) as usize || base < ptr::addr_of!(end) as usize {
+        panic!("ramdisk: initrd does not fit");
+    }
+    // the two may overlap.
+    unsafe { ptr::copy(start as *const u8, base as *mut u8, size) };
+
+    let mem = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size) };
+    RAMDISK.set(RamDisk::new(mem)).ok().unwrap();
+    let ramdisk = RAMDISK.get().unwrap();
+    // the first device registered, so there is room.
+    let dev = blockdev::register(ramdisk).unwrap();
+    println!("ramdisk {}: {} blocks", dev, ramdisk.nblocks);
+    base
+}
@@ -24,6 +24,16 @@ impl Semaphore {
         *cnt += 1;
     }
 
+    // Like wait(), but fails instead of sleeping.
+    pub fn try_wait(&self) -> bool {
+        let mut cnt = self.mutex.lock();
+        if *cnt >= self.max {
+            return false;
+        }
+        *cnt += 1;
+        true
+    }
+
     pub fn post(&self) {
         let mut cnt = self.mutex.lock();
         assert!(*cnt > 0);
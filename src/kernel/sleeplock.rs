@@ -55,6 +55,16 @@ impl<T> SleepLock<T> {
         SleepLockGuard { sleep_lock: &self }
     }
 
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    // Raw pointer to the data, for a device that fills it
+    // while nobody holds the lock.
+    pub fn as_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
     pub fn holding(&self) -> bool {
         let lk = self.lk.lock();
         lk.locked && lk.pid == CPUS.my_proc().unwrap().pid()
@@ -0,0 +1,407 @@
+// Sockets: the files socket() makes, over the UDP of net.rs, the
+// TCP of tcp.rs, and the Unix domain sockets of unix.rs.
+//
+// read and write on a socket are recv and send without flags. A
+// socket is bound to a port by bind(), or on its first use; only
+// INADDR_ANY or our own address can be bound. Closing a connected
+// TCP socket leaves the connection to finish on its own.
+
+use crate::{
+    fcntl::OMode,
+    file::{FType, File, FTABLE},
+    net::{UdpSocket, LOCAL, UDP_MAXDATA},
+    proc::{CopyInOut, CPUS},
+    socket::{af, so, sock, sol, tcpopt, SockAddr, INADDR_ANY},
+    spinlock::Mutex,
+    tcp::{self, Listener, Options, Tcb},
+    unix::UnixSocket,
+    vm::VirtAddr,
+};
+use alloc::{sync::Arc, vec, vec::Vec};
+
+pub struct Socket {
+    proto: Mutex<Proto>,
+}
+
+enum Proto {
+    Udp {
+        sock: Option<Arc<UdpSocket>>,
+        peer: Option<(u32, u16)>, // by connect()
+    },
+    Tcp {
+        state: Tcp,
+        port: u16, // by bind(); 0 if none
+        opts: Options,
+    },
+    Unix(Arc<UnixSocket>),
+}
+
+enum Tcp {
+    Idle,
+    Listen(Arc<Listener>),
+    Conn(Arc<Tcb>),
+}
+
+impl core::fmt::Debug for Socket {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &*self.proto.lock() {
+            Proto::Udp { sock, .. } => {
+                write!(f, "Socket(udp {:?})", sock.as_ref().map(|s| s.port()))
+            }
+            Proto::Tcp {
+                state: Tcp::Listen(l),
+                ..
+            } => write!(f, "Socket({:?})", l),
+            Proto::Tcp {
+                state: Tcp::Conn(t),
+                ..
+            } => write!(f, "Socket({:?})", t),
+            Proto::Tcp { .. } => write!(f, "Socket(tcp)"),
+            Proto::Unix(u) => write!(f, "Socket({:?})", u),
+        }
+    }
+}
+
+// the address to bind: INADDR_ANY or ours.
+fn local(addr: &SockAddr) -> Result<u16, ()> {
+    if addr.family as usize != af::INET || (addr.addr != INADDR_ANY && addr.addr != LOCAL) {
+        return Err(());
+    }
+    Ok(addr.port)
+}
+
+fn path(addr: &SockAddr) -> Result<&str, ()> {
+    match addr.path() {
+        Some(path) if addr.family as usize == af::UNIX && !path.is_empty() => Ok(path),
+        _ => Err(()),
+    }
+}
+
+fn remote(addr: &SockAddr) -> Result<(u32, u16), ()> {
+    if addr.family as usize != af::INET || addr.port == 0 {
+        return Err(());
+    }
+    Ok((addr.addr, addr.port))
+}
+
+impl Socket {
+    // the connection, if a connected TCP socket.
+    fn tcb(&self) -> Option<Arc<Tcb>> {
+        match &*self.proto.lock() {
+            Proto::Tcp {
+                state: Tcp::Conn(tcb),
+                ..
+            } => Some(Arc::clone(tcb)),
+            _ => None,
+        }
+    }
+
+    // the socket, if AF_UNIX.
+    pub fn unix(&self) -> Option<Arc<UnixSocket>> {
+        match &*self.proto.lock() {
+            Proto::Unix(u) => Some(Arc::clone(u)),
+            _ => None,
+        }
+    }
+
+    fn with(proto: Proto) -> Self {
+        Self {
+            proto: Mutex::new(proto, "socket"),
+        }
+    }
+
+    fn file(self, nonblock: bool) -> Option<File> {
+        let mut mode = OMode::new();
+        mode.read(true).write(true).nonblock(nonblock);
+        FTABLE.alloc(mode, FType::Socket(self))
+    }
+
+    // Make a socket of domain and type; protocol 0 is the one
+    // of the type.
+    pub fn alloc(domain: usize, stype: usize, protocol: usize) -> Option<File> {
+        let proto = match (domain, stype & !sock::NONBLOCK, protocol) {
+            (af::UNIX, t, 0) => Proto::Unix(Arc::new(UnixSocket::new(t)?)),
+            (af::INET, sock::DGRAM, 0 | 17) => Proto::Udp {
+                sock: None,
+                peer: None,
+            },
+            (af::INET, sock::STREAM, 0 | 6) => Proto::Tcp {
+                state: Tcp::Idle,
+                port: 0,
+                opts: Options::default(),
+            },
+            _ => return None,
+        };
+        Self::with(proto).file(stype & sock::NONBLOCK != 0)
+    }
+
+    // Two AF_UNIX sockets connected to each other.
+    pub fn pair(domain: usize, stype: usize) -> Option<(File, File)> {
+        if domain != af::UNIX {
+            return None;
+        }
+        let nonblock = stype & sock::NONBLOCK != 0;
+        let (a, b) = UnixSocket::pair(stype & !sock::NONBLOCK)?;
+        let f0 = Self::with(Proto::Unix(Arc::new(a))).file(nonblock)?;
+        let f1 = Self::with(Proto::Unix(Arc::new(b))).file(nonblock)?;
+        Some((f0, f1))
+    }
+
+    pub fn bind(&self, addr: &SockAddr) -> Result<(), ()> {
+        if let Some(u) = self.unix() {
+            return u.bind(path(addr)?);
+        }
+        let port = local(addr)?;
+        match &mut *self.proto.lock() {
+            Proto::Udp {
+                sock: sock @ None, ..
+            } => {
+                sock.replace(UdpSocket::bind(port)?);
+                Ok(())
+            }
+            Proto::Tcp {
+                state: Tcp::Idle,
+                port: bound @ 0,
+                opts,
+            } => {
+                *bound = tcp::bind_port(port, opts.reuseaddr)?;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    // Connect to addr. For UDP, only sets where datagrams go and
+    // come from.
+    pub fn connect(&self, addr: &SockAddr, nonblock: bool) -> Result<(), ()> {
+        if let Some(u) = self.unix() {
+            return u.connect(path(addr)?);
+        }
+        let (raddr, rport) = remote(addr)?;
+        let tcb = match &mut *self.proto.lock() {
+            Proto::Udp { sock, peer } => {
+                if sock.is_none() {
+                    sock.replace(UdpSocket::bind(0)?);
+                }
+                peer.replace((raddr, rport));
+                return Ok(());
+            }
+            Proto::Tcp {
+                state: state @ Tcp::Idle,
+                port,
+                opts,
+            } => {
+                let tcb = Tcb::connect(raddr, rport, *port, *opts)?;
+                *state = Tcp::Conn(Arc::clone(&tcb));
+                tcb
+            }
+            _ => return Err(()),
+        };
+        // out of the lock
+        tcb.wait(nonblock)
+    }
+
+    pub fn listen(&self, backlog: usize) -> Result<(), ()> {
+        match &mut *self.proto.lock() {
+            Proto::Tcp {
+                state: state @ Tcp::Idle,
+                port,
+                opts,
+            } => {
+                let l = Listener::listen(*port, backlog, *opts)?;
+                *port = l.port();
+                *state = Tcp::Listen(l);
+                Ok(())
+            }
+            Proto::Tcp {
+                state: Tcp::Listen(_),
+                ..
+            } => Ok(()),
+            Proto::Unix(u) => u.listen(backlog),
+            _ => Err(()),
+        }
+    }
+
+    // Take a connection of a listening socket; returns the file of
+    // its socket and the peer's address, which is unnamed for
+    // AF_UNIX.
+    pub fn accept(&self, nonblock: bool) -> Result<(File, SockAddr), ()> {
+        if let Some(u) = self.unix() {
+            let sock = Self::with(Proto::Unix(Arc::new(u.accept(nonblock)?)));
+            return Ok((sock.file(false).ok_or(())?, SockAddr::unix("").unwrap()));
+        }
+        let (l, port, opts) = match &*self.proto.lock() {
+            Proto::Tcp {
+                state: Tcp::Listen(l),
+                port,
+                opts,
+            } => (Arc::clone(l), *port, *opts),
+            _ => return Err(()),
+        };
+        let tcb = l.accept(nonblock)?;
+        let (raddr, rport) = tcb.peer();
+        let sock = Self::with(Proto::Tcp {
+            state: Tcp::Conn(tcb),
+            port,
+            opts,
+        });
+        Ok((sock.file(false).ok_or(())?, SockAddr::inet(raddr, rport)))
+    }
+
+    // Send n bytes at src, to addr or the connected peer.
+    pub fn send(
+        &self,
+        src: VirtAddr,
+        n: usize,
+        nonblock: bool,
+        addr: Option<&SockAddr>,
+    ) -> Result<usize, ()> {
+        self.sendmsg(src, n, nonblock, addr, Vec::new())
+    }
+
+    // send with files to pass, which only AF_UNIX can.
+    pub fn sendmsg(
+        &self,
+        src: VirtAddr,
+        n: usize,
+        nonblock: bool,
+        addr: Option<&SockAddr>,
+        files: Vec<File>,
+    ) -> Result<usize, ()> {
+        if let Some(u) = self.unix() {
+            let to = addr.map(path).transpose()?;
+            return u.send(src, n, nonblock, to, files);
+        }
+        if !files.is_empty() {
+            return Err(());
+        }
+        let tcb = match &mut *self.proto.lock() {
+            Proto::Udp { sock, peer } => {
+                let (dst, dport) = match addr {
+                    Some(addr) => remote(addr)?,
+                    None => peer.ok_or(())?,
+                };
+                if n > UDP_MAXDATA {
+                    return Err(());
+                }
+                let mut buf = vec![0u8; n];
+                unsafe { CPUS.my_proc().unwrap().either_copyin(&mut buf[..], src)? };
+                if sock.is_none() {
+                    sock.replace(UdpSocket::bind(0)?);
+                }
+                return sock.as_ref().unwrap().send_to(dst, dport, &buf);
+            }
+            Proto::Tcp {
+                state: Tcp::Conn(tcb),
+                ..
+            } => Arc::clone(tcb),
+            _ => return Err(()),
+        };
+        tcb.send(src, n, nonblock)
+    }
+
+    // Receive up to n bytes to dst, and where they came from. A
+    // longer datagram is cut short.
+    pub fn recv(
+        &self,
+        dst: VirtAddr,
+        n: usize,
+        nonblock: bool,
+        addr: Option<&mut SockAddr>,
+    ) -> Result<usize, ()> {
+        let (m, files) = self.recvmsg(dst, n, nonblock, addr)?;
+        drop(files);
+        Ok(m)
+    }
+
+    // recv, and the files passed with what is received.
+    pub fn recvmsg(
+        &self,
+        dst: VirtAddr,
+        n: usize,
+        nonblock: bool,
+        addr: Option<&mut SockAddr>,
+    ) -> Result<(usize, Vec<File>), ()> {
+        if let Some(u) = self.unix() {
+            let (m, files, from) = u.recv(dst, n, nonblock)?;
+            if let Some(addr) = addr {
+                *addr = SockAddr::unix(from.as_deref().unwrap_or("")).unwrap_or_default();
+            }
+            return Ok((m, files));
+        }
+        if let Some(tcb) = self.tcb() {
+            if let Some(addr) = addr {
+                let (raddr, rport) = tcb.peer();
+                *addr = SockAddr::inet(raddr, rport);
+            }
+            return Ok((tcb.recv(dst, n, nonblock)?, Vec::new()));
+        }
+        let (udp, peer) = match &*self.proto.lock() {
+            Proto::Udp {
+                sock: Some(sock),
+                peer,
+            } => (Arc::clone(sock), *peer),
+            _ => return Err(()),
+        };
+        // a connected socket takes only the peer's datagrams.
+        let d = loop {
+            let d = udp.recv_from(nonblock)?;
+            if peer.map_or(true, |p| p == (d.src, d.sport)) {
+                break d;
+            }
+        };
+        let m = n.min(d.data.len());
+        unsafe { CPUS.my_proc().unwrap().either_copyout(dst, &d.data[..m])? };
+        if let Some(addr) = addr {
+            *addr = SockAddr::inet(d.src, d.sport);
+        }
+        Ok((m, Vec::new()))
+    }
+
+    pub fn shutdown(&self, how: usize) -> Result<(), ()> {
+        if let Some(u) = self.unix() {
+            return u.shutdown(how);
+        }
+        self.tcb().ok_or(())?.shutdown(how)
+    }
+
+    // Set option name of level to val.
+    pub fn setsockopt(&self, level: usize, name: usize, val: usize) -> Result<(), ()> {
+        let mut guard = self.proto.lock();
+        let Proto::Tcp { state, opts, .. } = &mut *guard else {
+            // datagrams are not buffered for sending, and queued
+            // by count; neither are buffers of AF_UNIX sizable.
+            return match (level, name) {
+                (sol::SOCKET, so::REUSEADDR | so::SNDBUF | so::RCVBUF) => Ok(()),
+                _ => Err(()),
+            };
+        };
+        match (level, name) {
+            (sol::SOCKET, so::REUSEADDR) => opts.reuseaddr = val != 0,
+            (sol::SOCKET, so::SNDBUF) if (1..=tcp::MAXBUF).contains(&val) => opts.sndbuf = val,
+            (sol::SOCKET, so::RCVBUF) if (1..=tcp::MAXBUF).contains(&val) => opts.rcvbuf = val,
+            (sol::TCP, tcpopt::NODELAY) => opts.nodelay = val != 0,
+            _ => return Err(()),
+        }
+        if let Tcp::Conn(tcb) = state {
+            tcb.set_options(*opts);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        match &*self.proto.lock() {
+            Proto::Tcp {
+                state: Tcp::Conn(tcb),
+                ..
+            } => tcb.close(),
+            Proto::Tcp {
+                state: Tcp::Listen(l),
+                ..
+            } => l.close(),
+            _ => {}
+        }
+    }
+}
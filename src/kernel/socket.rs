@@ -0,0 +1,96 @@
+// Address families
+pub mod af {
+    pub const UNIX: usize = 1; // local, bound to paths
+    pub const INET: usize = 2; // IPv4
+}
+
+// Socket types, for socket()
+pub mod sock {
+    pub const STREAM: usize = 1; // byte stream: TCP for AF_INET
+    pub const DGRAM: usize = 2; // datagrams: UDP for AF_INET
+    pub const NONBLOCK: usize = 0x800; // or'ed with the type, like omode::NONBLOCK
+}
+
+// Flags of send and recv
+pub mod msg {
+    pub const DONTWAIT: usize = 0x40; // this call does not wait
+}
+
+// What shutdown() shuts down
+pub mod shut {
+    pub const RD: usize = 0;
+    pub const WR: usize = 1;
+    pub const RDWR: usize = 2;
+}
+
+// Levels and names of socket options, for setsockopt()
+pub mod sol {
+    pub const SOCKET: usize = 1;
+    pub const TCP: usize = 6;
+}
+pub mod so {
+    pub const REUSEADDR: usize = 2; // bind may take a port still in TIME-WAIT
+    pub const SNDBUF: usize = 7; // bytes buffered for sending
+    pub const RCVBUF: usize = 8; // bytes buffered for receiving
+}
+pub mod tcpopt {
+    pub const NODELAY: usize = 1; // send small segments at once
+}
+
+pub const INADDR_ANY: u32 = 0;
+
+pub const UNIX_PATH_MAX: usize = 108;
+
+// A socket address. The address and port of AF_INET are in host
+// byte order; the path of AF_UNIX ends at the first NUL, if any.
+// An AF_UNIX address with an empty path is that of an unbound
+// socket.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SockAddr {
+    pub family: u16,
+    pub port: u16,
+    pub addr: u32,
+    pub path: [u8; UNIX_PATH_MAX],
+}
+
+impl Default for SockAddr {
+    fn default() -> Self {
+        Self {
+            family: 0,
+            port: 0,
+            addr: 0,
+            path: [0; UNIX_PATH_MAX],
+        }
+    }
+}
+
+impl SockAddr {
+    pub fn inet(addr: u32, port: u16) -> Self {
+        Self {
+            family: af::INET as u16,
+            port,
+            addr,
+            ..Default::default()
+        }
+    }
+
+    // None if path is too long.
+    pub fn unix(path: &str) -> Option<Self> {
+        let mut sa = Self {
+            family: af::UNIX as u16,
+            ..Default::default()
+        };
+        sa.path.get_mut(..path.len())?.copy_from_slice(path.as_bytes());
+        Some(sa)
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        let len = self
+            .path
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(UNIX_PATH_MAX);
+        core::str::from_utf8(&self.path[..len]).ok()
+    }
+}
@@ -56,6 +56,23 @@ impl<T> Mutex<T> {
         }
     }
 
+    // Acquire the lock only if nobody holds it, this cpu included.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let _intr_lock = CPUS.intr_lock();
+        self.locked
+            .compare_exchange(
+                ptr::null_mut(),
+                unsafe { CPUS.my_cpu() },
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| MutexGuard {
+                mutex: self,
+                _intr_lock,
+            })
+    }
+
     // Check whether this cpu is holding the lock.
     // Interrupts must be off.
     pub unsafe fn holding(&self) -> bool {
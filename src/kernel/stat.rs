@@ -5,6 +5,8 @@ pub enum IType {
     Dir = 1,
     File = 2,
     Device = 3,
+    Socket = 4, // bound Unix domain socket
+    Fifo = 5,   // named pipe
 }
 
 impl Default for IType {
@@ -13,6 +15,21 @@ impl Default for IType {
     }
 }
 
+impl IType {
+    // The type stored on disk as bits, if it is one.
+    pub fn from_u16(bits: u16) -> Option<IType> {
+        match bits {
+            0 => Some(IType::None),
+            1 => Some(IType::Dir),
+            2 => Some(IType::File),
+            3 => Some(IType::Device),
+            4 => Some(IType::Socket),
+            5 => Some(IType::Fifo),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Stat {
@@ -22,3 +39,31 @@ pub struct Stat {
     pub nlink: u16,   // Number of links to file
     pub size: usize,  // Size of file in bytes
 }
+
+// Directory record returned by getdents().
+// Each record is this header followed by namelen bytes of name,
+// padded so that the next record is aligned; reclen is the size
+// of the whole record.
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Dirent {
+    pub ino: u32,     // Inode number
+    pub itype: IType, // Type of file
+    pub reclen: u16,  // Length of this record
+    pub namelen: u16, // Length of name
+    _pad: u16,
+}
+
+impl Dirent {
+    pub const fn new(ino: u32, itype: IType, namelen: usize) -> Self {
+        let align = core::mem::align_of::<Dirent>();
+        let reclen = (core::mem::size_of::<Dirent>() + namelen + align - 1) & !(align - 1);
+        Self {
+            ino,
+            itype,
+            reclen: reclen as u16,
+            namelen: namelen as u16,
+            _pad: 0,
+        }
+    }
+}
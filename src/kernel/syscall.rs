@@ -8,16 +8,21 @@ use crate::{
     log::LOG,
     param::{MAXARG, MAXPATH},
     pipe::Pipe,
-    proc::{ProcData, Process, CPUS, PROCS},
+    proc::{CopyInOut, ProcData, Process, CPUS, PROCS},
     riscv::PGSIZE,
+    sock::Socket,
+    socket::{msg, SockAddr},
     stat::IType,
     trap::TICKS,
+    unix::MAXRIGHTS,
     vm::{Addr, UVAddr},
 };
 
 #[cfg(target_os = "none")]
 use alloc::string::{String, ToString};
 #[cfg(target_os = "none")]
+use alloc::vec::Vec;
+#[cfg(target_os = "none")]
 use core::concat;
 use core::mem::variant_count;
 #[cfg(target_os = "none")]
@@ -47,6 +52,34 @@ pub enum SysCalls {
     Link = 19,
     Mkdir = 20,
     Close = 21,
+    Rename = 22,
+    Getdents = 23,
+    Getcwd = 24,
+    Truncate = 25,
+    Ftruncate = 26,
+    Fsync = 27,
+    Fdatasync = 28,
+    Sync = 29,
+    Fadvise = 30,
+    Ioctl = 31,
+    Socket = 32,
+    Bind = 33,
+    Connect = 34,
+    Listen = 35,
+    Accept = 36,
+    Send = 37,
+    Sendto = 38,
+    Recv = 39,
+    Recvfrom = 40,
+    Shutdown = 41,
+    Setsockopt = 42,
+    Socketpair = 43,
+    Sendmsg = 44,
+    Recvmsg = 45,
+    Mkfifo = 46,
+    Fcntl = 47,
+    Mount = 48,
+    Umount = 49,
     Invalid = 0,
 }
 
@@ -74,6 +107,34 @@ impl SysCalls {
         (Self::link, "(file1: &str, file2: &str) -> isize"), // link: Create another name (file2) for the file file1.
         (Self::mkdir, "(dir: &str) -> isize"),               // mkdir: Create a new directory.
         (Self::close, "(fd: usize) -> isize"),               // close: Release open file fd.
+        (Self::rename, "(old: &str, new: &str) -> isize"),   // rename: Atomically move the file old to new.
+        (Self::getdents, "(fd: usize, buf: &mut [u8]) -> isize"), // getdents: Read directory records into buf; returns bytes read, or 0 at the end.
+        (Self::getcwd, "(buf: &mut [u8]) -> isize"), // getcwd: Put the absolute path of the current directory in buf; returns its length.
+        (Self::truncate, "(path: &str, len: usize) -> isize"), // truncate: Shrink or grow the file path to len bytes.
+        (Self::ftruncate, "(fd: usize, len: usize) -> isize"), // ftruncate: Shrink or grow the open file fd to len bytes.
+        (Self::fsync, "(fd: usize) -> isize"), // fsync: Wait until the data and metadata of fd are on disk.
+        (Self::fdatasync, "(fd: usize) -> isize"), // fdatasync: Wait until the data of fd is on disk.
+        (Self::sync, "() -> isize"), // sync: Wait until all file system updates are on disk.
+        (Self::fadvise, "(fd: usize, off: usize, len: usize, advice: usize) -> isize"), // fadvise: Tell how len bytes of fd at off will be read; len 0 means to the end.
+        (Self::ioctl, "(fd: usize, req: usize, arg: usize) -> isize"), // ioctl: Device specific request req with arg to the device file fd.
+        (Self::socket, "(domain: usize, stype: usize, protocol: usize) -> isize"), // socket: Create an endpoint for communication; returns an fd.
+        (Self::bind, "(fd: usize, addr: &SockAddr) -> isize"), // bind: Give the socket fd the local address addr.
+        (Self::connect, "(fd: usize, addr: &SockAddr) -> isize"), // connect: Connect the socket fd to addr.
+        (Self::listen, "(fd: usize, backlog: usize) -> isize"), // listen: Take connections on the socket fd, up to backlog waiting.
+        (Self::accept, "(fd: usize, addr: &mut SockAddr) -> isize"), // accept: Wait for a connection on fd; returns its fd, and the peer in addr.
+        (Self::send, "(fd: usize, buf: &[u8], flags: usize) -> isize"), // send: Send buf on the connected socket fd; returns bytes sent.
+        (Self::sendto, "(fd: usize, buf: &[u8], flags: usize, addr: &SockAddr) -> isize"), // sendto: Send buf on the socket fd to addr.
+        (Self::recv, "(fd: usize, buf: &mut [u8], flags: usize) -> isize"), // recv: Receive into buf from the socket fd; returns bytes received, or 0 at the end.
+        (Self::recvfrom, "(fd: usize, buf: &mut [u8], flags: usize, addr: &mut SockAddr) -> isize"), // recvfrom: Like recv, and put the sender in addr.
+        (Self::shutdown, "(fd: usize, how: usize) -> isize"), // shutdown: Shut down reading, writing or both of the connection of fd.
+        (Self::setsockopt, "(fd: usize, level: usize, name: usize, val: usize) -> isize"), // setsockopt: Set the option name at level of the socket fd to val.
+        (Self::socketpair, "(domain: usize, stype: usize, sv: &mut [usize]) -> isize"), // socketpair: Create two connected AF_UNIX sockets, put their file descriptors in sv[0] and sv[1].
+        (Self::sendmsg, "(fd: usize, buf: &[u8], fds: &[usize], flags: usize) -> isize"), // sendmsg: Like send, and pass the open files fds along with buf over an AF_UNIX socket.
+        (Self::recvmsg, "(fd: usize, buf: &mut [u8], fds: &mut [usize], flags: usize) -> isize"), // recvmsg: Like recv, and put the descriptors of the files passed in fds; usize::MAX in the slots left.
+        (Self::mkfifo, "(path: &str) -> isize"), // mkfifo: Create a FIFO, a named pipe.
+        (Self::fcntl, "(fd: usize, cmd: usize, arg: usize) -> isize"), // fcntl: Control the open file fd; cmd is one of fcntl::*.
+        (Self::mount, "(dev: usize, path: &str) -> isize"), // mount: Mount the file system on block device dev, read-only, on the directory path.
+        (Self::umount, "(path: &str) -> isize"), // umount: Unmount the file system mounted on path.
     ];
     fn invalid() -> Result<usize, ()> {
         unreachable!()
@@ -144,6 +205,20 @@ impl ProcData {
             .and(Ok(0))
     }
 
+    // Fetch the nth system call argument as a slice.
+    // The argument points to the (data, len) pair of the slice.
+    // Return the address and length of the data, or Err
+    pub fn arg_slice(&mut self, n: usize) -> Result<(UVAddr, usize), ()> {
+        let addr = self.arg_addr(n);
+        let mut data: UVAddr = UVAddr::from(0);
+        let mut len: usize = 0;
+        unsafe {
+            self.fetch_data(addr, &mut data)?;
+            self.fetch_data(addr + core::mem::size_of::<usize>(), &mut len)?;
+        }
+        Ok((data, len))
+    }
+
     // Fetch the str at addr from the current process.
     // Return &str or Err
     pub fn fetch_str<'a>(&mut self, addr: UVAddr, buf: &'a mut [u8]) -> Result<&'a str, ()> {
@@ -326,6 +401,448 @@ impl SysCalls {
             Ok(0)
         }
     }
+    fn getdents() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let (addr, len) = data.arg_slice(1)?;
+
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+            // looking up the entry types takes inode references,
+            // which must be dropped inside a transaction.
+            let res;
+            {
+                LOG.begin_op();
+                res = f.getdents(From::from(addr), len);
+                LOG.end_op();
+            }
+            res
+        }
+    }
+    fn getcwd() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let mut path = [0u8; MAXPATH];
+            let p = CPUS.my_proc().unwrap();
+            let data = p.data_mut();
+            let (addr, len) = data.arg_slice(0)?;
+
+            let res;
+            {
+                LOG.begin_op();
+                res = fs::getcwd(data.cwd.as_ref().unwrap(), &mut path).map(|s| s.len());
+                LOG.end_op();
+            }
+            let n = res?;
+            if n > len {
+                return Err(());
+            }
+            unsafe { p.either_copyout(From::from(addr), &path[..n]) }.and(Ok(n))
+        }
+    }
+    fn truncate() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let mut path = [0u8; MAXPATH];
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let path = Path::new(data.arg_str(0, &mut path)?);
+            let len = u32::try_from(data.arg(1)).or(Err(()))?;
+
+            let res;
+            {
+                LOG.begin_op();
+                res = match path.namei() {
+                    Some((_, ip)) => {
+                        let mut ip_guard = ip.lock();
+                        if ip_guard.itype() == IType::File {
+                            ip_guard.truncate(len).or(Err(()))
+                        } else {
+                            Err(())
+                        }
+                    }
+                    None => Err(()),
+                };
+                LOG.end_op();
+            }
+            res.and(Ok(0))
+        }
+    }
+    fn mount() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let mut path = [0u8; MAXPATH];
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let dev = u32::try_from(data.arg(0)).or(Err(()))?;
+            let path = Path::new(data.arg_str(1, &mut path)?);
+
+            let res;
+            {
+                LOG.begin_op();
+                res = fs::mount(dev, path);
+                LOG.end_op();
+            }
+            res.and(Ok(0))
+        }
+    }
+    fn umount() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let mut path = [0u8; MAXPATH];
+            let path = Path::new(CPUS.my_proc().unwrap().data_mut().arg_str(0, &mut path)?);
+
+            let res;
+            {
+                LOG.begin_op();
+                res = fs::umount(path);
+                LOG.end_op();
+            }
+            res.and(Ok(0))
+        }
+    }
+    fn ftruncate() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let len = data.arg(1);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let res;
+            {
+                LOG.begin_op();
+                res = f.truncate(len);
+                LOG.end_op();
+            }
+            res.and(Ok(0))
+        }
+    }
+    fn fsync() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            data.arg_fd(0).ok_or(())?;
+
+            LOG.sync();
+            Ok(0)
+        }
+    }
+    fn fdatasync() -> Result<usize, ()> {
+        // data and metadata are committed together.
+        Self::fsync()
+    }
+    fn sync() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            LOG.sync();
+            Ok(0)
+        }
+    }
+    fn fadvise() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let off = data.arg(1);
+            let len = data.arg(2);
+            let advice = data.arg(3);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.fadvise(off, len, advice).and(Ok(0))
+        }
+    }
+    fn ioctl() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let req = data.arg(1);
+            let arg = data.arg(2);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.ioctl(req, arg)
+        }
+    }
+    fn socket() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let f = Socket::alloc(data.arg(0), data.arg(1), data.arg(2)).ok_or(())?;
+            data.fdalloc(f).ok_or(())
+        }
+    }
+    fn bind() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let mut addr = SockAddr::default();
+            unsafe { data.fetch_data(data.arg_addr(1), &mut addr)? };
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.socket().ok_or(())?.bind(&addr).and(Ok(0))
+        }
+    }
+    fn connect() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let mut addr = SockAddr::default();
+            unsafe { data.fetch_data(data.arg_addr(1), &mut addr)? };
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.socket()
+                .ok_or(())?
+                .connect(&addr, f.is_nonblock())
+                .and(Ok(0))
+        }
+    }
+    fn listen() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let backlog = data.arg(1);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.socket().ok_or(())?.listen(backlog).and(Ok(0))
+        }
+    }
+    fn accept() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let p = CPUS.my_proc().unwrap();
+            let data = p.data_mut();
+            let uaddr = data.arg_addr(1);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let (nf, addr) = f.socket().ok_or(())?.accept(f.is_nonblock())?;
+            let fd = data.fdalloc(nf).ok_or(())?;
+            if unsafe { p.either_copyout(uaddr.into(), &addr) }.is_err() {
+                data.ofile[fd].take();
+                return Err(());
+            }
+            Ok(fd)
+        }
+    }
+    fn send() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let (buf, len) = data.arg_slice(1)?;
+            let flags = data.arg(2);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let nonblock = f.is_nonblock() || flags & msg::DONTWAIT != 0;
+            f.socket().ok_or(())?.send(buf.into(), len, nonblock, None)
+        }
+    }
+    fn sendto() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let (buf, len) = data.arg_slice(1)?;
+            let flags = data.arg(2);
+            let mut addr = SockAddr::default();
+            unsafe { data.fetch_data(data.arg_addr(3), &mut addr)? };
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let nonblock = f.is_nonblock() || flags & msg::DONTWAIT != 0;
+            f.socket()
+                .ok_or(())?
+                .send(buf.into(), len, nonblock, Some(&addr))
+        }
+    }
+    fn recv() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let (buf, len) = data.arg_slice(1)?;
+            let flags = data.arg(2);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let nonblock = f.is_nonblock() || flags & msg::DONTWAIT != 0;
+            f.socket().ok_or(())?.recv(buf.into(), len, nonblock, None)
+        }
+    }
+    fn recvfrom() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let p = CPUS.my_proc().unwrap();
+            let data = p.data_mut();
+            let (buf, len) = data.arg_slice(1)?;
+            let flags = data.arg(2);
+            let uaddr = data.arg_addr(3);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let nonblock = f.is_nonblock() || flags & msg::DONTWAIT != 0;
+            let mut addr = SockAddr::default();
+            let n = f
+                .socket()
+                .ok_or(())?
+                .recv(buf.into(), len, nonblock, Some(&mut addr))?;
+            unsafe { p.either_copyout(uaddr.into(), &addr) }.and(Ok(n))
+        }
+    }
+    fn shutdown() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let how = data.arg(1);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.socket().ok_or(())?.shutdown(how).and(Ok(0))
+        }
+    }
+    fn setsockopt() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let level = data.arg(1);
+            let name = data.arg(2);
+            let val = data.arg(3);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.socket()
+                .ok_or(())?
+                .setsockopt(level, name, val)
+                .and(Ok(0))
+        }
+    }
+    fn socketpair() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let p = CPUS.my_proc().unwrap();
+            let data = p.data_mut();
+            let domain = data.arg(0);
+            let stype = data.arg(1);
+            let (sv, len) = data.arg_slice(2)?;
+            if len < 2 {
+                return Err(());
+            }
+
+            let (f0, f1) = Socket::pair(domain, stype).ok_or(())?;
+            let fd0 = data.fdalloc(f0).ok_or(())?;
+            let fd1 = match data.fdalloc(f1) {
+                Some(fd) => fd,
+                _ => {
+                    data.ofile[fd0].take();
+                    return Err(());
+                }
+            };
+            if unsafe { p.either_copyout(sv.into(), &[fd0, fd1]) }.is_err() {
+                data.ofile[fd0].take();
+                data.ofile[fd1].take();
+                return Err(());
+            }
+            Ok(0)
+        }
+    }
+    fn sendmsg() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let p = CPUS.my_proc().unwrap();
+            let data = p.data_mut();
+            let (buf, len) = data.arg_slice(1)?;
+            let (fdsaddr, nfds) = data.arg_slice(2)?;
+            let flags = data.arg(3);
+            if nfds > MAXRIGHTS {
+                return Err(());
+            }
+            let mut fds = [0usize; MAXRIGHTS];
+            unsafe { data.fetch_data(fdsaddr, &mut fds[..nfds])? };
+            let files = fds[..nfds]
+                .iter()
+                .map(|&fd| data.ofile.get(fd)?.clone())
+                .collect::<Option<Vec<_>>>()
+                .ok_or(())?;
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let nonblock = f.is_nonblock() || flags & msg::DONTWAIT != 0;
+            f.socket()
+                .ok_or(())?
+                .sendmsg(buf.into(), len, nonblock, None, files)
+        }
+    }
+    fn recvmsg() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let p = CPUS.my_proc().unwrap();
+            let data = p.data_mut();
+            let (buf, len) = data.arg_slice(1)?;
+            let (fdsaddr, nfds) = data.arg_slice(2)?;
+            let flags = data.arg(3);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            let nonblock = f.is_nonblock() || flags & msg::DONTWAIT != 0;
+            let (n, files) = f
+                .socket()
+                .ok_or(())?
+                .recvmsg(buf.into(), len, nonblock, None)?;
+            // files there is no room for are closed.
+            let nfds = nfds.min(MAXRIGHTS);
+            let mut fds = [usize::MAX; MAXRIGHTS];
+            for (slot, file) in fds[..nfds].iter_mut().zip(files) {
+                *slot = data.fdalloc(file).unwrap_or(usize::MAX);
+            }
+            unsafe { p.either_copyout(fdsaddr.into(), &fds[..nfds]) }.and(Ok(n))
+        }
+    }
+    fn fcntl() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let data = CPUS.my_proc().unwrap().data();
+            let cmd = data.arg(1);
+            let arg = data.arg(2);
+            let (_, f) = data.arg_fd(0).ok_or(())?;
+
+            f.fcntl(cmd, arg)
+        }
+    }
     fn fstat() -> Result<usize, ()> {
         #[cfg(not(target_os = "none"))]
         unimplemented!();
@@ -358,6 +875,26 @@ impl SysCalls {
             res.and(Ok(0))
         }
     }
+    fn rename() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let mut old = [0; MAXPATH];
+            let mut new = [0; MAXPATH];
+            let data = CPUS.my_proc().unwrap().data_mut();
+            let old_path = Path::new(data.arg_str(0, &mut old)?);
+            let new_path = Path::new(data.arg_str(1, &mut new)?);
+
+            let res;
+            {
+                LOG.begin_op();
+                res = fs::rename(old_path, new_path);
+                LOG.end_op();
+            }
+            res.and(Ok(0))
+        }
+    }
     fn unlink() -> Result<usize, ()> {
         #[cfg(not(target_os = "none"))]
         unimplemented!();
@@ -386,15 +923,16 @@ impl SysCalls {
             let omode = data.arg(1);
             let path = Path::new(data.arg_str(0, &mut path)?);
 
-            let fd;
+            let f;
             {
                 LOG.begin_op();
-                fd = FTABLE
-                    .alloc(OMode::from_usize(omode), FType::Node(path))
-                    .and_then(|f| data.fdalloc(f));
+                f = FTABLE.alloc(OMode::from_usize(omode), FType::Node(path));
                 LOG.end_op();
             }
-            fd.ok_or(())
+            // a FIFO waits out of the transaction.
+            let f = f.ok_or(())?;
+            f.wait_open()?;
+            data.fdalloc(f).ok_or(())
         }
     }
     fn mkdir() -> Result<usize, ()> {
@@ -408,7 +946,9 @@ impl SysCalls {
             let res;
             {
                 LOG.begin_op();
-                res = fs::create(path, IType::Dir, 0, 0).and(Some(0)).ok_or(());
+                res = fs::create(path, IType::Dir, 0, 0, false)
+                    .and(Some(0))
+                    .ok_or(());
                 LOG.end_op();
             }
             res
@@ -428,7 +968,26 @@ impl SysCalls {
             let res;
             {
                 LOG.begin_op();
-                res = fs::create(path, IType::Device, major, minor)
+                res = fs::create(path, IType::Device, major, minor, false)
+                    .and(Some(0))
+                    .ok_or(());
+                LOG.end_op();
+            }
+            res
+        }
+    }
+    fn mkfifo() -> Result<usize, ()> {
+        #[cfg(not(target_os = "none"))]
+        unimplemented!();
+        #[cfg(target_os = "none")]
+        {
+            let mut path = [0u8; MAXPATH];
+            let path = Path::new(CPUS.my_proc().unwrap().data_mut().arg_str(0, &mut path)?);
+
+            let res;
+            {
+                LOG.begin_op();
+                res = fs::create(path, IType::Fifo, 0, 0, false)
                     .and(Some(0))
                     .ok_or(());
                 LOG.end_op();
@@ -550,6 +1109,34 @@ impl SysCalls {
             19 => Self::Link,
             20 => Self::Mkdir,
             21 => Self::Close,
+            22 => Self::Rename,
+            23 => Self::Getdents,
+            24 => Self::Getcwd,
+            25 => Self::Truncate,
+            26 => Self::Ftruncate,
+            27 => Self::Fsync,
+            28 => Self::Fdatasync,
+            29 => Self::Sync,
+            30 => Self::Fadvise,
+            31 => Self::Ioctl,
+            32 => Self::Socket,
+            33 => Self::Bind,
+            34 => Self::Connect,
+            35 => Self::Listen,
+            36 => Self::Accept,
+            37 => Self::Send,
+            38 => Self::Sendto,
+            39 => Self::Recv,
+            40 => Self::Recvfrom,
+            41 => Self::Shutdown,
+            42 => Self::Setsockopt,
+            43 => Self::Socketpair,
+            44 => Self::Sendmsg,
+            45 => Self::Recvmsg,
+            46 => Self::Mkfifo,
+            47 => Self::Fcntl,
+            48 => Self::Mount,
+            49 => Self::Umount,
             _ => Self::Invalid,
         }
     }
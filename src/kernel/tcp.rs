@@ -0,0 +1,1116 @@
+// TCP (RFC 793), with the congestion control of RFC 5681.
+//
+// A connection is a Tcb. It is in TCBS from its first SYN until it
+// is closed; the socket using it refers to it too, and a connection
+// whose socket was closed stays in TCBS to send what is left and
+// shut down. A Listener in LISTENERS answers the SYNs to its port
+// and queues the connections that complete for accept().
+//
+// Segments are handled in the network interrupt, and timer() runs
+// every clock tick:
+//  - when the retransmission timeout runs out, what is not acked is
+//    sent again from its start (go-back-N). The timeout is estimated
+//    from round trip times as in RFC 6298, and doubles per retry.
+//  - a zero window of the peer is probed with one byte on the same
+//    timer.
+//  - TIME-WAIT ends after TIME_WAIT ticks.
+// Flow control keeps the data in flight within the window the peer
+// advertises, and congestion control within cwnd, which grows by
+// slow start and congestion avoidance. A timeout shrinks cwnd to a
+// segment; three duplicate ACKs start fast retransmit and fast
+// recovery.
+//
+// Segments that come out of order are dropped, and answered with an
+// ACK of what is expected, so the peer soon sends it again. There is
+// no window scaling, SACK or timestamps.
+
+use crate::{
+    net::{
+        get16, get32, put16, put32, send_ip, transport_checksum, EPHEMERAL, IPPROTO_TCP, IP_HLEN,
+        LOCAL, MTU,
+    },
+    proc::{CopyInOut, Process, CPUS, PROCS},
+    riscv::PGSIZE,
+    socket::shut,
+    spinlock::Mutex,
+    trap::TICKS,
+    vm::VirtAddr,
+};
+use alloc::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const TCP_HLEN: usize = 20;
+const MSS: usize = MTU - IP_HLEN - TCP_HLEN; // largest segment we take
+const DEFAULT_MSS: usize = 536; // if the peer does not say
+const MIN_MSS: usize = 64; // smallest we take the peer's word for
+const MAXWIN: usize = 65535; // no window scaling
+
+const SNDBUF: usize = 64 * 1024;
+const RCVBUF: usize = MAXWIN;
+pub const MAXBUF: usize = 1024 * 1024; // most SO_SNDBUF and SO_RCVBUF take
+const CHUNK: usize = PGSIZE; // most copied in under the lock at once
+
+// timers, in ticks
+const RTO_INIT: usize = 10;
+const RTO_MIN: usize = 2;
+const RTO_MAX: usize = 600;
+const MAXRETRIES: u32 = 12; // then the connection is given up
+const TIME_WAIT: usize = 20; // 2 MSL, much shortened
+const FIN_WAIT_2: usize = 600; // for connections without a socket
+
+mod flags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+}
+use flags::*;
+
+// sequence numbers wrap around.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+fn ticks() -> usize {
+    *TICKS.lock()
+}
+
+// Options of a socket, that its connections take.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub sndbuf: usize,
+    pub rcvbuf: usize,
+    pub nodelay: bool, // no Nagle algorithm
+    pub reuseaddr: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sndbuf: SNDBUF,
+            rcvbuf: RCVBUF,
+            nodelay: false,
+            reuseaddr: false,
+        }
+    }
+}
+
+struct Segment<'a> {
+    sport: u16,
+    dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    wnd: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(src: u32, dst: u32, seg: &'a [u8]) -> Option<Self> {
+        if seg.len() < TCP_HLEN || transport_checksum(src, dst, IPPROTO_TCP, seg) != 0 {
+            return None;
+        }
+        let off = (seg[12] >> 4) as usize * 4;
+        if off < TCP_HLEN || off > seg.len() {
+            return None;
+        }
+        let mut mss = None;
+        let mut opts = &seg[TCP_HLEN..off];
+        while let [kind, rest @ ..] = opts {
+            match kind {
+                0 => break,       // end of options
+                1 => opts = rest, // no-op
+                _ => {
+                    let len = *rest.first()? as usize;
+                    if len < 2 || len > opts.len() {
+                        return None;
+                    }
+                    if *kind == 2 && len == 4 {
+                        mss = Some(get16(opts, 2));
+                    }
+                    opts = &opts[len..];
+                }
+            }
+        }
+        Some(Self {
+            sport: get16(seg, 0),
+            dport: get16(seg, 2),
+            seq: get32(seg, 4),
+            ack: get32(seg, 8),
+            flags: seg[13],
+            wnd: get16(seg, 14),
+            mss,
+            data: &seg[off..],
+        })
+    }
+
+    // sequence numbers taken, SYN and FIN taking one each.
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+
+    // The segment size the peer takes, kept to what is sane.
+    fn peer_mss(&self) -> usize {
+        self.mss
+            .map_or(DEFAULT_MSS, |m| m as usize)
+            .clamp(MIN_MSS, MSS)
+    }
+}
+
+// Send a segment. The MSS option goes with SYNs.
+#[allow(clippy::too_many_arguments)]
+fn transmit(
+    lport: u16,
+    raddr: u32,
+    rport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    wnd: u16,
+    data: &[u8],
+) {
+    let hlen = if flags & SYN != 0 {
+        TCP_HLEN + 4
+    } else {
+        TCP_HLEN
+    };
+    let mut seg = vec![0u8; hlen + data.len()];
+    put16(&mut seg, 0, lport);
+    put16(&mut seg, 2, rport);
+    put32(&mut seg, 4, seq);
+    put32(&mut seg, 8, ack);
+    seg[12] = ((hlen / 4) << 4) as u8;
+    seg[13] = flags;
+    put16(&mut seg, 14, wnd);
+    if flags & SYN != 0 {
+        seg[20] = 2;
+        seg[21] = 4;
+        put16(&mut seg, 22, MSS as u16);
+    }
+    seg[hlen..].copy_from_slice(data);
+    let sum = transport_checksum(LOCAL, raddr, IPPROTO_TCP, &seg);
+    put16(&mut seg, 16, sum);
+    // a lost segment is sent again on timeout.
+    let _ = send_ip(raddr, IPPROTO_TCP, &seg);
+}
+
+// Answer a segment that belongs to no connection.
+fn reset(src: u32, seg: &Segment) {
+    if seg.flags & RST != 0 {
+        return;
+    }
+    if seg.flags & ACK != 0 {
+        transmit(seg.dport, src, seg.sport, seg.ack, 0, RST, 0, &[]);
+    } else {
+        let ack = seg.seq.wrapping_add(seg.len());
+        transmit(seg.dport, src, seg.sport, 0, ack, RST | ACK, 0, &[]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+struct Inner {
+    state: State,
+    lport: u16,
+    raddr: u32,
+    rport: u16,
+    listener: Option<Weak<Listener>>, // until established, if passive
+    error: bool,                      // reset, refused or timed out
+    orphan: bool,                     // the socket is closed
+
+    // sending: sndbuf holds the data from snd_una on.
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32, // highest snd_nxt, which a timeout goes back from
+    snd_wnd: usize,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    sndbuf: VecDeque<u8>,
+    fin: bool, // shut down for writing; the FIN follows the data
+    probe: bool,
+
+    // receiving
+    rcv_nxt: u32,
+    rcvbuf: VecDeque<u8>,
+    adv: usize, // window last advertised
+    eof: bool,  // FIN received
+    rd_shut: bool,
+    ack_now: bool,
+
+    // congestion control
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    dupacks: u32,
+    recovery: bool,
+
+    // timers, and the round trip time in ticks * 8
+    deadline: Option<usize>,
+    rto: usize,
+    retries: u32,
+    srtt: usize,
+    rttvar: usize,
+    rtt: Option<(u32, usize)>, // sequence number timed, and when it was sent
+
+    opts: Options,
+}
+
+impl Inner {
+    fn new(lport: u16, raddr: u32, rport: u16, opts: Options) -> Self {
+        static ISS: AtomicU32 = AtomicU32::new(0);
+        let iss = ISS
+            .fetch_add(64000, Ordering::Relaxed)
+            .wrapping_add(ticks() as u32 * 250);
+        Self {
+            state: State::Closed,
+            lport,
+            raddr,
+            rport,
+            listener: None,
+            error: false,
+            orphan: false,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            sndbuf: VecDeque::new(),
+            fin: false,
+            probe: false,
+            rcv_nxt: 0,
+            rcvbuf: VecDeque::new(),
+            adv: 0,
+            eof: false,
+            rd_shut: false,
+            ack_now: false,
+            mss: DEFAULT_MSS,
+            cwnd: 0,
+            ssthresh: MAXWIN,
+            dupacks: 0,
+            recovery: false,
+            deadline: None,
+            rto: RTO_INIT,
+            retries: 0,
+            srtt: 0,
+            rttvar: 0,
+            rtt: None,
+            opts,
+        }
+    }
+
+    fn window(&self) -> usize {
+        self.opts
+            .rcvbuf
+            .saturating_sub(self.rcvbuf.len())
+            .min(MAXWIN)
+    }
+
+    fn segment(&mut self, seq: u32, flags: u8, data: &[u8]) {
+        let wnd = self.window();
+        let ack = if flags & ACK != 0 {
+            self.adv = wnd;
+            self.ack_now = false;
+            self.rcv_nxt
+        } else {
+            0
+        };
+        transmit(
+            self.lport, self.raddr, self.rport, seq, ack, flags, wnd as u16, data,
+        );
+    }
+
+    // (Re)send the SYN, or the SYN-ACK of a passive open.
+    fn send_syn(&mut self) {
+        let flags = if self.state == State::SynReceived {
+            SYN | ACK
+        } else {
+            SYN
+        };
+        let now = ticks();
+        self.segment(self.iss, flags, &[]);
+        self.snd_nxt = self.iss.wrapping_add(1);
+        self.snd_max = self.snd_nxt;
+        if self.retries == 0 {
+            self.rtt = Some((self.snd_nxt, now));
+        }
+        self.deadline.get_or_insert(now + self.rto);
+    }
+
+    // The handshake is done; seg acks the SYN.
+    fn established(&mut self, seg: &Segment) {
+        self.state = State::Established;
+        self.snd_una = seg.ack;
+        self.snd_wnd = seg.wnd as usize;
+        self.snd_wl1 = seg.seq;
+        self.snd_wl2 = seg.ack;
+        self.cwnd = 2 * self.mss;
+        self.deadline = None;
+        self.retries = 0;
+        if let Some((_, sent)) = self.rtt.take() {
+            self.rtt_sample(ticks() - sent);
+        }
+    }
+
+    fn can_send(&self) -> bool {
+        matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        )
+    }
+
+    // Send what the windows allow, the FIN once all data is sent,
+    // and an ACK if one is due; start or stop the timer.
+    fn output(&mut self) {
+        let now = ticks();
+        if self.can_send() {
+            loop {
+                let off = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                let unsent = self.sndbuf.len().saturating_sub(off);
+                let usable = self.snd_wnd.min(self.cwnd).saturating_sub(off);
+                let mut n = unsent.min(usable).min(self.mss);
+                if n == 0 && unsent > 0 && self.probe {
+                    n = 1;
+                }
+                self.probe = false;
+                if n == 0 {
+                    break;
+                }
+                // no small segments while data is in flight (Nagle),
+                // nor into a window that is still small.
+                if n < self.mss && off > 0 && (n < unsent || (!self.opts.nodelay && !self.fin)) {
+                    break;
+                }
+                let data: Vec<u8> = self.sndbuf.range(off..off + n).copied().collect();
+                let seq = self.snd_nxt;
+                let flags = if off + n == self.sndbuf.len() {
+                    ACK | PSH
+                } else {
+                    ACK
+                };
+                self.segment(seq, flags, &data);
+                if self.rtt.is_none() && seq == self.snd_max {
+                    self.rtt = Some((seq.wrapping_add(n as u32), now));
+                }
+                self.snd_nxt = seq.wrapping_add(n as u32);
+                if seq_lt(self.snd_max, self.snd_nxt) {
+                    self.snd_max = self.snd_nxt;
+                }
+            }
+            let end = self.snd_una.wrapping_add(self.sndbuf.len() as u32);
+            if self.fin && self.snd_nxt == end {
+                self.segment(end, FIN | ACK, &[]);
+                self.snd_nxt = end.wrapping_add(1);
+                if seq_lt(self.snd_max, self.snd_nxt) {
+                    self.snd_max = self.snd_nxt;
+                }
+                self.state = match self.state {
+                    State::Established => State::FinWait1,
+                    State::CloseWait => State::LastAck,
+                    s => s,
+                };
+            }
+        }
+        if self.ack_now {
+            self.segment(self.snd_nxt, ACK, &[]);
+        }
+
+        if !matches!(
+            self.state,
+            State::SynSent | State::SynReceived | State::TimeWait | State::FinWait2
+        ) {
+            let off = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let waiting = self.snd_wnd == 0 && self.sndbuf.len() > off;
+            if self.snd_una != self.snd_max || waiting {
+                self.deadline.get_or_insert(now + self.rto);
+            } else {
+                self.deadline = None;
+            }
+        }
+    }
+
+    fn rtt_sample(&mut self, r: usize) {
+        let r = r * 8;
+        if self.srtt == 0 && self.rttvar == 0 {
+            self.srtt = r;
+            self.rttvar = r / 2;
+        } else {
+            self.rttvar = (3 * self.rttvar + self.srtt.abs_diff(r)) / 4;
+            self.srtt = (7 * self.srtt + r) / 8;
+        }
+        self.rto = ((self.srtt + 4 * self.rttvar) / 8).clamp(RTO_MIN, RTO_MAX);
+    }
+
+    // Give the connection up.
+    fn abort(&mut self, rst: bool) {
+        if rst && self.state != State::Closed {
+            self.segment(self.snd_nxt, RST | ACK, &[]);
+        }
+        self.state = State::Closed;
+        self.error = true;
+        self.deadline = None;
+    }
+
+    fn timeout(&mut self) {
+        match self.state {
+            State::TimeWait | State::FinWait2 => {
+                self.state = State::Closed;
+                return;
+            }
+            State::Closed => return,
+            _ => {}
+        }
+        if self.retries >= MAXRETRIES {
+            self.abort(true);
+            return;
+        }
+        self.retries += 1;
+        self.rto = (self.rto * 2).min(RTO_MAX);
+        self.rtt = None;
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(),
+            _ => {
+                if self.snd_una != self.snd_max {
+                    let flight = self.snd_max.wrapping_sub(self.snd_una) as usize;
+                    self.ssthresh = (flight / 2).max(2 * self.mss);
+                    self.cwnd = self.mss;
+                    self.recovery = false;
+                    self.dupacks = 0;
+                    self.snd_nxt = self.snd_una;
+                }
+                // a closed window gets one byte.
+                self.probe = true;
+                self.output();
+            }
+        }
+    }
+
+    // Handle a segment of the connection. Returns true if it has
+    // become established by a passive open.
+    fn input(&mut self, seg: &Segment) -> bool {
+        let now = ticks();
+        if self.state == State::SynSent {
+            let ack_ok =
+                seg.flags & ACK != 0 && seq_lt(self.iss, seg.ack) && seq_le(seg.ack, self.snd_max);
+            if seg.flags & RST != 0 {
+                if ack_ok {
+                    self.abort(false); // refused
+                }
+                return false;
+            }
+            if seg.flags & ACK != 0 && !ack_ok {
+                transmit(self.lport, self.raddr, self.rport, seg.ack, 0, RST, 0, &[]);
+                return false;
+            }
+            // no simultaneous open.
+            if seg.flags & SYN == 0 || !ack_ok {
+                return false;
+            }
+            self.rcv_nxt = seg.seq.wrapping_add(1);
+            self.mss = seg.peer_mss();
+            self.established(seg);
+            self.ack_now = true;
+            self.output();
+            return false;
+        }
+
+        // the SYN-ACK got lost, and the SYN comes again.
+        if self.state == State::SynReceived
+            && seg.flags & SYN != 0
+            && seg.seq.wrapping_add(1) == self.rcv_nxt
+        {
+            self.send_syn();
+            return false;
+        }
+
+        // is any of it within the window?
+        let len = seg.len();
+        let wnd = self.window().max(self.adv) as u32;
+        let in_window =
+            |seq: u32| seq_le(self.rcv_nxt, seq) && seq_lt(seq, self.rcv_nxt.wrapping_add(wnd));
+        let acceptable = match (len, wnd) {
+            (0, 0) => seg.seq == self.rcv_nxt,
+            (0, _) => in_window(seg.seq),
+            (_, 0) => false,
+            _ => in_window(seg.seq) || in_window(seg.seq.wrapping_add(len - 1)),
+        };
+        if !acceptable {
+            if seg.flags & RST == 0 {
+                self.ack_now = true;
+                self.output();
+            }
+            return false;
+        }
+        if seg.flags & RST != 0 {
+            self.abort(false);
+            return false;
+        }
+        if seg.flags & SYN != 0 {
+            self.abort(true);
+            return false;
+        }
+        if seg.flags & ACK == 0 {
+            return false;
+        }
+
+        let mut passive = false;
+        if self.state == State::SynReceived {
+            if !(seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_max)) {
+                transmit(self.lport, self.raddr, self.rport, seg.ack, 0, RST, 0, &[]);
+                return false;
+            }
+            self.established(seg);
+            passive = true;
+        }
+
+        // the acknowledgment
+        let mut fin_acked = false;
+        if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_max) {
+            let acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+            let data = acked.min(self.sndbuf.len());
+            self.sndbuf.drain(..data);
+            fin_acked = self.fin && acked > data;
+            self.snd_una = seg.ack;
+            if seq_lt(self.snd_nxt, self.snd_una) {
+                self.snd_nxt = self.snd_una;
+            }
+            if let Some((seq, sent)) = self.rtt {
+                if seq_le(seq, seg.ack) {
+                    self.rtt = None;
+                    self.rtt_sample(now - sent);
+                }
+            }
+            self.retries = 0;
+            self.deadline = None;
+            if self.recovery {
+                self.cwnd = self.ssthresh;
+                self.recovery = false;
+            } else if self.cwnd < self.ssthresh {
+                self.cwnd += self.mss;
+            } else {
+                self.cwnd += (self.mss * self.mss / self.cwnd.max(1)).max(1);
+            }
+            self.dupacks = 0;
+        } else if seg.ack == self.snd_una
+            && seg.data.is_empty()
+            && seg.flags & FIN == 0
+            && seg.wnd as usize == self.snd_wnd
+            && self.snd_una != self.snd_max
+        {
+            self.dupacks += 1;
+            if self.dupacks == 3 {
+                // fast retransmit
+                let flight = self.snd_max.wrapping_sub(self.snd_una) as usize;
+                self.ssthresh = (flight / 2).max(2 * self.mss);
+                self.cwnd = self.ssthresh + 3 * self.mss;
+                self.recovery = true;
+                self.rtt = None;
+                let n = self.sndbuf.len().min(self.mss);
+                if n > 0 {
+                    let data: Vec<u8> = self.sndbuf.range(..n).copied().collect();
+                    self.segment(self.snd_una, ACK, &data);
+                }
+            } else if self.dupacks > 3 {
+                self.cwnd += self.mss;
+            }
+        } else if seq_lt(self.snd_max, seg.ack) {
+            // acks what was never sent.
+            self.ack_now = true;
+            self.output();
+            return passive;
+        }
+
+        // the window, from the most recent segment
+        if seq_lt(self.snd_wl1, seg.seq) || self.snd_wl1 == seg.seq && seq_le(self.snd_wl2, seg.ack)
+        {
+            if self.snd_wnd == 0 {
+                self.retries = 0; // the peer is alive
+            }
+            self.snd_wnd = seg.wnd as usize;
+            self.snd_wl1 = seg.seq;
+            self.snd_wl2 = seg.ack;
+        }
+
+        if fin_acked {
+            match self.state {
+                State::FinWait1 => {
+                    self.state = State::FinWait2;
+                    if self.orphan {
+                        self.deadline = Some(now + FIN_WAIT_2);
+                    }
+                }
+                State::Closing => {
+                    self.state = State::TimeWait;
+                    self.deadline = Some(now + TIME_WAIT);
+                }
+                State::LastAck => {
+                    self.state = State::Closed;
+                    return passive;
+                }
+                _ => {}
+            }
+        }
+
+        // the data, what is new of it
+        let mut data = seg.data;
+        let mut seq = seg.seq;
+        if seq_lt(seq, self.rcv_nxt) {
+            let skip = (self.rcv_nxt.wrapping_sub(seq) as usize).min(data.len());
+            data = &data[skip..];
+            seq = seq.wrapping_add(skip as u32);
+        }
+        if !data.is_empty() {
+            if seq == self.rcv_nxt
+                && matches!(
+                    self.state,
+                    State::Established | State::FinWait1 | State::FinWait2
+                )
+            {
+                let n = if self.rd_shut {
+                    data.len()
+                } else {
+                    let n = data.len().min(self.window());
+                    self.rcvbuf.extend(&data[..n]);
+                    n
+                };
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+            }
+            self.ack_now = true;
+        }
+
+        // the FIN, if all before it is here
+        let fin_seq = seg.seq.wrapping_add(seg.data.len() as u32);
+        if seg.flags & FIN != 0 && fin_seq == self.rcv_nxt && !self.eof {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.eof = true;
+            self.ack_now = true;
+            self.state = match self.state {
+                State::Established => State::CloseWait,
+                State::FinWait1 => State::Closing,
+                State::FinWait2 => {
+                    self.deadline = Some(now + TIME_WAIT);
+                    State::TimeWait
+                }
+                s => s,
+            };
+        }
+
+        self.output();
+        passive
+    }
+}
+
+// A connection.
+pub struct Tcb {
+    inner: Mutex<Inner>,
+}
+
+impl core::fmt::Debug for Tcb {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = self.inner.lock();
+        write!(
+            f,
+            "Tcb {{ {} -> {:#x}:{}, {:?} }}",
+            s.lport, s.raddr, s.rport, s.state
+        )
+    }
+}
+
+// connections, and listening ports
+static TCBS: Mutex<Vec<Arc<Tcb>>> = Mutex::new(Vec::new(), "tcbs");
+static LISTENERS: Mutex<Vec<Arc<Listener>>> = Mutex::new(Vec::new(), "listeners");
+
+// Is port free to be bound? A port in TIME-WAIT is, with reuseaddr.
+fn port_free(tcbs: &[Arc<Tcb>], port: u16, reuseaddr: bool) -> bool {
+    !LISTENERS.lock().iter().any(|l| l.port == port)
+        && !tcbs.iter().any(|t| {
+            let s = t.inner.lock();
+            s.lport == port
+                && s.state != State::Closed
+                && !(reuseaddr && s.state == State::TimeWait)
+        })
+}
+
+// Find a free port, from the ephemeral ones if port is 0.
+fn alloc_port(tcbs: &[Arc<Tcb>], port: u16, reuseaddr: bool) -> Result<u16, ()> {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+
+    if port != 0 {
+        return port_free(tcbs, port, reuseaddr).then_some(port).ok_or(());
+    }
+    let n = (u16::MAX - EPHEMERAL) as u32 + 1;
+    (0..n)
+        .map(|_| EPHEMERAL + (NEXT.fetch_add(1, Ordering::Relaxed) % n) as u16)
+        .find(|&p| port_free(tcbs, p, false))
+        .ok_or(())
+}
+
+// A port for bind(): port itself if it is free, or a free
+// ephemeral port if it is 0.
+pub fn bind_port(port: u16, reuseaddr: bool) -> Result<u16, ()> {
+    alloc_port(&TCBS.lock(), port, reuseaddr)
+}
+
+impl Tcb {
+    fn chan(&self) -> usize {
+        &self.inner as *const _ as usize
+    }
+
+    // Open a connection from lport, or an ephemeral port if it is
+    // 0, to rport at raddr: the SYN is sent. wait() for it.
+    pub fn connect(raddr: u32, rport: u16, lport: u16, opts: Options) -> Result<Arc<Self>, ()> {
+        let tcb;
+        {
+            let mut tcbs = TCBS.lock();
+            let lport = alloc_port(&tcbs, lport, opts.reuseaddr)?;
+            tcb = Arc::new(Self {
+                inner: Mutex::new(Inner::new(lport, raddr, rport, opts), "tcb"),
+            });
+            tcb.inner.lock().state = State::SynSent;
+            tcbs.push(Arc::clone(&tcb));
+        }
+        tcb.inner.lock().send_syn();
+        Ok(tcb)
+    }
+
+    // Wait until the connection is established. Err if it could
+    // not be, or nonblock and it is not yet.
+    pub fn wait(&self, nonblock: bool) -> Result<(), ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut s = self.inner.lock();
+        loop {
+            match s.state {
+                State::SynSent | State::SynReceived => {}
+                _ if s.error => return Err(()),
+                _ => return Ok(()),
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            s = p.sleep(self.chan(), s);
+        }
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.inner.lock().lport
+    }
+
+    pub fn peer(&self) -> (u32, u16) {
+        let s = self.inner.lock();
+        (s.raddr, s.rport)
+    }
+
+    pub fn set_options(&self, opts: Options) {
+        let mut s = self.inner.lock();
+        s.opts = opts;
+        s.output();
+    }
+
+    // Queue n bytes at src for sending; returns how many were taken.
+    // With nonblock, takes what fits without waiting.
+    pub fn send(&self, src: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut s = self.inner.lock();
+        let mut i = 0;
+        while i < n {
+            let writable = matches!(
+                s.state,
+                State::SynSent | State::SynReceived | State::Established | State::CloseWait
+            );
+            if s.error || s.fin || !writable {
+                break;
+            }
+            let space = s.opts.sndbuf.saturating_sub(s.sndbuf.len());
+            if space == 0 || matches!(s.state, State::SynSent | State::SynReceived) {
+                if nonblock || p.inner.lock().killed {
+                    break;
+                }
+                s = p.sleep(self.chan(), s);
+                continue;
+            }
+            let m = space.min(n - i).min(CHUNK);
+            let mut buf = vec![0u8; m];
+            if unsafe { p.either_copyin(&mut buf[..], src + i) }.is_err() {
+                break;
+            }
+            s.sndbuf.extend(buf);
+            i += m;
+            s.output();
+            drop(s);
+            s = self.inner.lock();
+        }
+        if i == 0 && n > 0 {
+            return Err(());
+        }
+        Ok(i)
+    }
+
+    // Take up to n received bytes to dst; 0 at the end of the
+    // stream. With nonblock, Err if there is nothing yet.
+    pub fn recv(&self, dst: VirtAddr, n: usize, nonblock: bool) -> Result<usize, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut s = self.inner.lock();
+        loop {
+            if !s.rcvbuf.is_empty() {
+                break;
+            }
+            if s.error {
+                return Err(());
+            }
+            if s.eof || s.rd_shut {
+                return Ok(0);
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            s = p.sleep(self.chan(), s);
+        }
+        let m = n.min(s.rcvbuf.len());
+        let (a, b) = s.rcvbuf.as_slices();
+        let k = m.min(a.len());
+        unsafe {
+            p.either_copyout(dst, &a[..k])?;
+            p.either_copyout(dst + k, &b[..m - k])?;
+        }
+        s.rcvbuf.drain(..m);
+        // tell the peer once the window has opened enough.
+        let opened = s.window().saturating_sub(s.adv);
+        if opened >= s.mss.min(s.opts.rcvbuf / 2) {
+            s.ack_now = true;
+            s.output();
+        }
+        Ok(m)
+    }
+
+    pub fn shutdown(&self, how: usize) -> Result<(), ()> {
+        let mut s = self.inner.lock();
+        if matches!(s.state, State::SynSent | State::SynReceived | State::Closed) {
+            return Err(());
+        }
+        if how == shut::RD || how == shut::RDWR {
+            s.rd_shut = true;
+            s.rcvbuf.clear();
+        }
+        if how == shut::WR || how == shut::RDWR {
+            s.fin = true;
+            s.output();
+        }
+        PROCS.wakeup(self.chan());
+        Ok(())
+    }
+
+    // The socket is closed: send the FIN after what is queued, or
+    // reset the connection if received data is lost.
+    pub fn close(&self) {
+        let mut s = self.inner.lock();
+        s.orphan = true;
+        match s.state {
+            State::SynSent => s.state = State::Closed,
+            _ if !s.rcvbuf.is_empty() => s.abort(true),
+            State::SynReceived | State::Established | State::CloseWait => {
+                s.rd_shut = true;
+                s.fin = true;
+                s.output();
+            }
+            State::FinWait2 => s.deadline = Some(ticks() + FIN_WAIT_2),
+            _ => {}
+        }
+    }
+
+    fn input(self: &Arc<Self>, seg: &Segment) {
+        let (passive, listener) = {
+            let mut s = self.inner.lock();
+            let passive = s.input(seg);
+            PROCS.wakeup(self.chan());
+            (passive, if passive { s.listener.take() } else { None })
+        };
+        if passive {
+            match listener.and_then(|l| l.upgrade()) {
+                Some(l) => {
+                    l.queue.lock().push_back(Arc::clone(self));
+                    PROCS.wakeup(l.chan());
+                }
+                None => self.inner.lock().abort(true),
+            }
+        }
+    }
+
+    fn tick(&self, now: usize) {
+        let mut s = self.inner.lock();
+        if s.deadline.map_or(false, |d| now >= d) {
+            s.deadline = None;
+            s.timeout();
+            PROCS.wakeup(self.chan());
+        }
+    }
+}
+
+// A listening port, and the connections waiting for accept().
+pub struct Listener {
+    port: u16,
+    backlog: usize,
+    opts: Options,
+    queue: Mutex<VecDeque<Arc<Tcb>>>,
+}
+
+impl core::fmt::Debug for Listener {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Listener {{ {} }}", self.port)
+    }
+}
+
+impl Listener {
+    // Listen on port, or an ephemeral port if it is 0.
+    pub fn listen(port: u16, backlog: usize, opts: Options) -> Result<Arc<Self>, ()> {
+        let tcbs = TCBS.lock();
+        let port = alloc_port(&tcbs, port, opts.reuseaddr)?;
+        let l = Arc::new(Self {
+            port,
+            backlog: backlog.max(1),
+            opts,
+            queue: Mutex::new(VecDeque::new(), "listener"),
+        });
+        LISTENERS.lock().push(Arc::clone(&l));
+        Ok(l)
+    }
+
+    fn chan(&self) -> usize {
+        &self.queue as *const _ as usize
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // Take the next established connection, waiting for one
+    // unless nonblock.
+    pub fn accept(&self, nonblock: bool) -> Result<Arc<Tcb>, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut queue = self.queue.lock();
+        loop {
+            if let Some(t) = queue.pop_front() {
+                return Ok(t);
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            queue = p.sleep(self.chan(), queue);
+        }
+    }
+
+    // The socket is closed: reset the connections not accepted.
+    pub fn close(self: &Arc<Self>) {
+        LISTENERS.lock().retain(|l| !Arc::ptr_eq(l, self));
+        let queue = core::mem::take(&mut *self.queue.lock());
+        for t in queue {
+            t.inner.lock().abort(true);
+        }
+    }
+
+    // Connections to the port not yet established.
+    fn half_open(&self, tcbs: &[Arc<Tcb>]) -> usize {
+        tcbs.iter()
+            .filter(|t| {
+                let s = t.inner.lock();
+                s.state == State::SynReceived
+                    && s.listener.as_ref().is_some_and(|l| l.as_ptr() == self)
+            })
+            .count()
+    }
+
+    // A SYN came to the port. Connections both half-open and
+    // waiting for accept() count against the backlog, so a flood
+    // of SYNs takes no more than that.
+    fn open(self: &Arc<Self>, src: u32, seg: &Segment) {
+        let mut tcbs = TCBS.lock();
+        if self.queue.lock().len() + self.half_open(&tcbs) >= self.backlog {
+            return; // the peer will try again
+        }
+        let mut inner = Inner::new(seg.dport, src, seg.sport, self.opts);
+        inner.state = State::SynReceived;
+        inner.rcv_nxt = seg.seq.wrapping_add(1);
+        inner.snd_wnd = seg.wnd as usize;
+        inner.mss = seg.peer_mss();
+        inner.listener = Some(Arc::downgrade(self));
+        let tcb = Arc::new(Tcb {
+            inner: Mutex::new(inner, "tcb"),
+        });
+        tcbs.push(Arc::clone(&tcb));
+        drop(tcbs);
+        tcb.inner.lock().send_syn();
+    }
+}
+
+// Handle a TCP segment from src to dst.
+pub fn receive(src: u32, dst: u32, seg: &[u8]) {
+    if dst != LOCAL {
+        return;
+    }
+    let Some(seg) = Segment::parse(src, dst, seg) else {
+        return;
+    };
+    let tcb = TCBS
+        .lock()
+        .iter()
+        .find(|t| {
+            let s = t.inner.lock();
+            s.state != State::Closed
+                && s.lport == seg.dport
+                && s.raddr == src
+                && s.rport == seg.sport
+        })
+        .cloned();
+    if let Some(tcb) = tcb {
+        tcb.input(&seg);
+        return;
+    }
+    if seg.flags & (SYN | ACK | RST) == SYN {
+        let l = LISTENERS
+            .lock()
+            .iter()
+            .find(|l| l.port == seg.dport)
+            .cloned();
+        if let Some(l) = l {
+            l.open(src, &seg);
+            return;
+        }
+    }
+    reset(src, &seg);
+}
+
+// Run the timers; called every tick.
+pub fn timer(now: usize) {
+    let mut tcbs = TCBS.lock();
+    for t in tcbs.iter() {
+        t.tick(now);
+    }
+    tcbs.retain(|t| t.inner.lock().state != State::Closed);
+}
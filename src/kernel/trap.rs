@@ -1,6 +1,7 @@
 use crate::{
     kernelvec::kernelvec,
-    memlayout::{TRAMPOLINE, UART0_IRQ, VIRTIO0_IRQ},
+    log,
+    memlayout::{TRAMPOLINE, UART0_IRQ},
     plic,
     proc::{Cpus, ProcState, Process, CPUS, PROCS},
     riscv::{
@@ -9,9 +10,10 @@ use crate::{
     },
     spinlock::Mutex,
     syscall::syscall,
+    tcp,
     trampoline::trampoline,
     uart::UART,
-    virtio_disk::DISK,
+    virtio_disk, virtio_net,
     vm::Addr,
 };
 
@@ -203,9 +205,15 @@ pub extern "C" fn kerneltrap() {
 }
 
 fn clockintr() {
-    let mut ticks = TICKS.lock();
-    *ticks += 1;
-    PROCS.wakeup(&(*ticks) as *const _ as usize)
+    let now = {
+        let mut ticks = TICKS.lock();
+        *ticks += 1;
+        PROCS.wakeup(&(*ticks) as *const _ as usize);
+        *ticks
+    };
+    // out of the lock; the timers read the ticks.
+    tcp::timer(now);
+    log::timer();
 }
 
 // check if it's an external interrupt or software interrupt,
@@ -224,7 +232,8 @@ fn devintr(intr: Interrupt) -> Option<Intr> {
             if let Some(irq) = irq {
                 match irq {
                     UART0_IRQ => UART.intr(),
-                    VIRTIO0_IRQ => DISK.intr(),
+                    irq if virtio_disk::intr(irq) => {}
+                    irq if virtio_net::intr(irq) => {}
                     _ => println!("unexpected interrupt irq={}", irq),
                 }
                 // the PLIC allows each device to raise at most one
@@ -0,0 +1,655 @@
+// Unix domain sockets.
+//
+// A stream connection is a pair of Channels, one each way. A
+// listening socket queues the connections made to it until they
+// are accepted; connect() does not wait for accept(). A datagram
+// socket has a queue of the datagrams sent to it.
+//
+// bind() makes a socket inode at a path, and connect() and sendto()
+// find the socket by the inode their path names. A bound socket
+// keeps its inode, so the inode is not reused for another socket
+// while it is bound, even if the path is unlinked.
+//
+// Open files can be sent along with data (SCM_RIGHTS). On a stream
+// they go with the first byte of the write, and a read that reaches
+// them stops short of the next files. Files that the reader does
+// not take are closed. Unix domain sockets themselves cannot be
+// sent: one in flight could keep itself open, through its own
+// connection or queue or a cycle of others, and there is no
+// garbage collection of such cycles.
+
+use crate::{
+    file::File,
+    fs::Path,
+    log::LOG,
+    param::NOFILE,
+    proc::{CopyInOut, Process, CPUS, PROCS},
+    socket::{shut, sock},
+    spinlock::Mutex,
+    stat::{IType, Stat},
+    vm::VirtAddr,
+};
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+const STREAMBUF: usize = 32 * 1024; // bytes buffered each way
+const MAXDGRAM: usize = 32 * 1024;
+const MAXQUEUE: usize = 64; // datagrams queued per socket
+pub const MAXRIGHTS: usize = NOFILE; // files sent at once
+
+// One direction of a stream.
+struct Channel {
+    inner: Mutex<ChanInner>,
+}
+
+struct ChanInner {
+    data: VecDeque<u8>,
+    // files, and the stream position of the byte they go with
+    rights: VecDeque<(usize, Vec<File>)>,
+    taken: usize, // bytes read so far
+    wclosed: bool,
+    rclosed: bool,
+}
+
+impl Channel {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(
+                ChanInner {
+                    data: VecDeque::new(),
+                    rights: VecDeque::new(),
+                    taken: 0,
+                    wclosed: false,
+                    rclosed: false,
+                },
+                "unixchan",
+            ),
+        })
+    }
+
+    fn chan(&self) -> usize {
+        &self.inner as *const _ as usize
+    }
+
+    fn write(
+        &self,
+        src: VirtAddr,
+        n: usize,
+        nonblock: bool,
+        files: Vec<File>,
+    ) -> Result<usize, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut files = Some(files).filter(|f| !f.is_empty());
+        if files.is_some() && n == 0 {
+            return Err(());
+        }
+        let mut c = self.inner.lock();
+        let mut i = 0;
+        while i < n {
+            if c.rclosed || c.wclosed {
+                break;
+            }
+            let space = STREAMBUF.saturating_sub(c.data.len());
+            if space == 0 {
+                if nonblock || p.inner.lock().killed {
+                    break;
+                }
+                c = p.sleep(self.chan(), c);
+                continue;
+            }
+            let m = space.min(n - i);
+            let mut buf = vec![0u8; m];
+            if unsafe { p.either_copyin(&mut buf[..], src + i) }.is_err() {
+                break;
+            }
+            if let Some(files) = files.take() {
+                let pos = c.taken + c.data.len();
+                c.rights.push_back((pos, files));
+            }
+            c.data.extend(buf);
+            i += m;
+            PROCS.wakeup(self.chan());
+        }
+        drop(c);
+        // files not sent are closed out of the lock.
+        drop(files);
+        if i == 0 && n > 0 {
+            return Err(());
+        }
+        Ok(i)
+    }
+
+    fn read(&self, dst: VirtAddr, n: usize, nonblock: bool) -> Result<(usize, Vec<File>), ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut c = self.inner.lock();
+        loop {
+            if !c.data.is_empty() {
+                break;
+            }
+            if c.wclosed || c.rclosed {
+                return Ok((0, Vec::new()));
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            c = p.sleep(self.chan(), c);
+        }
+        // files go with the first byte, once it is copied out.
+        let first = c.rights.front().map_or(false, |r| r.0 == c.taken);
+        let mut m = n.min(c.data.len());
+        if let Some(&(pos, _)) = c.rights.get(first as usize) {
+            m = m.min(pos - c.taken);
+        }
+        let (a, b) = c.data.as_slices();
+        let k = m.min(a.len());
+        let res = unsafe {
+            p.either_copyout(dst, &a[..k])
+                .and_then(|_| p.either_copyout(dst + k, &b[..m - k]))
+        };
+        let mut files = Vec::new();
+        if res.is_ok() {
+            if first {
+                files = c.rights.pop_front().unwrap().1;
+            }
+            c.data.drain(..m);
+            c.taken += m;
+            PROCS.wakeup(self.chan());
+        }
+        drop(c);
+        res.and(Ok((m, files)))
+    }
+
+    // No more writes: the reader sees the end once it has all.
+    fn close_write(&self) {
+        self.inner.lock().wclosed = true;
+        PROCS.wakeup(self.chan());
+    }
+
+    // No reader: writes fail, and what is buffered is thrown away.
+    fn close_read(&self) {
+        let rights = {
+            let mut c = self.inner.lock();
+            c.rclosed = true;
+            c.data.clear();
+            core::mem::take(&mut c.rights)
+        };
+        PROCS.wakeup(self.chan());
+        drop(rights);
+    }
+}
+
+// An end of a stream connection; dropping it closes it.
+struct Conn {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+}
+
+impl Conn {
+    fn pair() -> (Self, Self) {
+        let (a, b) = (Channel::new(), Channel::new());
+        (
+            Self {
+                rx: Arc::clone(&a),
+                tx: Arc::clone(&b),
+            },
+            Self { rx: b, tx: a },
+        )
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.rx.close_read();
+        self.tx.close_write();
+    }
+}
+
+// A listening stream socket.
+struct Listener {
+    backlog: usize,
+    queue: Mutex<VecDeque<Conn>>,
+}
+
+impl Listener {
+    fn chan(&self) -> usize {
+        &self.queue as *const _ as usize
+    }
+
+    fn connect(&self) -> Result<Conn, ()> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= self.backlog {
+            return Err(());
+        }
+        let (c, s) = Conn::pair();
+        queue.push_back(s);
+        PROCS.wakeup(self.chan());
+        Ok(c)
+    }
+
+    fn accept(&self, nonblock: bool) -> Result<Conn, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut queue = self.queue.lock();
+        loop {
+            if let Some(c) = queue.pop_front() {
+                return Ok(c);
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            queue = p.sleep(self.chan(), queue);
+        }
+    }
+}
+
+// connections not accepted are closed.
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let pending = core::mem::take(&mut *self.queue.lock());
+        drop(pending);
+    }
+}
+
+struct Datagram {
+    from: Option<String>, // path of the sender, if bound
+    data: Vec<u8>,
+    files: Vec<File>,
+}
+
+// The datagrams sent to a datagram socket.
+struct Queue {
+    inner: Mutex<(VecDeque<Datagram>, bool)>, // and closed
+}
+
+impl Queue {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new((VecDeque::new(), false), "unixdgram"),
+        })
+    }
+
+    fn chan(&self) -> usize {
+        &self.inner as *const _ as usize
+    }
+
+    // Queue d, waiting for room unless nonblock. Gives d back if
+    // it cannot be queued, so its files are closed out of the lock.
+    fn deliver(&self, d: Datagram, nonblock: bool) -> Result<(), Datagram> {
+        let p = CPUS.my_proc().unwrap();
+        let mut q = self.inner.lock();
+        loop {
+            if q.1 {
+                return Err(d);
+            }
+            if q.0.len() < MAXQUEUE {
+                q.0.push_back(d);
+                PROCS.wakeup(self.chan());
+                return Ok(());
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(d);
+            }
+            q = p.sleep(self.chan(), q);
+        }
+    }
+
+    fn take(&self, nonblock: bool) -> Result<Datagram, ()> {
+        let p = CPUS.my_proc().unwrap();
+        let mut q = self.inner.lock();
+        loop {
+            if let Some(d) = q.0.pop_front() {
+                PROCS.wakeup(self.chan());
+                return Ok(d);
+            }
+            if nonblock || p.inner.lock().killed {
+                return Err(());
+            }
+            q = p.sleep(self.chan(), q);
+        }
+    }
+
+    // Put d back at the head, where take() found it. Dropped, out
+    // of the lock, if the queue has been closed since.
+    fn put_back(&self, d: Datagram) {
+        let mut q = self.inner.lock();
+        if q.1 {
+            drop(q);
+            drop(d);
+            return;
+        }
+        q.0.push_front(d);
+        PROCS.wakeup(self.chan());
+    }
+
+    fn close(&self) {
+        let queued = {
+            let mut q = self.inner.lock();
+            q.1 = true;
+            core::mem::take(&mut q.0)
+        };
+        PROCS.wakeup(self.chan());
+        drop(queued);
+    }
+}
+
+// What a bound socket inode leads to.
+enum Endpoint {
+    Listener(Weak<Listener>),
+    Queue(Weak<Queue>),
+}
+
+// bound socket inodes, by device and inode number
+static BOUND: Mutex<Vec<((u32, u32), Endpoint)>> = Mutex::new(Vec::new(), "unixbound");
+
+// The path and inode a socket is bound to.
+struct Name {
+    path: String,
+    key: (u32, u32),
+    ip: Option<crate::fs::Inode>,
+}
+
+impl Name {
+    // Make a socket inode at path; Err if anything is there.
+    fn bind(path: &str) -> Result<Self, ()> {
+        LOG.begin_op();
+        let ip = crate::fs::create(Path::new(path), IType::Socket, 0, 0, true);
+        let key = ip.as_ref().map(|ip| {
+            let mut st = Stat::default();
+            ip.lock().stat(&mut st);
+            (st.dev, st.ino)
+        });
+        LOG.end_op();
+        Ok(Self {
+            path: path.to_string(),
+            key: key.ok_or(())?,
+            ip,
+        })
+    }
+
+    fn register(&self, e: Endpoint) {
+        BOUND.lock().push((self.key, e));
+    }
+}
+
+impl Drop for Name {
+    fn drop(&mut self) {
+        BOUND.lock().retain(|(k, _)| *k != self.key);
+        LOG.begin_op();
+        self.ip.take();
+        LOG.end_op();
+    }
+}
+
+// Find the socket bound to the inode at path.
+fn lookup(path: &str) -> Result<Endpoint, ()> {
+    LOG.begin_op();
+    let key = Path::new(path).namei().and_then(|(_, ip)| {
+        let guard = ip.lock();
+        let mut st = Stat::default();
+        guard.stat(&mut st);
+        (st.itype == IType::Socket).then_some((st.dev, st.ino))
+    });
+    LOG.end_op();
+    let key = key.ok_or(())?;
+    let bound = BOUND.lock();
+    match bound.iter().find(|(k, _)| *k == key).map(|(_, e)| e) {
+        Some(Endpoint::Listener(l)) if l.strong_count() > 0 => Ok(Endpoint::Listener(l.clone())),
+        Some(Endpoint::Queue(q)) if q.strong_count() > 0 => Ok(Endpoint::Queue(q.clone())),
+        _ => Err(()),
+    }
+}
+
+enum State {
+    Idle, // a stream socket not connected or listening
+    Listen(Arc<Listener>),
+    Conn(Conn),
+    Dgram {
+        queue: Arc<Queue>,
+        peer: Option<Weak<Queue>>,
+    },
+}
+
+pub struct UnixSocket {
+    inner: Mutex<(State, Option<Name>)>,
+}
+
+impl core::fmt::Debug for UnixSocket {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let inner = self.inner.lock();
+        let kind = match inner.0 {
+            State::Idle => "stream",
+            State::Listen(_) => "listening",
+            State::Conn(_) => "connected",
+            State::Dgram { .. } => "datagram",
+        };
+        write!(
+            f,
+            "UnixSocket({} {:?})",
+            kind,
+            inner.1.as_ref().map(|n| n.path.as_str())
+        )
+    }
+}
+
+impl UnixSocket {
+    fn with(state: State) -> Self {
+        Self {
+            inner: Mutex::new((state, None), "unixsock"),
+        }
+    }
+
+    fn dgram() -> State {
+        State::Dgram {
+            queue: Queue::new(),
+            peer: None,
+        }
+    }
+
+    pub fn new(stype: usize) -> Option<Self> {
+        match stype {
+            sock::STREAM => Some(Self::with(State::Idle)),
+            sock::DGRAM => Some(Self::with(Self::dgram())),
+            _ => None,
+        }
+    }
+
+    // Two sockets connected to each other.
+    pub fn pair(stype: usize) -> Option<(Self, Self)> {
+        match stype {
+            sock::STREAM => {
+                let (a, b) = Conn::pair();
+                Some((Self::with(State::Conn(a)), Self::with(State::Conn(b))))
+            }
+            sock::DGRAM => {
+                let (qa, qb) = (Queue::new(), Queue::new());
+                let a = State::Dgram {
+                    peer: Some(Arc::downgrade(&qb)),
+                    queue: Arc::clone(&qa),
+                };
+                let b = State::Dgram {
+                    peer: Some(Arc::downgrade(&qa)),
+                    queue: qb,
+                };
+                Some((Self::with(a), Self::with(b)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn bind(&self, path: &str) -> Result<(), ()> {
+        if self.inner.lock().1.is_some() {
+            return Err(());
+        }
+        // create() sleeps, so out of the lock.
+        let name = Name::bind(path)?;
+        let mut inner = self.inner.lock();
+        if inner.1.is_some() {
+            drop(inner);
+            return Err(());
+        }
+        if let State::Dgram { queue, .. } = &inner.0 {
+            name.register(Endpoint::Queue(Arc::downgrade(queue)));
+        }
+        inner.1 = Some(name);
+        Ok(())
+    }
+
+    pub fn listen(&self, backlog: usize) -> Result<(), ()> {
+        let mut inner = self.inner.lock();
+        let (state, Some(name)) = &mut *inner else {
+            return Err(()); // not bound
+        };
+        match state {
+            State::Idle => {
+                let l = Arc::new(Listener {
+                    backlog: backlog.max(1),
+                    queue: Mutex::new(VecDeque::new(), "unixlisten"),
+                });
+                name.register(Endpoint::Listener(Arc::downgrade(&l)));
+                *state = State::Listen(l);
+                Ok(())
+            }
+            State::Listen(_) => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    pub fn connect(&self, path: &str) -> Result<(), ()> {
+        let e = lookup(path)?;
+        let mut inner = self.inner.lock();
+        match (&mut inner.0, e) {
+            (state @ State::Idle, Endpoint::Listener(l)) => {
+                let c = l.upgrade().ok_or(())?.connect()?;
+                *state = State::Conn(c);
+                Ok(())
+            }
+            (State::Dgram { peer, .. }, Endpoint::Queue(q)) => {
+                peer.replace(q);
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    pub fn accept(&self, nonblock: bool) -> Result<Self, ()> {
+        let l = match &self.inner.lock().0 {
+            State::Listen(l) => Arc::clone(l),
+            _ => return Err(()),
+        };
+        let c = l.accept(nonblock)?;
+        Ok(Self::with(State::Conn(c)))
+    }
+
+    // Send n bytes at src and files, to the socket bound at path
+    // or the connected one.
+    pub fn send(
+        &self,
+        src: VirtAddr,
+        n: usize,
+        nonblock: bool,
+        path: Option<&str>,
+        files: Vec<File>,
+    ) -> Result<usize, ()> {
+        // see the top of the file.
+        if files
+            .iter()
+            .any(|f| f.socket().and_then(|s| s.unix()).is_some())
+        {
+            return Err(());
+        }
+        // the queue sent to, and the path sent from
+        let (to, from) = {
+            let inner = self.inner.lock();
+            match &inner.0 {
+                State::Conn(c) => {
+                    let tx = Arc::clone(&c.tx);
+                    drop(inner);
+                    return tx.write(src, n, nonblock, files);
+                }
+                State::Dgram { peer, .. } => {
+                    (peer.clone(), inner.1.as_ref().map(|n| n.path.clone()))
+                }
+                _ => return Err(()),
+            }
+        };
+        let to = match path {
+            Some(path) => match lookup(path)? {
+                Endpoint::Queue(q) => q,
+                _ => return Err(()),
+            },
+            None => to.ok_or(())?,
+        };
+        let to = to.upgrade().ok_or(())?;
+        if n > MAXDGRAM {
+            return Err(());
+        }
+        let mut data = vec![0u8; n];
+        unsafe { CPUS.my_proc().unwrap().either_copyin(&mut data[..], src)? };
+        let d = Datagram { from, data, files };
+        // an undelivered datagram is dropped here, out of the lock.
+        to.deliver(d, nonblock).or(Err(()))?;
+        Ok(n)
+    }
+
+    // Receive up to n bytes to dst, the files that came with them,
+    // and the path of the sender if it is known. A longer datagram
+    // is cut short.
+    pub fn recv(
+        &self,
+        dst: VirtAddr,
+        n: usize,
+        nonblock: bool,
+    ) -> Result<(usize, Vec<File>, Option<String>), ()> {
+        let queue = {
+            let inner = self.inner.lock();
+            match &inner.0 {
+                State::Conn(c) => {
+                    let rx = Arc::clone(&c.rx);
+                    drop(inner);
+                    let (m, files) = rx.read(dst, n, nonblock)?;
+                    return Ok((m, files, None));
+                }
+                State::Dgram { queue, .. } => Arc::clone(queue),
+                _ => return Err(()),
+            }
+        };
+        let d = queue.take(nonblock)?;
+        let m = n.min(d.data.len());
+        if unsafe { CPUS.my_proc().unwrap().either_copyout(dst, &d.data[..m]) }.is_err() {
+            // left for the next recv, files and all.
+            queue.put_back(d);
+            return Err(());
+        }
+        Ok((m, d.files, d.from))
+    }
+
+    pub fn shutdown(&self, how: usize) -> Result<(), ()> {
+        let (rx, tx) = match &self.inner.lock().0 {
+            State::Conn(c) => (Arc::clone(&c.rx), Arc::clone(&c.tx)),
+            _ => return Err(()),
+        };
+        if how == shut::RD || how == shut::RDWR {
+            rx.close_read();
+        }
+        if how == shut::WR || how == shut::RDWR {
+            tx.close_write();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        // take everything out, to close it out of the lock.
+        let (state, name) = core::mem::replace(&mut *self.inner.lock(), (State::Idle, None));
+        if let State::Dgram { queue, .. } = &state {
+            queue.close();
+        }
+        drop(state);
+        drop(name);
+    }
+}
@@ -0,0 +1,378 @@
+//
+// what qemu's virtio devices have in common: the mmio interface,
+// feature negotiation and the virtqueues. virtio_disk.rs and
+// virtio_net.rs drive the devices themselves.
+//
+// the spec is at https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+//
+
+use core::sync::atomic::{fence, Ordering};
+
+// Memory mapped IO registers.
+#[repr(usize)]
+pub enum VirtioMMIO {
+    // 0x74726976
+    MagicValue = 0x00,
+    // version; should be 2
+    Version = 0x004,
+    // device type; 1 is net, 2 is disk
+    DeviceId = 0008,
+    // 0x554d4552
+    VenderId = 0x00c,
+    DeviceFeatures = 0x010,
+    // which 32 feature bits DeviceFeatures shows, write-only
+    DeviceFeaturesSel = 0x014,
+    DriverFeatures = 0x020,
+    // which 32 feature bits DriverFeatures takes, write-only
+    DriverFeaturesSel = 0x024,
+    // select queue, write-only
+    QueueSel = 0x030,
+    // max size of current queue, read-only
+    QueueNumMax = 0x034,
+    // size of current queue, write-only
+    QueueNum = 0x038,
+    // ready bit
+    QueueReady = 0x044,
+    // write-only
+    QueueNotify = 0x050,
+    // read-only
+    InterruptStatus = 0x060,
+    // write-only
+    InterruptAck = 0x064,
+    // read/write
+    Status = 0x070,
+    // physical address for descpritor table, write-only
+    QueueDescLow = 0x080,
+    QueueDescHigh = 0x084,
+    // physical address for available ring, write-only
+    DriverDescLow = 0x090,
+    DriverDescHigh = 0x094,
+    // physical address for used ring, write-only
+    DeviceDescLow = 0x0a0,
+    DeviceDescHigh = 0x0a4,
+    // device configuration space, read-only here
+    Config = 0x100,
+}
+
+impl VirtioMMIO {
+    pub fn read(self, base: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((base + self as usize) as *const u32) }
+    }
+    pub unsafe fn write(self, base: usize, data: u32) {
+        core::ptr::write_volatile((base + self as usize) as *mut u32, data);
+    }
+    // read the word at off in the configuration space.
+    pub fn config(base: usize, off: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((base + Self::Config as usize + off) as *const u32) }
+    }
+    // read the byte at off in the configuration space.
+    pub fn config_u8(base: usize, off: usize) -> u8 {
+        unsafe { core::ptr::read_volatile((base + Self::Config as usize + off) as *const u8) }
+    }
+}
+
+// device types
+pub const VIRTIO_ID_NET: u32 = 1;
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+
+// Status register bits, from qemu virtio_config.h
+pub mod virtio_status {
+    pub const ACKNOWLEDGE: u32 = 0b0001;
+    pub const DRIVER: u32 = 0b0010;
+    pub const DRIVER_OK: u32 = 0b0100;
+    pub const FEATURES_OK: u32 = 0b1000;
+}
+
+// Feature bits of any device
+pub mod virtio_features {
+    pub const F_ANY_LAYOUT: u32 = 1 << 27;
+    pub const RING_F_INDIRECT_DESC: u32 = 1 << 28;
+    pub const RING_F_EVENT_IDX: u32 = 1 << 29;
+    // bit 32: the device follows the spec rather than legacy rules
+    pub const F_VERSION_1: u32 = 1 << 0;
+}
+
+// this many virtio descriptors in a queue.
+// must be a power of 2.
+pub const NUM: usize = 32;
+
+// a single descriptor, from the spc
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: VirtqDescFlags,
+    pub next: u16,
+}
+
+pub type VirtqDescFlags = u16;
+pub mod virtq_desc_flags {
+    pub const FREED: u16 = 0b000;
+    // chained with another descriptor
+    pub const NEXT: u16 = 0b001;
+    // device writes (vs read)
+    pub const WRITE: u16 = 0b010;
+    // points to a table of descriptors
+    pub const INDIRECT: u16 = 0b100;
+}
+
+impl VirtqDesc {
+    pub const fn new() -> Self {
+        Self {
+            addr: 0,
+            len: 0,
+            flags: virtq_desc_flags::FREED,
+            next: 0,
+        }
+    }
+}
+
+// the (entire) avail ring, from the spc
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(2))]
+struct VirtqAvail {
+    flags: u16,       // always zero,
+    idx: u16,         // driver will write ring[idx] next
+    ring: [u16; NUM], // descriptor numbers of chain heads
+    unused: u16,
+}
+
+impl VirtqAvail {
+    const fn new() -> Self {
+        Self {
+            flags: 0,
+            idx: 0,
+            ring: [0; NUM],
+            unused: 0,
+        }
+    }
+}
+
+// one entry in the "used" ring, with which the
+// device tells the driver about completed requests.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32, // index of start of completes descriptor chain
+    len: u32,
+}
+
+impl VirtqUsedElem {
+    const fn new() -> Self {
+        Self { id: 0, len: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(4))]
+struct VirtqUsed {
+    flags: u16, // always zero
+    idx: u16,   // device increments when it adds a ring[] entry
+    ring: [VirtqUsedElem; NUM],
+}
+
+impl VirtqUsed {
+    const fn new() -> Self {
+        Self {
+            flags: 0,
+            idx: 0,
+            ring: [VirtqUsedElem::new(); NUM],
+        }
+    }
+}
+
+// One virtqueue.
+#[repr(C)]
+pub struct Virtq {
+    // a set (not a ring) of DMA descpritors, with which the
+    // driver tells the device where to read and write individual
+    // operations. there are NUM descpritors.
+    pub desc: [VirtqDesc; NUM],
+
+    // a ring in which the driver writes descriptor numbers
+    // that the driver would like the device to process. it only
+    // includes the head descriptor of each chain. the ring has
+    // NUM elements.
+    avail: VirtqAvail,
+
+    // a ring in which the device writes descriptor numbers that
+    // the device has finished processing (just the head of each chain).
+    // there are NUM used ring entries
+    used: VirtqUsed,
+
+    // our own book-keeping
+    free: [bool; NUM], // is a descriptor free ?
+    used_idx: u16,     // we've looked this far in used[2..NUM].
+    sel: u32,          // queue number
+}
+
+impl Virtq {
+    pub const fn new() -> Self {
+        Self {
+            desc: [VirtqDesc::new(); NUM],
+            avail: VirtqAvail::new(),
+            used: VirtqUsed::new(),
+            free: [false; NUM],
+            used_idx: 0,
+            sel: 0,
+        }
+    }
+
+    // Set up the queue numbered sel of the device at base.
+    // The queue must not move afterwards.
+    pub unsafe fn init(&mut self, base: usize, sel: u32) {
+        self.sel = sel;
+        VirtioMMIO::QueueSel.write(base, sel);
+
+        // ensure the queue is not in use
+        assert!(
+            VirtioMMIO::QueueReady.read(base) == 0,
+            "virtio queue shoud not be ready"
+        );
+
+        // check maximum queue size.
+        let max = VirtioMMIO::QueueNumMax.read(base);
+        assert!(max != 0, "virtio queue missing");
+        assert!(max >= NUM as u32, "virtio max queue too short");
+
+        // set queue size.
+        VirtioMMIO::QueueNum.write(base, NUM as _);
+
+        // write physical addresses.
+        VirtioMMIO::QueueDescLow.write(base, &self.desc as *const _ as u64 as u32);
+        VirtioMMIO::QueueDescHigh.write(base, (&self.desc as *const _ as u64 >> 32) as u32);
+        VirtioMMIO::DriverDescLow.write(base, &self.avail as *const _ as u64 as u32);
+        VirtioMMIO::DriverDescHigh.write(base, (&self.avail as *const _ as u64 >> 32) as u32);
+        VirtioMMIO::DeviceDescLow.write(base, &self.used as *const _ as u64 as u32);
+        VirtioMMIO::DeviceDescHigh.write(base, (&self.used as *const _ as u64 >> 32) as u32);
+
+        // queue is ready.
+        VirtioMMIO::QueueReady.write(base, 0x1);
+
+        // all NUM descriptors start out unused.
+        self.free.iter_mut().for_each(|f| *f = true);
+    }
+
+    // find a free descriptor, mark it non-free, return its index.
+    pub fn alloc_desc(&mut self) -> Option<usize> {
+        let i = self.free.iter().position(|&f| f)?;
+        self.free[i] = false;
+        Some(i)
+    }
+
+    // mark a descriptor as free.
+    pub fn free_desc(&mut self, i: usize) {
+        assert!(i < NUM, "free_dec 1");
+        assert!(!self.free[i], "free_desc 2");
+        self.desc[i].addr = 0;
+        self.desc[i].len = 0;
+        self.desc[i].flags = 0;
+        self.desc[i].next = 0;
+        self.free[i] = true;
+    }
+
+    // Put the chain starting at descriptor i in the avail ring.
+    // The device hears of it at the next notify().
+    pub fn push(&mut self, i: usize) {
+        // tell the device the index of our descriptor.
+        let a = self.avail.idx as usize % NUM;
+        self.avail.ring[a] = i.try_into().unwrap();
+
+        fence(Ordering::SeqCst);
+
+        // tell the device another avail ring entry is available.
+        self.avail.idx = self.avail.idx.wrapping_add(1); // not % NUM ...
+    }
+
+    pub fn notify(&self, base: usize) {
+        fence(Ordering::SeqCst);
+        unsafe {
+            VirtioMMIO::QueueNotify.write(base, self.sel); // value is queue number
+        }
+    }
+
+    // Take the next chain the device is done with: the index of
+    // its first descriptor, and the bytes the device wrote.
+    pub fn pop(&mut self) -> Option<(usize, u32)> {
+        // the device increments used.idx when it
+        // adds an entry to the used ring.
+        if self.used_idx == unsafe { core::ptr::read_volatile(&self.used.idx) } {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = self.used.ring[self.used_idx as usize % NUM];
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Some((elem.id as usize, elem.len))
+    }
+}
+
+// Is there a virtio device of type id at base?
+pub fn probe(base: usize, id: u32) -> bool {
+    VirtioMMIO::MagicValue.read(base) == 0x74726976
+        && VirtioMMIO::Version.read(base) == 2
+        && VirtioMMIO::DeviceId.read(base) == id
+        && VirtioMMIO::VenderId.read(base) == 0x554d4551
+}
+
+// Reset the device at base and negotiate features: select
+// picks from the low 32 bits the device offers, and VERSION_1
+// is taken if offered. Returns the low bits taken. The queues
+// are set up next, then ready().
+pub unsafe fn negotiate(base: usize, select: impl FnOnce(u32) -> u32) -> u32 {
+    let mut status: u32 = 0;
+
+    // reset device
+    VirtioMMIO::Status.write(base, status);
+
+    // set ACKNOWLEDGE status bit
+    status |= virtio_status::ACKNOWLEDGE;
+    VirtioMMIO::Status.write(base, status);
+
+    // set DRIVER status bit
+    status |= virtio_status::DRIVER;
+    VirtioMMIO::Status.write(base, status);
+
+    // negotiate features
+    VirtioMMIO::DeviceFeaturesSel.write(base, 0);
+    let features = select(VirtioMMIO::DeviceFeatures.read(base));
+    VirtioMMIO::DriverFeaturesSel.write(base, 0);
+    VirtioMMIO::DriverFeatures.write(base, features);
+    VirtioMMIO::DeviceFeaturesSel.write(base, 1);
+    let high = VirtioMMIO::DeviceFeatures.read(base) & virtio_features::F_VERSION_1;
+    VirtioMMIO::DriverFeaturesSel.write(base, 1);
+    VirtioMMIO::DriverFeatures.write(base, high);
+
+    // tell device that feature negotiation is complete.
+    status |= virtio_status::FEATURES_OK;
+    VirtioMMIO::Status.write(base, status);
+
+    // re-read status to ensure FEATURES_OK is set.
+    status = VirtioMMIO::Status.read(base);
+    assert!(
+        status & virtio_status::FEATURES_OK != 0,
+        "virtio FEATURES_OK unset"
+    );
+    features
+}
+
+// Tell the device at base we're completely ready.
+pub unsafe fn ready(base: usize) {
+    let status = VirtioMMIO::Status.read(base);
+    VirtioMMIO::Status.write(base, status | virtio_status::DRIVER_OK);
+}
+
+// Acknowledge an interrupt of the device at base.
+pub fn ack(base: usize) {
+    // the device won't raise another interrupt until we tell it
+    // we've seen this interrupt, which the following line does.
+    // this may race with the device writing new entries to
+    // the "used" ring, in which case we may process the new
+    // completion entries in this interrupt, and have nothing to do
+    // in the next interrup, whish is harmless.
+    let intr_stat = VirtioMMIO::InterruptStatus.read(base);
+    unsafe {
+        VirtioMMIO::InterruptAck.write(base, intr_stat & 0x3);
+    }
+    fence(Ordering::SeqCst);
+}
@@ -1,233 +1,136 @@
 use crate::{
     array,
-    bio::Data,
+    bio::{Ahead, Data},
+    blockdev::{self, BlockDevice},
     fs::BSIZE,
-    memlayout::VIRTIO0,
+    memlayout::{virtio, NVIRTIO, VIRTIO0_IRQ},
     proc::{Process, CPUS, PROCS},
-    sleeplock::SleepLockGuard,
     spinlock::Mutex,
+    sync::OnceLock,
+    virtio::{
+        self, virtio_features, virtq_desc_flags, VirtioMMIO, Virtq, VirtqDesc, NUM, VIRTIO_ID_BLOCK,
+    },
 };
-use core::{
-    convert::TryInto,
-    sync::atomic::{fence, Ordering},
-};
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::convert::TryInto;
 
 //
 // driver for qemu's virtio disk device.
 // uses qemu's mmio interface to virtio.
 //
 // qemu ... -drive file=fs.img,if=none,format=raw,id=x0 -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
-
-pub static DISK: Mutex<Disk> = Mutex::new(Disk::new(), "virtio_disk");
-
-// Memory mapped IO registers.
-#[repr(usize)]
-enum VirtioMMIO {
-    // 0x74726976
-    MagicValue = 0x00,
-    // version; should be 2
-    Version = 0x004,
-    // device type; 1 is net, 2 is disk
-    DeviceId = 0008,
-    // 0x554d4552
-    VenderId = 0x00c,
-    DeviceFeatures = 0x010,
-    DriverFeatures = 0x020,
-    // select queue, write-only
-    QueueSel = 0x030,
-    // max size of current queue, read-only
-    QueueNumMax = 0x034,
-    // size of current queue, write-only
-    QueueNum = 0x038,
-    // ready bit
-    QueueReady = 0x044,
-    // write-only
-    QueueNotify = 0x050,
-    // read-only
-    InterruptStatus = 0x060,
-    // write-only
-    InterruptAck = 0x064,
-    // read/write
-    Status = 0x070,
-    // physical address for descpritor table, write-only
-    QueueDescLow = 0x080,
-    QueueDescHigh = 0x084,
-    // physical address for available ring, write-only
-    DriverDescLow = 0x090,
-    DriverDescHigh = 0x094,
-    // physical address for used ring, write-only
-    DeviceDescLow = 0x0a0,
-    DeviceDescHigh = 0x0a4,
-}
-
-impl VirtioMMIO {
-    fn read(self) -> u32 {
-        unsafe { core::ptr::read_volatile((VIRTIO0 + self as usize) as *const u32) }
-    }
-    unsafe fn write(self, data: u32) {
-        core::ptr::write_volatile((VIRTIO0 + self as usize) as *mut u32, data);
-    }
-}
-
-type VirtioStatus = u32;
-// Status register bits, from qemu virtio_config.h
-mod virtio_status {
-    pub(crate) const ACKNOWLEDGE: u32 = 0b0001;
-    pub(crate) const DRIVER: u32 = 0b0010;
-    pub(crate) const DRIVER_OK: u32 = 0b0100;
-    pub(crate) const FEATURES_OK: u32 = 0b1000;
+//
+// every virtio mmio slot is probed, and the disks found are
+// registered as block devices in slot order, so the disk on
+// bus.0 is the root unless there is a RAM disk (see ramdisk.rs).
+// more go on the other buses (bus.1 is the network card in
+// .cargo/config.toml), e.g.
+// qemu ... -drive file=data.img,if=none,format=raw,id=x1 -device virtio-blk-device,drive=x1,bus=virtio-mmio-bus.2
+
+// one per mmio slot, whether or not a disk is there.
+pub static DISKS: [Mutex<Disk>; NVIRTIO] = array![Mutex::new(Disk::new(), "virtio_disk"); NVIRTIO];
+
+// slots of the disks found.
+static SLOTS: OnceLock<Vec<usize>> = OnceLock::new();
+
+// offsets in the block device configuration space
+mod virtio_blk_config {
+    pub(crate) const CAPACITY: usize = 0; // u64, in 512-byte sectors
+    pub(crate) const SEG_MAX: usize = 12;
+    pub(crate) const MAX_DISCARD_SECTORS: usize = 36;
 }
 
-// Device feature bits
-mod virtio_features {
+// Block device feature bits
+mod virtio_blk_features {
+    // Maximum number of segments in a request is in seg_max
+    pub(crate) const BLK_F_SEG_MAX: u32 = 1 << 2;
     // Disk is read-only
     pub(crate) const BLK_F_RO: u32 = 1 << 5;
     // Supports scsi command passthru
     pub(crate) const BLK_F_SCSI: u32 = 1 << 7;
+    // Cache flush command support
+    pub(crate) const BLK_F_FLUSH: u32 = 1 << 9;
     // Writeback mode available in config
     pub(crate) const BLK_F_CONFIG_WCE: u32 = 1 << 11;
     // support more than one vq
     pub(crate) const BLK_F_MQ: u32 = 1 << 12;
-    pub(crate) const F_ANY_LAYOUT: u32 = 1 << 27;
-    pub(crate) const RING_F_INDIRECT_DESC: u32 = 1 << 28;
-    pub(crate) const RING_F_EVENT_IDX: u32 = 1 << 29;
+    // Discard command support
+    pub(crate) const BLK_F_DISCARD: u32 = 1 << 13;
 }
 
-// this many virtio descriptors.
-// must be a power of 2.
-const NUM: usize = 8;
+// every request takes one of the NUM virtio descriptors, which
+// points to its own table of indirect descriptors, so this many
+// can be in flight.
+
+// blocks merged into one request at most.
+const MAXSEG: usize = 16;
+
+// requests waiting for a descriptor; past this, readahead is refused.
+const MAXQUEUE: usize = 64;
 
 #[repr(C)]
 pub struct Disk {
-    // a set (not a ring) of DMA descpritors, with which the
-    // driver tells the device where to read and write individual
-    // disk operations. there are NUM descpritors.
-    // most commands consists of a "chain" (a linked list) of couple of
-    // these descriptors.
-    desc: [VirtqDesc; NUM],
-
-    // a ring in which the driver writes descriptor numbers
-    // that the driver would like the device to process. it only
-    // includes the head descriptor of each chain. the ring has
-    // NUM elements.
-    avail: VirtqAvail,
-
-    // a ring in which the device writes descriptor numbers that
-    // the device has finished processing (just the head of each chain).
-    // there are NUM used ring entries
-    used: VirtqUsed,
-
-    // our own book-keeping
-    free: [bool; NUM], // is a descriptor free ?
-    used_idx: u16,     // we've looked this far in used[2..NUM].
+    base: usize,      // mmio registers
+    capacity: u32,    // size in blocks
+    seg_max: usize,   // blocks merged into one request at most
+    max_discard: u32, // blocks discarded by one request at most, 0 if none
+    readonly: bool,   // the device refuses writes
+    flush: bool,      // the device caches writes until flushed
+    // the virtqueue; each descriptor of a request points to a
+    // table in indirect.
+    vq: Virtq,
 
     // track info aboud in-flight operations,
     // for use when completion interrupt arrives.
-    // indexed by first descriptor index of chain.
+    // indexed by descriptor index.
     info: [Info; NUM],
 
     // disk command handlers.
     // one-for-one with descriptors, for convenience.
     ops: [VirtioBlkReq; NUM],
-}
 
-// a single descriptor, from the spc
-#[derive(Debug, Clone, Copy)]
-#[repr(C, align(16))]
-struct VirtqDesc {
-    addr: u64,
-    len: u32,
-    flags: VirtqDescFlags,
-    next: u16,
-}
+    // ranges of discard commands, likewise.
+    ranges: [VirtioBlkDiscard; NUM],
 
-type VirtqDescFlags = u16;
-mod virtq_desc_flags {
-    pub(crate) const FREED: u16 = 0b00;
-    // chained with another descriptor
-    pub(crate) const NEXT: u16 = 0b01;
-    // device writes (vs read)
-    pub(crate) const WRITE: u16 = 0b10;
-}
+    // the chain of each request: command, blocks, status.
+    // one-for-one with descriptors.
+    indirect: [[VirtqDesc; MAXSEG + 2]; NUM],
 
-impl VirtqDesc {
-    const fn new() -> Self {
-        Self {
-            addr: 0,
-            len: 0,
-            flags: virtq_desc_flags::FREED,
-            next: 0,
-        }
-    }
+    // requests waiting for a free descriptor, oldest first.
+    queue: VecDeque<Req>,
+    plugged: usize, // callers batching requests; see plug()
 }
 
-// the (entire) avail ring, from the spc
-#[derive(Debug, Clone, Copy)]
-#[repr(C, align(2))]
-struct VirtqAvail {
-    flags: u16,       // always zero,
-    idx: u16,         // driver will write ring[idx] next
-    ring: [u16; NUM], // descriptor numbers of chain heads
-    unused: u16,
-}
-
-impl VirtqAvail {
-    const fn new() -> Self {
-        Self {
-            flags: 0,
-            idx: 0,
-            ring: [0; NUM],
-            unused: 0,
-        }
-    }
-}
-
-// one entry in the "used" ring, with which the
-// device tells the driver about completed requests.
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-struct VirtqUsedElem {
-    id: u32, // index of start of completes descriptor chain
-    len: u32,
+// A disk request: blocks [blockno, blockno + segs.len()) read
+// or written at once, blocks [blockno, blockno + nblocks)
+// discarded, or a flush of the device's write cache.
+struct Req {
+    type_: u32, // VIRTIO_BLK_T_*
+    blockno: u32,
+    nblocks: u32,
+    segs: Vec<Seg>,
+    busy: *mut bool, // cleared when done, for requests without blocks
 }
 
-impl VirtqUsedElem {
-    const fn new() -> Self {
-        Self { id: 0, len: 0 }
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-#[repr(C, align(4))]
-struct VirtqUsed {
-    flags: u16, // always zero
-    idx: u16,   // device increments when it adds a ring[] entry
-    ring: [VirtqUsedElem; NUM],
-}
-
-impl VirtqUsed {
-    const fn new() -> Self {
-        Self {
-            flags: 0,
-            idx: 0,
-            ring: [VirtqUsedElem::new(); NUM],
-        }
-    }
+// One block of a request. Its buffer's disk flag stays set
+// until intr() finds the request done.
+struct Seg {
+    data: *mut Data,
+    ahead: Option<Ahead>, // keeps a buffer nobody holds alive
 }
 
 // track info aboud in-flight operations,
 // for use when completion interrupt arrives.
-// indexed by first descriptor index of chain.
 #[repr(C)]
 struct Info {
-    buf: Option<SleepLockGuard<'static, Data>>,
+    req: Option<Req>,
     status: u8,
 }
 
 impl Info {
     const fn new() -> Self {
         Self {
-            buf: None,
+            req: None,
             status: 0,
         }
     }
@@ -238,14 +141,16 @@ impl Info {
 
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
 
 // the format of the first descriptor in a disk request.
-// to be followed by two more descriptors containing
-// the block, and a one-byte status.
+// to be followed by descriptors containing the blocks,
+// and one with a one-byte status.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 struct VirtioBlkReq {
-    type_: u32, // VIRTIO_BLK_T_IN or ..._OUT
+    type_: u32, // VIRTIO_BLK_T_*
     reserved: u32,
     sector: u64,
 }
@@ -260,282 +165,397 @@ impl VirtioBlkReq {
     }
 }
 
+// the data of a discard command: one range of sectors.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct VirtioBlkDiscard {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl VirtioBlkDiscard {
+    const fn new() -> Self {
+        Self {
+            sector: 0,
+            num_sectors: 0,
+            flags: 0,
+        }
+    }
+}
+
 impl Disk {
     const fn new() -> Self {
         Self {
-            desc: [VirtqDesc::new(); NUM],
-            avail: VirtqAvail::new(),
-            used: VirtqUsed::new(),
-            free: [false; NUM],
-            used_idx: 0,
+            base: 0,
+            capacity: 0,
+            seg_max: MAXSEG,
+            max_discard: 0,
+            readonly: false,
+            flush: false,
+            vq: Virtq::new(),
             info: array![Info::new(); NUM],
             ops: [VirtioBlkReq::new(); NUM],
+            ranges: [VirtioBlkDiscard::new(); NUM],
+            indirect: [[VirtqDesc::new(); MAXSEG + 2]; NUM],
+            queue: VecDeque::new(),
+            plugged: 0,
         }
     }
 
-    unsafe fn init(&mut self) {
-        let mut status: VirtioStatus = 0;
-
-        if VirtioMMIO::MagicValue.read() != 0x74726976
-            || VirtioMMIO::Version.read() != 2
-            || VirtioMMIO::DeviceId.read() != 2
-            || VirtioMMIO::VenderId.read() != 0x554d4551
-        {
-            panic!("could not find virtio disk");
+    unsafe fn init(&mut self, base: usize) {
+        self.base = base;
+
+        // RO, FLUSH, DISCARD and SEG_MAX are kept if offered.
+        let features = virtio::negotiate(base, |mut features| {
+            features &= !(virtio_blk_features::BLK_F_SCSI);
+            features &= !(virtio_blk_features::BLK_F_CONFIG_WCE);
+            features &= !(virtio_blk_features::BLK_F_MQ);
+            features &= !(virtio_features::F_ANY_LAYOUT);
+            features &= !(virtio_features::RING_F_EVENT_IDX);
+            assert!(
+                features & virtio_features::RING_F_INDIRECT_DESC != 0,
+                "virtio disk has no indirect descriptors"
+            );
+            features
+        });
+
+        // what the device does, and how big it is.
+        self.readonly = features & virtio_blk_features::BLK_F_RO != 0;
+        self.flush = features & virtio_blk_features::BLK_F_FLUSH != 0;
+        let sectors = VirtioMMIO::config(self.base, virtio_blk_config::CAPACITY) as u64
+            | (VirtioMMIO::config(self.base, virtio_blk_config::CAPACITY + 4) as u64) << 32;
+        self.capacity = (sectors / (BSIZE / 512) as u64).min(u32::MAX as u64) as u32;
+        if features & virtio_blk_features::BLK_F_SEG_MAX != 0 {
+            let seg_max = VirtioMMIO::config(self.base, virtio_blk_config::SEG_MAX);
+            self.seg_max = (seg_max as usize).clamp(1, MAXSEG);
+        }
+        if features & virtio_blk_features::BLK_F_DISCARD != 0 {
+            let max = VirtioMMIO::config(self.base, virtio_blk_config::MAX_DISCARD_SECTORS);
+            self.max_discard = max / (BSIZE / 512) as u32;
         }
-
-        // reset device
-        VirtioMMIO::Status.write(status);
-
-        // set ACKNOWLEDGE status bit
-        status |= virtio_status::ACKNOWLEDGE;
-        VirtioMMIO::Status.write(status);
-
-        // set DRIVER status bit
-        status |= virtio_status::DRIVER;
-        VirtioMMIO::Status.write(status);
-
-        // negotiate features
-        let mut features = VirtioMMIO::DeviceFeatures.read();
-        features &= !(virtio_features::BLK_F_RO);
-        features &= !(virtio_features::BLK_F_SCSI);
-        features &= !(virtio_features::BLK_F_CONFIG_WCE);
-        features &= !(virtio_features::BLK_F_MQ);
-        features &= !(virtio_features::F_ANY_LAYOUT);
-        features &= !(virtio_features::RING_F_EVENT_IDX);
-        features &= !(virtio_features::RING_F_INDIRECT_DESC);
-        VirtioMMIO::DriverFeatures.write(features);
-
-        // tell device that feature negotiation is complete.
-        status |= virtio_status::FEATURES_OK;
-        VirtioMMIO::Status.write(status);
-
-        // re-read status to ensure FEATURES_OK is set.
-        status = VirtioMMIO::Status.read();
-        assert!(
-            status & virtio_status::FEATURES_OK != 0,
-            "virtio disk FEATURES_OK unset"
-        );
 
         // initialize queue 0.
-        VirtioMMIO::QueueSel.write(0);
-
-        // ensure queue 0 is not in use
-        assert!(
-            VirtioMMIO::QueueReady.read() == 0,
-            "virtio disk shoud not be ready"
-        );
-
-        // check maximum queue size.
-        let max = VirtioMMIO::QueueNumMax.read();
-        assert!(max != 0, "virtio disk has no queue 0");
-        assert!(max >= NUM as u32, "virtio disk max queue too short");
-
-        // set queue size.
-        VirtioMMIO::QueueNum.write(NUM as _);
-
-        // write physical addresses.
-        VirtioMMIO::QueueDescLow.write(&self.desc as *const _ as u64 as u32);
-        VirtioMMIO::QueueDescHigh.write((&self.desc as *const _ as u64 >> 32) as u32);
-        VirtioMMIO::DriverDescLow.write(&self.avail as *const _ as u64 as u32);
-        VirtioMMIO::DriverDescHigh.write((&self.avail as *const _ as u64 >> 32) as u32);
-        VirtioMMIO::DeviceDescLow.write(&self.used as *const _ as u64 as u32);
-        VirtioMMIO::DeviceDescHigh.write((&self.used as *const _ as u64 >> 32) as u32);
-
-        // queue is ready.
-        VirtioMMIO::QueueReady.write(0x1);
+        self.vq.init(base, 0);
 
-        // all NUM descriptors start out unused.
-        self.free.iter_mut().for_each(|f| *f = true);
+        virtio::ready(base);
 
-        // tell device we're completely ready.
-        status |= virtio_status::DRIVER_OK;
-        VirtioMMIO::Status.write(status);
-
-        // plic.rs and trap.rs arrange for interrupts from VIRTIO0_IRQ.
-    }
-
-    // find a free descriptor, mark it non-free, return its index.
-    fn alloc_desc(&mut self) -> Option<usize> {
-        self.free
-            .iter_mut()
-            .enumerate()
-            .filter(|(_, v)| **v)
-            .take(1)
-            .map(|(i, v)| {
-                *v = false;
-                i
-            })
-            .next()
-    }
-
-    // mark a descriptor as free.
-    fn free_desc(&mut self, i: usize) {
-        assert!(i < NUM, "free_dec 1");
-        assert!(!self.free[i], "free_desc 2");
-        self.desc[i].addr = 0;
-        self.desc[i].len = 0;
-        self.desc[i].flags = 0;
-        self.desc[i].next = 0;
-        self.free[i] = true;
-        PROCS.wakeup(&self.free[0] as *const _ as usize);
+        // plic.rs and trap.rs arrange for interrupts from the slot's
+        // irq, which intr() passes on to this disk.
     }
 
-    // free a chain of descriptors.
-    fn free_chain(&mut self, mut i: usize) {
-        loop {
-            let desc = self.desc.get(i).unwrap();
-            let flag = desc.flags;
-            let nxt = desc.next;
-            self.free_desc(i);
-            if (flag & virtq_desc_flags::NEXT) != 0 {
-                i = nxt as usize;
-            } else {
-                break;
+    // Queue a block, merged into a queued request for the
+    // blocks next to it if there is one. Hands seg back if the
+    // block is past the end, or it is a write and the disk is
+    // read-only.
+    fn enqueue(&mut self, seg: Seg, write: bool) -> Result<(), Seg> {
+        let blockno = unsafe { (*seg.data).blockno() };
+        if blockno >= self.capacity || write && self.readonly {
+            return Err(seg);
+        }
+        let type_ = if write {
+            VIRTIO_BLK_T_OUT // write the disk
+        } else {
+            VIRTIO_BLK_T_IN // read the disk
+        };
+        for req in self.queue.iter_mut() {
+            if req.type_ != type_ || req.segs.len() >= self.seg_max {
+                continue;
+            }
+            if req.blockno + req.segs.len() as u32 == blockno {
+                req.segs.push(seg);
+                req.nblocks += 1;
+                return Ok(());
+            }
+            if blockno + 1 == req.blockno {
+                req.blockno = blockno;
+                req.segs.insert(0, seg);
+                req.nblocks += 1;
+                return Ok(());
             }
         }
+        self.queue.push_back(Req {
+            type_,
+            blockno,
+            nblocks: 1,
+            segs: vec![seg],
+            busy: core::ptr::null_mut(),
+        });
+        Ok(())
     }
 
-    // allocate three descriptors (they need not be contiguous).
-    // disk transfers always use three descriptors.
-    fn alloc3_desc(&mut self, idx: &mut [usize; 3]) -> Result<(), ()> {
-        for (i, idxi) in idx.iter_mut().enumerate() {
-            match self.alloc_desc() {
-                Some(ix) => *idxi = ix,
-                None => {
-                    for j in 0..i {
-                        self.free_desc(j)
-                    }
-                    return Err(());
+    // Hand queued requests to the device while descriptors are
+    // free. Unless forced, wait for callers batching requests.
+    fn dispatch(&mut self, force: bool) {
+        if self.plugged > 0 && !force {
+            return;
+        }
+        let mut notify = false;
+        while !self.queue.is_empty() {
+            match self.vq.alloc_desc() {
+                Some(i) => {
+                    let req = self.queue.pop_front().unwrap();
+                    self.submit(i, req);
+                    notify = true;
                 }
+                None => break,
             }
         }
-        Ok(())
+        if notify {
+            self.vq.notify(self.base);
+        }
     }
-}
 
-impl Mutex<Disk> {
-    pub fn rw(
-        &self,
-        b: Option<SleepLockGuard<'static, Data>>,
-        write: bool,
-    ) -> Option<SleepLockGuard<'static, Data>> {
-        let mut b = b.unwrap();
-        let sector = b.blockno() as usize * (BSIZE / 512);
+    // format the descriptors of a request, starting at
+    // descriptor i, and put it in the avail ring.
+    fn submit(&mut self, i: usize, req: Req) {
+        // the spec's Section 5.2 says that block operations use a
+        // chain of descriptors: one for type/reserved/sector, one for
+        // each part of the data, one for a 1-byte status result.
+        // the chain is an indirect table, so that it takes only one
+        // descriptor of the ring.
+        // qemu's virtio-blk.c reads them.
 
-        let mut guard = self.lock();
-        let p = CPUS.my_proc().unwrap();
+        let sector = (req.blockno as usize * (BSIZE / 512)) as u64;
+        let op = &mut self.ops[i];
+        op.type_ = req.type_;
+        op.reserved = 0;
+        op.sector = sector;
+
+        let table = &mut self.indirect[i];
+        table[0].addr = op as *mut _ as u64;
+        table[0].len = core::mem::size_of::<VirtioBlkReq>().try_into().unwrap();
+        table[0].flags = virtq_desc_flags::NEXT;
+        table[0].next = 1;
+
+        for (j, seg) in req.segs.iter().enumerate() {
+            let d = &mut table[j + 1];
+            d.addr = unsafe { &(*seg.data).data } as *const _ as u64;
+            d.len = BSIZE.try_into().unwrap();
+            d.flags = if req.type_ == VIRTIO_BLK_T_OUT {
+                0
+            } else {
+                virtq_desc_flags::WRITE // device writes the block
+            };
+            d.flags |= virtq_desc_flags::NEXT;
+            d.next = (j + 2).try_into().unwrap();
+        }
+        let mut n = req.segs.len() + 1;
+
+        if req.type_ == VIRTIO_BLK_T_DISCARD {
+            let range = &mut self.ranges[i];
+            range.sector = sector;
+            range.num_sectors = req.nblocks * (BSIZE / 512) as u32;
+            range.flags = 0;
+            table[n].addr = range as *mut _ as u64;
+            table[n].len = core::mem::size_of::<VirtioBlkDiscard>().try_into().unwrap();
+            table[n].flags = virtq_desc_flags::NEXT;
+            table[n].next = (n + 1).try_into().unwrap();
+            n += 1;
+        }
 
-        // the spec's Section 5.2 says that legacy block operations use
-        // three descriptors: one for type/reserved/sector, one for the
-        // data, one for a 1-byte status result.
+        self.info[i].status = 0xff; // device writes 0 on success
+        table[n].addr = &mut self.info[i].status as *mut _ as u64;
+        table[n].len = 1;
+        table[n].flags = virtq_desc_flags::WRITE; // device write the status
+        table[n].next = 0;
 
-        // allocate the three descriptors.
-        let mut idx: [usize; 3] = [0; 3];
-        loop {
-            if guard.alloc3_desc(&mut idx).is_ok() {
-                break;
-            }
-            guard = p.sleep(&guard.free[0] as *const _ as usize, guard);
-        }
+        let desc = &mut self.vq.desc[i];
+        desc.addr = table.as_ptr() as u64;
+        desc.len = ((n + 1) * core::mem::size_of::<VirtqDesc>())
+            .try_into()
+            .unwrap();
+        desc.flags = virtq_desc_flags::INDIRECT;
+        desc.next = 0;
 
-        // format the three descriptors.
-        // qemu's virtio-blk.c reads them.
+        // record the request for intr()
+        self.info[i].req.replace(req);
 
-        let buf0 = guard.ops.get_mut(idx[0]).unwrap();
-        buf0.type_ = if write {
-            VIRTIO_BLK_T_OUT // write the disk
-        } else {
-            VIRTIO_BLK_T_IN // read the disk
+        self.vq.push(i);
+    }
+}
+
+impl BlockDevice for Mutex<Disk> {
+    fn start_rw(&self, b: &mut Data, write: bool) -> Result<(), ()> {
+        #[cfg(feature = "crashtest")]
+        if write {
+            crate::crashtest::before_write();
+        }
+
+        let mut guard = self.lock();
+        let seg = Seg {
+            data: b,
+            ahead: None,
         };
-        buf0.reserved = 0;
-        buf0.sector = sector as u64;
-
-        guard.desc[idx[0]].addr = buf0 as *mut _ as u64;
-        guard.desc[idx[0]].len = core::mem::size_of::<VirtioBlkReq>().try_into().unwrap();
-        guard.desc[idx[0]].flags = virtq_desc_flags::NEXT;
-        guard.desc[idx[0]].next = idx[1].try_into().unwrap();
-
-        guard.desc[idx[1]].addr = &b.data as *const _ as u64;
-        guard.desc[idx[1]].len = BSIZE.try_into().unwrap();
-        guard.desc[idx[1]].flags = if write {
-            0
-        } else {
-            virtq_desc_flags::WRITE // device writes b->data
+        guard.enqueue(seg, write).or(Err(()))?;
+        b.disk = true;
+        b.error = false;
+        guard.dispatch(false);
+        Ok(())
+    }
+
+    // The caller has set the buffer's disk flag, which intr()
+    // clears; see wait(). Fails if too many requests are waiting
+    // already.
+    fn start(&self, ahead: Ahead) -> Result<(), Ahead> {
+        let mut guard = self.lock();
+        if guard.queue.len() >= MAXQUEUE {
+            return Err(ahead);
+        }
+        let seg = Seg {
+            data: ahead.data(),
+            ahead: Some(ahead),
         };
-        guard.desc[idx[1]].flags |= virtq_desc_flags::NEXT;
-        guard.desc[idx[1]].next = idx[2].try_into().unwrap();
+        if let Err(seg) = guard.enqueue(seg, false) {
+            return Err(seg.ahead.unwrap());
+        }
+        guard.dispatch(false);
+        Ok(())
+    }
 
-        guard.info[idx[0]].status = 0xff; // device writes 0 on success
-        guard.desc[idx[2]].addr = &mut guard.info[idx[0]].status as *mut _ as u64;
-        guard.desc[idx[2]].len = 1;
-        guard.desc[idx[2]].flags = virtq_desc_flags::WRITE; // device write the status
-        guard.desc[idx[2]].next = 0;
+    // intr() sets the buffer's error flag if the device failed
+    // the request.
+    fn wait(&self, data: &Data) -> Result<(), ()> {
+        self.wait_for(&data.disk);
+        if unsafe { core::ptr::read_volatile(&data.error) } {
+            return Err(());
+        }
+        Ok(())
+    }
 
-        // record struct buf for intr()
-        b.disk = true;
-        guard.info[idx[0]].buf.replace(b);
+    // Writes completed before are covered, not just those in the
+    // device's cache.
+    fn flush(&self) {
+        if self.lock().flush {
+            self.command(VIRTIO_BLK_T_FLUSH, 0, 0);
+        }
+    }
 
-        // tell the device the first index in our chain of decriptors.
-        let i = guard.avail.idx as usize % NUM;
-        guard.avail.ring[i] = idx[0].try_into().unwrap();
+    // Does nothing if the device does not support it.
+    fn discard(&self, mut blockno: u32, mut n: u32) -> Result<(), ()> {
+        let (max, capacity, readonly) = {
+            let guard = self.lock();
+            (guard.max_discard, guard.capacity, guard.readonly)
+        };
+        if blockno.saturating_add(n) > capacity {
+            return Err(());
+        }
+        if max == 0 || readonly {
+            return Ok(());
+        }
+        while n > 0 {
+            let m = n.min(max);
+            self.command(VIRTIO_BLK_T_DISCARD, blockno, m);
+            blockno += m;
+            n -= m;
+        }
+        Ok(())
+    }
 
-        fence(Ordering::SeqCst);
+    fn capacity(&self) -> u32 {
+        self.lock().capacity
+    }
 
-        // tell the device another avail ring entry is available.
-        guard.avail.idx += 1; // not % NUM ...
+    fn readonly(&self) -> bool {
+        self.lock().readonly
+    }
 
-        fence(Ordering::SeqCst);
+    fn plug(&self) {
+        self.lock().plugged += 1;
+    }
 
-        unsafe {
-            VirtioMMIO::QueueNotify.write(0); // value is queue number
-        }
+    fn unplug(&self) {
+        let mut guard = self.lock();
+        guard.plugged -= 1;
+        guard.dispatch(false);
+    }
+}
 
-        // wait for intr() to say request has finished.
-        while let Some(ref b) = guard.info[idx[0]].buf {
-            guard = p.sleep(b as *const _ as usize, guard);
+impl Mutex<Disk> {
+    // Wait until intr() clears busy.
+    fn wait_for(&self, busy: &bool) {
+        let mut guard = self.lock();
+        let p = CPUS.my_proc().unwrap();
+        // a request waited for must not be held back.
+        guard.dispatch(true);
+        while unsafe { core::ptr::read_volatile(busy) } {
+            guard = p.sleep(busy as *const _ as usize, guard);
         }
+    }
 
-        guard.free_chain(idx[0]);
-        guard.info[idx[0]].buf.take()
+    // Queue a request without blocks, and wait for it.
+    fn command(&self, type_: u32, blockno: u32, nblocks: u32) {
+        let mut busy = true;
+        self.lock().queue.push_back(Req {
+            type_,
+            blockno,
+            nblocks,
+            segs: Vec::new(),
+            busy: &mut busy,
+        });
+        self.wait_for(&busy);
     }
 
     pub fn intr(&self) {
         let mut guard = self.lock();
-        // the device won't raise another interrupt until we tell it
-        // we've seen this interrupt, which the following line does.
-        // this may race with the device writing new entries to
-        // the "used" ring, in which case we may process the new
-        // completion entries in this interrupt, and have nothing to do
-        // in the next interrup, whish is harmless.
-        let intr_stat = VirtioMMIO::InterruptStatus.read();
-        unsafe {
-            VirtioMMIO::InterruptAck.write(intr_stat & 0x3);
-        }
-
-        fence(Ordering::SeqCst);
+        virtio::ack(guard.base);
 
-        // the device increments disk.used.idx when it
-        // adds an entry to the used ring.
-        while guard.used_idx != guard.used.idx {
-            fence(Ordering::SeqCst);
-            let id = guard.used.ring[guard.used_idx as usize % NUM].id as usize;
+        while let Some((id, _)) = guard.vq.pop() {
+            // an I/O error, or a request the device does not support
+            let failed = guard.info[id].status != 0;
 
-            if guard.info[id].status != 0 {
-                panic!("disk intr status");
+            let req = guard.info[id].req.take().unwrap();
+            guard.vq.free_desc(id);
+            if failed && req.segs.is_empty() {
+                println!("virtio disk: request type {} failed", req.type_);
             }
+            for seg in req.segs {
+                // disk is done with buf
+                unsafe { (*seg.data).error = failed };
+                let disk = unsafe { &mut (*seg.data).disk };
+                unsafe { core::ptr::write_volatile(disk, false) };
+                PROCS.wakeup(disk as *mut _ as usize);
+                drop(seg.ahead); // a buffer read ahead may be recycled now
+            }
+            if !req.busy.is_null() {
+                unsafe { core::ptr::write_volatile(req.busy, false) };
+                PROCS.wakeup(req.busy as usize);
+            }
+        }
 
-            let b = guard.info[id].buf.as_mut().unwrap();
-            b.disk = false; // disk is done with buf
-            PROCS.wakeup(&b as *const _ as usize);
+        // the freed descriptors can take queued requests.
+        guard.dispatch(false);
+    }
+}
 
-            guard.used_idx += 1;
+// Handle an interrupt from irq if it is a disk's; returns
+// false if it is not.
+pub fn intr(irq: u32) -> bool {
+    let slot = irq.wrapping_sub(VIRTIO0_IRQ) as usize;
+    match SLOTS.get() {
+        Some(slots) if slots.contains(&slot) => {
+            DISKS[slot].intr();
+            true
         }
+        _ => false,
     }
 }
 
 pub fn init() {
-    unsafe {
-        DISK.get_mut().init();
+    let mut slots = Vec::new();
+    for (slot, disk) in DISKS.iter().enumerate() {
+        if virtio::probe(virtio(slot), VIRTIO_ID_BLOCK) {
+            unsafe {
+                disk.get_mut().init(virtio(slot));
+            }
+            match blockdev::register(disk) {
+                Some(dev) => {
+                    println!("virtio disk {}: slot {}", dev, slot);
+                    slots.push(slot);
+                }
+                None => println!("virtio disk: slot {}: too many block devices", slot),
+            }
+        }
     }
+    SLOTS.set(slots).unwrap();
 }
@@ -0,0 +1,244 @@
+use crate::{
+    array,
+    memlayout::{virtio, NVIRTIO, VIRTIO0_IRQ},
+    net,
+    spinlock::Mutex,
+    sync::OnceLock,
+    virtio::{self, virtq_desc_flags, VirtioMMIO, Virtq, NUM, VIRTIO_ID_NET},
+};
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+
+//
+// driver for qemu's virtio network device, over the mmio
+// interface and virtqueues of virtio.rs.
+//
+// qemu ... -netdev user,id=net0 -device virtio-net-device,netdev=net0,bus=virtio-mmio-bus.1
+//
+// the first network device found in the virtio slots is used.
+// a socket backend instead of user, e.g.
+// -netdev socket,id=net0,listen=:1234 and -netdev socket,id=net0,connect=:1234,
+// links two octox guests without any network on the host.
+//
+
+pub static NET: Mutex<Net> = Mutex::new(Net::new(), "virtio_net");
+
+// slot of the device, if there is one.
+static SLOT: OnceLock<usize> = OnceLock::new();
+
+// queues
+const RXQ: u32 = 0;
+const TXQ: u32 = 1;
+
+// a receive buffer holds the header and an Ethernet frame.
+const BUFSIZE: usize = 2048;
+
+// packets waiting for a transmit descriptor, so that a burst of
+// TCP segments is not lost to a full queue.
+const NBACKLOG: usize = 128;
+
+// Network device feature bits
+mod virtio_net_features {
+    // Device has given MAC address
+    pub(crate) const NET_F_MAC: u32 = 1 << 5;
+}
+
+// offsets in the network device configuration space
+mod virtio_net_config {
+    pub(crate) const MAC: usize = 0;
+}
+
+// the header in front of every packet, from Section 5.1.6 of
+// the spec. all zero here: no offloads are negotiated.
+#[repr(C)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+const HDRLEN: usize = core::mem::size_of::<VirtioNetHdr>();
+
+pub struct Net {
+    base: usize,
+    mac: [u8; net::ETH_ALEN],
+    rx: Virtq,
+    tx: Virtq,
+    // receive buffers, one-for-one with descriptors of rx;
+    // out while the frame in it is handled.
+    rxbufs: [Option<Box<[u8; BUFSIZE]>>; NUM],
+    // packets being sent, one-for-one with descriptors of tx.
+    txbufs: [Option<Vec<u8>>; NUM],
+    backlog: VecDeque<Vec<u8>>,
+}
+
+impl Net {
+    const fn new() -> Self {
+        Self {
+            base: 0,
+            mac: [0; net::ETH_ALEN],
+            rx: Virtq::new(),
+            tx: Virtq::new(),
+            rxbufs: array![None; NUM],
+            txbufs: array![None; NUM],
+            backlog: VecDeque::new(),
+        }
+    }
+
+    unsafe fn init(&mut self, base: usize) {
+        self.base = base;
+
+        let features =
+            virtio::negotiate(base, |features| features & virtio_net_features::NET_F_MAC);
+
+        if features & virtio_net_features::NET_F_MAC != 0 {
+            for (i, b) in self.mac.iter_mut().enumerate() {
+                *b = VirtioMMIO::config_u8(base, virtio_net_config::MAC + i);
+            }
+        } else {
+            // a locally administered address of our own
+            self.mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        }
+
+        self.rx.init(base, RXQ);
+        self.tx.init(base, TXQ);
+
+        virtio::ready(base);
+
+        // give the device every receive buffer.
+        for _ in 0..NUM {
+            let d = self.rx.alloc_desc().unwrap();
+            let buf = self.rxbufs[d].insert(Box::new([0; BUFSIZE]));
+            let desc = &mut self.rx.desc[d];
+            desc.addr = buf.as_ptr() as u64;
+            desc.len = BUFSIZE as u32;
+            desc.flags = virtq_desc_flags::WRITE; // device writes the packet
+            self.rx.push(d);
+        }
+        self.rx.notify(base);
+    }
+
+    // give pkt to the device; returns it if no descriptor is free.
+    fn start(&mut self, pkt: Vec<u8>) -> Result<(), Vec<u8>> {
+        let Some(i) = self.tx.alloc_desc() else {
+            return Err(pkt);
+        };
+        let desc = &mut self.tx.desc[i];
+        desc.addr = pkt.as_ptr() as u64;
+        desc.len = pkt.len() as u32;
+        desc.flags = 0; // device reads the packet
+        self.txbufs[i].replace(pkt);
+        self.tx.push(i);
+        Ok(())
+    }
+
+    // free the descriptors of packets the device has sent, and
+    // start those of the backlog.
+    fn reclaim(&mut self) {
+        while let Some((i, _)) = self.tx.pop() {
+            self.txbufs[i].take();
+            self.tx.free_desc(i);
+        }
+        let mut started = false;
+        while let Some(pkt) = self.backlog.pop_front() {
+            if let Err(pkt) = self.start(pkt) {
+                self.backlog.push_front(pkt);
+                break;
+            }
+            started = true;
+        }
+        if started {
+            self.tx.notify(self.base);
+        }
+    }
+}
+
+// The MAC address of the device, if there is one.
+pub fn mac() -> Option<[u8; net::ETH_ALEN]> {
+    SLOT.get()?;
+    Some(NET.lock().mac)
+}
+
+// Send an Ethernet frame. Fails if there is no device, or if
+// too many packets are waiting to be sent already.
+pub fn transmit(frame: &[u8]) -> Result<(), ()> {
+    SLOT.get().ok_or(())?;
+    let mut pkt = vec![0u8; HDRLEN + frame.len()];
+    pkt[HDRLEN..].copy_from_slice(frame);
+
+    let mut guard = NET.lock();
+    guard.reclaim();
+    if !guard.backlog.is_empty() {
+        if guard.backlog.len() >= NBACKLOG {
+            return Err(());
+        }
+        guard.backlog.push_back(pkt);
+        return Ok(());
+    }
+    if let Err(pkt) = guard.start(pkt) {
+        guard.backlog.push_back(pkt);
+        return Ok(());
+    }
+    let base = guard.base;
+    guard.tx.notify(base);
+    Ok(())
+}
+
+// Handle an interrupt from irq if it is the device's; returns
+// false if it is not.
+pub fn intr(irq: u32) -> bool {
+    if SLOT.get() != Some(&(irq.wrapping_sub(VIRTIO0_IRQ) as usize)) {
+        return false;
+    }
+
+    // take the received frames out, so that handling them
+    // may send packets.
+    let mut received = Vec::new();
+    {
+        let mut guard = NET.lock();
+        virtio::ack(guard.base);
+        guard.reclaim();
+        while let Some((i, len)) = guard.rx.pop() {
+            let buf = guard.rxbufs[i].take().unwrap();
+            received.push((i, buf, len as usize));
+        }
+    }
+
+    for (_, buf, len) in received.iter() {
+        if (HDRLEN..=BUFSIZE).contains(len) {
+            net::receive(&buf[HDRLEN..*len]);
+        }
+    }
+
+    // hand the buffers back.
+    let mut guard = NET.lock();
+    let base = guard.base;
+    for (i, buf, _) in received {
+        guard.rxbufs[i].replace(buf);
+        guard.rx.push(i);
+    }
+    guard.rx.notify(base);
+    true
+}
+
+pub fn init() {
+    for slot in 0..NVIRTIO {
+        if virtio::probe(virtio(slot), VIRTIO_ID_NET) {
+            // intr() takes the device's interrupts from now on.
+            SLOT.set(slot).unwrap();
+            let mut guard = NET.lock();
+            unsafe {
+                guard.init(virtio(slot));
+            }
+            let mac = guard.mac;
+            println!(
+                "virtio net: slot {} mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                slot, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+            );
+            return;
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use crate::defs::{as_bytes, as_bytes_mut};
-use crate::memlayout::{KERNBASE, PHYSTOP, PLIC, TRAMPOLINE, TRAPFLAME, UART0, VIRTIO0};
+#[cfg(feature = "crashtest")]
+use crate::memlayout::TEST;
+use crate::memlayout::{KERNBASE, NVIRTIO, PHYSTOP, PLIC, TRAMPOLINE, TRAPFLAME, UART0, VIRTIO0};
 use crate::proc::PROCS;
 use crate::riscv::{pgroundup, pteflags::*, registers::satp, sfence_vma, PGSHIFT, PGSIZE};
 use crate::sync::OnceLock;
@@ -708,12 +710,21 @@ impl Kvm {
     unsafe fn make(&mut self) {
         self.map(UART0.into(), UART0.into(), PGSIZE, PTE_R | PTE_W);
 
-        // virtio mmio disk interface
-        self.map(VIRTIO0.into(), VIRTIO0.into(), PGSIZE, PTE_R | PTE_W);
+        // virtio mmio interfaces
+        self.map(
+            VIRTIO0.into(),
+            VIRTIO0.into(),
+            NVIRTIO * PGSIZE,
+            PTE_R | PTE_W,
+        );
 
         // PLIC
         self.map(PLIC.into(), PLIC.into(), 0x4000_00, PTE_R | PTE_W);
 
+        // test device, to power off after a crash test
+        #[cfg(feature = "crashtest")]
+        self.map(TEST.into(), TEST.into(), PGSIZE, PTE_R | PTE_W);
+
         // map kernel text executable and read-only.
         self.map(
             KERNBASE.into(),
@@ -0,0 +1,228 @@
+// Consistency checks of a file system image.
+#![allow(dead_code)]
+
+use crate::fsimg::*;
+use mkfs::{fs::*, stat::*};
+use std::collections::VecDeque;
+
+// What a walk over the image found.
+pub struct Scan {
+    pub dinodes: Vec<DInode>,
+    pub parent: Vec<u32>, // directory an inode was first reached from, 0 if unreachable
+    pub refs: Vec<u32>,   // directory entries referring to each inode
+    pub owner: Vec<u32>,  // inode using each block, 0 if none
+    pub errs: Vec<String>,
+}
+
+impl Scan {
+    pub fn itype(&self, inum: u32) -> u16 {
+        u16::from_le(self.dinodes[inum as usize].itype)
+    }
+
+    // Is inum in use with a valid type?
+    pub fn valid(&self, inum: u32) -> bool {
+        inum != 0
+            && (inum as usize) < self.dinodes.len()
+            && self.itype(inum) != IType::None as u16
+            && self.itype(inum) <= IType::Fifo as u16
+    }
+
+    pub fn is_dir(&self, inum: u32) -> bool {
+        self.valid(inum) && self.itype(inum) == IType::Dir as u16
+    }
+}
+
+// Check the image and describe every inconsistency found.
+// The log is expected to be empty or loaded.
+pub fn check(img: &FsImg) -> std::io::Result<Vec<String>> {
+    Ok(scan(img)?.errs)
+}
+
+pub fn scan(img: &FsImg) -> std::io::Result<Scan> {
+    let sb = &img.sb;
+    let size = u32::from_le(sb.size);
+    let ninodes = u32::from_le(sb.ninodes);
+    let datastart = size - u32::from_le(sb.nblocks);
+    let mut s = Scan {
+        dinodes: vec![DInode::default(); ninodes as usize],
+        parent: vec![0; ninodes as usize],
+        refs: vec![0; ninodes as usize],
+        owner: vec![0; size as usize],
+        errs: Vec::new(),
+    };
+
+    for inum in 1..ninodes {
+        img.rinode(inum, &mut s.dinodes[inum as usize])?;
+        let itype = s.itype(inum);
+        if itype > IType::Fifo as u16 {
+            s.errs.push(format!("inode {}: bad type {}", inum, itype));
+        }
+    }
+
+    // Walk the tree from the root, counting the entries that
+    // refer to each inode. "." is not counted; ".." counts
+    // for the parent, and the root's ".." is its own link.
+    let mut queue = VecDeque::new();
+    if !s.is_dir(ROOTINO) {
+        s.errs.push("root inode is not a directory".to_string());
+    } else {
+        s.parent[ROOTINO as usize] = ROOTINO;
+        queue.push_back(ROOTINO);
+    }
+    while let Some(dir) = queue.pop_front() {
+        let din = s.dinodes[dir as usize];
+        let parent = s.parent[dir as usize];
+        if !(u32::from_le(din.size) as usize).is_multiple_of(core::mem::size_of::<DirEnt>()) {
+            s.errs.push(format!(
+                "dir {}: size {} not a multiple of an entry",
+                dir,
+                u32::from_le(din.size)
+            ));
+        }
+        let mut dot = false;
+        let mut dotdot = false;
+        for (off, de) in img.dirents(&din)? {
+            let inum = u16::from_le(de.inum) as u32;
+            if inum == 0 {
+                continue;
+            }
+            let name = dirent_name(&de);
+            if !s.valid(inum) {
+                s.errs.push(format!(
+                    "dir {}: entry {:?} at {} refers to free inode {}",
+                    dir, name, off, inum
+                ));
+                continue;
+            }
+            match name.as_str() {
+                "." => {
+                    if off != 0 || inum != dir {
+                        s.errs
+                            .push(format!("dir {}: bad \".\" -> {} at {}", dir, inum, off));
+                    }
+                    dot = true;
+                }
+                ".." => {
+                    if off != core::mem::size_of::<DirEnt>() as u32 || inum != parent {
+                        s.errs.push(format!(
+                            "dir {}: bad \"..\" -> {} at {}, parent {}",
+                            dir, inum, off, parent
+                        ));
+                    }
+                    s.refs[inum as usize] += 1;
+                    dotdot = true;
+                }
+                _ => {
+                    s.refs[inum as usize] += 1;
+                    if s.parent[inum as usize] == 0 {
+                        s.parent[inum as usize] = dir;
+                        if s.is_dir(inum) {
+                            queue.push_back(inum);
+                        }
+                    } else if s.is_dir(inum) {
+                        s.errs.push(format!(
+                            "dir {}: {:?} links to dir {} again",
+                            dir, name, inum
+                        ));
+                    }
+                }
+            }
+        }
+        if !dot || !dotdot {
+            s.errs.push(format!("dir {}: missing \".\" or \"..\"", dir));
+        }
+    }
+
+    // Link counts, and allocated inodes no entry refers to.
+    for inum in 1..ninodes {
+        if !s.valid(inum) {
+            continue;
+        }
+        let nlink = u16::from_le(s.dinodes[inum as usize].nlink) as u32;
+        if s.parent[inum as usize] == 0 {
+            s.errs
+                .push(format!("inode {}: unreachable (nlink {})", inum, nlink));
+        } else if nlink != s.refs[inum as usize] {
+            s.errs.push(format!(
+                "inode {}: nlink {}, {} references",
+                inum, nlink, s.refs[inum as usize]
+            ));
+        }
+    }
+
+    // Blocks: in the data area, referenced once, marked in the bitmap.
+    for inum in 1..ninodes {
+        if !s.valid(inum) {
+            continue;
+        }
+        let din = s.dinodes[inum as usize];
+        let isize = u32::from_le(din.size) as usize;
+        if isize > MAXFILE * BSIZE {
+            s.errs
+                .push(format!("inode {}: size {} too large", inum, isize));
+        }
+        for (bn, addr) in iblocks(img, &din)? {
+            if bn != usize::MAX && bn >= isize.div_ceil(BSIZE) {
+                s.errs
+                    .push(format!("inode {}: block {} past size {}", inum, bn, isize));
+            }
+            if addr < datastart || addr >= size {
+                s.errs
+                    .push(format!("inode {}: block {} out of range", inum, addr));
+                continue;
+            }
+            if s.owner[addr as usize] != 0 {
+                s.errs.push(format!(
+                    "block {}: used by inodes {} and {}",
+                    addr, s.owner[addr as usize], inum
+                ));
+            }
+            s.owner[addr as usize] = inum;
+            if !img.bitmap_get(addr)? {
+                s.errs.push(format!(
+                    "block {}: used by inode {} but free in bitmap",
+                    addr, inum
+                ));
+            }
+        }
+    }
+    for b in 0..size {
+        let used = img.bitmap_get(b)?;
+        if b < datastart && !used {
+            s.errs
+                .push(format!("block {}: metadata block free in bitmap", b));
+        } else if b >= datastart && used && s.owner[b as usize] == 0 {
+            s.errs
+                .push(format!("block {}: marked in bitmap but not used", b));
+        }
+    }
+
+    Ok(s)
+}
+
+// Blocks the inode refers to, with their index in the file;
+// the indirect block itself has index usize::MAX.
+pub fn iblocks(img: &FsImg, din: &DInode) -> std::io::Result<Vec<(usize, u32)>> {
+    let size = u32::from_le(img.sb.size);
+    let datastart = size - u32::from_le(img.sb.nblocks);
+    let mut blocks = Vec::new();
+    for (bn, &addr) in din.addrs[..NDIRECT].iter().enumerate() {
+        if addr != 0 {
+            blocks.push((bn, u32::from_le(addr)));
+        }
+    }
+    let ind = u32::from_le(din.addrs[NDIRECT]);
+    if ind != 0 {
+        blocks.push((usize::MAX, ind));
+        if ind >= datastart && ind < size {
+            let mut indirect = [0u32; NINDIRECT];
+            img.rsect(ind, mkfs_as_bytes_mut(&mut indirect))?;
+            for (i, &addr) in indirect.iter().enumerate() {
+                if addr != 0 {
+                    blocks.push((NDIRECT + i, u32::from_le(addr)));
+                }
+            }
+        }
+    }
+    Ok(blocks)
+}
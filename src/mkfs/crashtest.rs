@@ -0,0 +1,140 @@
+// Crash-consistency test driver.
+//
+// Usage: crashtest kernel fs.img
+//
+// kernel must be built with the crashtest feature. A first boot
+// counts the disk writes of its workload; then, for every crash
+// point n, a copy of fs.img is booted to crash after n writes,
+// booted again to recover, and checked.
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod check;
+mod fsimg;
+use fsimg::*;
+
+// Must match src/kernel/crashtest.rs.
+const MAGIC: &[u8; 8] = b"crashtst";
+const CRASH: u32 = 1;
+const RECOVER: u32 = 2;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Boot the kernel on img and return what it printed.
+fn boot(kernel: &str, img: &Path) -> Result<String, String> {
+    let mut child = Command::new("qemu-system-riscv64")
+        .args([
+            "-machine", "virt", "-bios", "none", "-m", "128M", "-smp", "1",
+        ])
+        .args(["-nographic", "-serial", "mon:stdio"])
+        .args(["-global", "virtio-mmio.force-legacy=false"])
+        .arg("-drive")
+        .arg(format!("file={},if=none,format=raw,id=x0", img.display()))
+        .args([
+            "-device",
+            "virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0",
+        ])
+        .args(["-kernel", kernel])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("qemu: {}", e))?;
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut out = String::new();
+        let _ = stdout.read_to_string(&mut out);
+        out
+    });
+    let start = Instant::now();
+    loop {
+        if child.try_wait().map_err(|e| e.to_string())?.is_some() {
+            break;
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out: {}", reader.join().unwrap()));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(reader.join().unwrap())
+}
+
+// Write the crashtest control words into the boot block of img.
+fn control(img: &Path, mode: u32, n: u32) -> std::io::Result<()> {
+    let mut f = OpenOptions::new().write(true).open(img)?;
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(MAGIC)?;
+    f.write_all(&mode.to_le_bytes())?;
+    f.write_all(&n.to_le_bytes())
+}
+
+// Crash after n writes, recover, and check; returns the problems found.
+fn crash_at(kernel: &str, pristine: &str, work: &Path, n: u32) -> Result<Vec<String>, String> {
+    fs::copy(pristine, work).map_err(|e| e.to_string())?;
+    control(work, CRASH, n).map_err(|e| e.to_string())?;
+    let out = boot(kernel, work)?;
+    if out.contains("not reached") {
+        return Err(format!("crash point {} not reached", n));
+    }
+    control(work, RECOVER, 0).map_err(|e| e.to_string())?;
+    let out = boot(kernel, work)?;
+    if !out.contains("crashtest: recovered") {
+        return Err(format!("recovery failed: {}", out));
+    }
+    let img = FsImg::open(work, false).map_err(|e| e.to_string())?;
+    check::check(&img).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        die("Usage: crashtest kernel fs.img");
+    }
+    let (kernel, pristine) = (&args[1], &args[2]);
+    let work = env::temp_dir().join(format!("crashtest-{}.img", process::id()));
+
+    // Count the writes of a whole run.
+    fs::copy(pristine, &work).unwrap_or_else(|e| die(&e.to_string()));
+    let out = boot(kernel, &work).unwrap_or_else(|e| die(&e));
+    let writes: u32 = out
+        .lines()
+        .find_map(|l| {
+            l.trim()
+                .strip_prefix("crashtest: ")?
+                .strip_suffix(" writes")?
+                .parse()
+                .ok()
+        })
+        .unwrap_or_else(|| die(&format!("no write count: {}", out)));
+    println!("crashtest: {} writes", writes);
+
+    let mut failed = 0;
+    for n in 0..writes {
+        match crash_at(kernel, pristine, &work, n) {
+            Ok(errs) if errs.is_empty() => println!("crash point {}: ok", n),
+            Ok(errs) => {
+                failed += 1;
+                println!("crash point {}: FAILED", n);
+                for e in errs {
+                    println!("  {}", e);
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                println!("crash point {}: FAILED: {}", n, e);
+            }
+        }
+    }
+    let _ = fs::remove_file(&work);
+
+    println!("crashtest: {} of {} crash points failed", failed, writes);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
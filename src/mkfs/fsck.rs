@@ -0,0 +1,275 @@
+// Check a file system image, and optionally repair it.
+//
+// Usage: fsck [-y] fs.img
+//
+// A committed transaction left in the log is replayed first: in
+// memory for checking, and on disk with -y. With -y, fsck then
+// clears entries that refer to free inodes, fixes "." and "..",
+// frees unlinked inodes, moves other unreachable inodes into
+// /lost+found, fixes link counts and block pointers, and rebuilds
+// the free bit map from the blocks in use.
+use mkfs::{fs::*, stat::*};
+use std::env;
+use std::process;
+
+mod check;
+mod fsimg;
+use check::*;
+use fsimg::*;
+
+const LOST_FOUND: &str = "lost+found";
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let (repair, path) = match args.len() {
+        2 => (false, &args[1]),
+        3 if args[1] == "-y" => (true, &args[2]),
+        _ => die("Usage: fsck [-y] fs.img"),
+    };
+
+    let mut img = FsImg::open(path, repair).unwrap_or_else(|e| die(&format!("{}: {}", path, e)));
+    match img.load_log()? {
+        LogState::Empty => {}
+        LogState::Committed { seq, n } => {
+            println!("log: replaying transaction {} ({} blocks)", seq, n)
+        }
+        LogState::Torn(why) => println!("log: {}, not replaying", why),
+    }
+    if repair {
+        img.install_log()?;
+    }
+
+    let errs = check(&img)?;
+    for e in errs.iter() {
+        println!("{}", e);
+    }
+    if errs.is_empty() {
+        println!("fsck: {}: clean", path);
+        return Ok(());
+    }
+    if !repair {
+        println!("fsck: {}: {} problems", path, errs.len());
+        process::exit(1);
+    }
+
+    // the bit map has to be right before anything is allocated.
+    fix_entries(&mut img)?;
+    fix_inodes(&mut img)?;
+    fix_bitmap(&mut img)?;
+    while fix_orphans(&mut img)? {}
+    fix_inodes(&mut img)?;
+    fix_bitmap(&mut img)?;
+
+    let errs = check(&img)?;
+    for e in errs.iter() {
+        println!("not repaired: {}", e);
+    }
+    if !errs.is_empty() {
+        process::exit(1);
+    }
+    println!("fsck: {}: repaired", path);
+    Ok(())
+}
+
+fn write_dirent(img: &mut FsImg, dir: u32, off: u32, inum: u32, name: &str) -> std::io::Result<()> {
+    let mut de = DirEnt {
+        inum: (inum as u16).to_le(),
+        ..Default::default()
+    };
+    de.name[..name.len()].copy_from_slice(name.as_bytes());
+    img.iwrite(dir, off as usize, mkfs_as_bytes(&de))
+}
+
+// Free the inode; its blocks are freed by fix_bitmap().
+fn clear_inode(img: &mut FsImg, inum: u32) -> std::io::Result<()> {
+    img.winode(inum, &Default::default())
+}
+
+// Clear inodes of bad types and entries that refer to free inodes,
+// and point "." and ".." of reachable directories where they belong.
+fn fix_entries(img: &mut FsImg) -> std::io::Result<()> {
+    let s = scan(img)?;
+    for inum in 1..s.dinodes.len() as u32 {
+        if s.itype(inum) > IType::Fifo as u16 {
+            println!("inode {}: cleared", inum);
+            clear_inode(img, inum)?;
+        }
+    }
+    let s = scan(img)?;
+    for dir in 1..s.dinodes.len() as u32 {
+        if !s.is_dir(dir) || s.parent[dir as usize] == 0 {
+            continue;
+        }
+        for (off, de) in img.dirents(&s.dinodes[dir as usize])? {
+            let inum = u16::from_le(de.inum) as u32;
+            let name = dirent_name(&de);
+            if off == 0 && (inum != dir || name != ".") {
+                println!("dir {}: \".\" fixed", dir);
+                write_dirent(img, dir, off, dir, ".")?;
+            } else if off == core::mem::size_of::<DirEnt>() as u32
+                && (inum != s.parent[dir as usize] || name != "..")
+            {
+                println!("dir {}: \"..\" fixed", dir);
+                write_dirent(img, dir, off, s.parent[dir as usize], "..")?;
+            } else if inum != 0 && off > core::mem::size_of::<DirEnt>() as u32 {
+                let bad = !s.valid(inum)
+                    || name == "."
+                    || name == ".."
+                    || (s.is_dir(inum) && s.parent[inum as usize] != dir);
+                if bad {
+                    println!("dir {}: entry {:?} -> {} removed", dir, name, inum);
+                    write_dirent(img, dir, off, 0, "")?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Free unreachable inodes nobody linked, and link the others into
+// /lost+found as #inum. Only inodes that no unreachable directory
+// refers to are handled, so subtrees are kept whole; returns true
+// if there may be more to do.
+fn fix_orphans(img: &mut FsImg) -> std::io::Result<bool> {
+    let s = scan(img)?;
+    let n = s.dinodes.len() as u32;
+    let unreachable: Vec<u32> = (1..n)
+        .filter(|&inum| s.valid(inum) && s.parent[inum as usize] == 0)
+        .collect();
+    if unreachable.is_empty() {
+        return Ok(false);
+    }
+    let mut claimed = vec![false; n as usize];
+    for &dir in unreachable.iter().filter(|&&inum| s.is_dir(inum)) {
+        for (_, de) in img.dirents(&s.dinodes[dir as usize])? {
+            let inum = u16::from_le(de.inum) as u32;
+            let name = dirent_name(&de);
+            if inum < n && inum != dir && name != "." && name != ".." {
+                claimed[inum as usize] = true;
+            }
+        }
+    }
+    let mut orphans: Vec<u32> = unreachable
+        .iter()
+        .copied()
+        .filter(|&inum| !claimed[inum as usize])
+        .collect();
+    if orphans.is_empty() {
+        // a cycle of unreachable directories; break it anywhere.
+        orphans.push(unreachable[0]);
+    }
+
+    for inum in orphans {
+        let din = s.dinodes[inum as usize];
+        if !s.is_dir(inum) && din.nlink == 0 {
+            println!("inode {}: unlinked, freed", inum);
+            clear_inode(img, inum)?;
+            continue;
+        }
+        let lf = lost_found(img)?;
+        let name = format!("#{}", inum);
+        println!("inode {}: moved to /{}/{}", inum, LOST_FOUND, name);
+        img.dirlink(lf, &name, inum)?;
+        if s.is_dir(inum) {
+            write_dirent(img, inum, 0, inum, ".")?;
+            write_dirent(img, inum, core::mem::size_of::<DirEnt>() as u32, lf, "..")?;
+        }
+    }
+    Ok(true)
+}
+
+// Find /lost+found, creating it if needed.
+fn lost_found(img: &mut FsImg) -> std::io::Result<u32> {
+    if let Some(inum) = img.dirlookup(ROOTINO, LOST_FOUND)? {
+        return Ok(inum);
+    }
+    let inum = img.ialloc(IType::Dir)?;
+    img.dirlink(inum, ".", inum)?;
+    img.dirlink(inum, "..", ROOTINO)?;
+    img.dirlink(ROOTINO, LOST_FOUND, inum)?;
+    println!("created /{}", LOST_FOUND);
+    Ok(inum)
+}
+
+// Set link counts of reachable inodes to the entries found, and
+// clear pointers to blocks out of range, past the end of the file,
+// or in use by an earlier inode.
+fn fix_inodes(img: &mut FsImg) -> std::io::Result<()> {
+    let s = scan(img)?;
+    let size = u32::from_le(img.sb.size);
+    let datastart = size - u32::from_le(img.sb.nblocks);
+    let mut owner = vec![0u32; size as usize];
+    for inum in 1..s.dinodes.len() as u32 {
+        if !s.valid(inum) {
+            continue;
+        }
+        let mut din = s.dinodes[inum as usize];
+        let mut changed = false;
+        if s.parent[inum as usize] != 0 && u16::from_le(din.nlink) as u32 != s.refs[inum as usize] {
+            println!("inode {}: nlink set to {}", inum, s.refs[inum as usize]);
+            din.nlink = (s.refs[inum as usize] as u16).to_le();
+            changed = true;
+        }
+        if u32::from_le(din.size) as usize > MAXFILE * BSIZE {
+            din.size = ((MAXFILE * BSIZE) as u32).to_le();
+            changed = true;
+        }
+        let nb = (u32::from_le(din.size) as usize).div_ceil(BSIZE);
+        let mut bad = |bn: usize, addr: u32| {
+            let bad = addr < datastart
+                || addr >= size
+                || owner[addr as usize] != 0
+                || (bn != usize::MAX && bn >= nb);
+            if !bad {
+                owner[addr as usize] = inum;
+            } else {
+                println!("inode {}: block {} dropped", inum, addr);
+            }
+            bad
+        };
+        for (bn, addr) in din.addrs[..NDIRECT].iter_mut().enumerate() {
+            if *addr != 0 && bad(bn, u32::from_le(*addr)) {
+                *addr = 0;
+                changed = true;
+            }
+        }
+        let ind = u32::from_le(din.addrs[NDIRECT]);
+        if ind != 0 {
+            if bad(usize::MAX, ind) {
+                din.addrs[NDIRECT] = 0;
+                changed = true;
+            } else {
+                let mut indirect = [0u32; NINDIRECT];
+                img.rsect(ind, mkfs_as_bytes_mut(&mut indirect))?;
+                let mut dropped = false;
+                for (i, addr) in indirect.iter_mut().enumerate() {
+                    if *addr != 0 && bad(NDIRECT + i, u32::from_le(*addr)) {
+                        *addr = 0;
+                        dropped = true;
+                    }
+                }
+                if dropped {
+                    img.wsect(ind, mkfs_as_bytes(&indirect))?;
+                }
+            }
+        }
+        if changed {
+            img.winode(inum, &din)?;
+        }
+    }
+    Ok(())
+}
+
+// Rewrite the free bit map from the blocks in use.
+fn fix_bitmap(img: &mut FsImg) -> std::io::Result<()> {
+    let s = scan(img)?;
+    let size = u32::from_le(img.sb.size);
+    let datastart = size - u32::from_le(img.sb.nblocks);
+    for b in 0..size {
+        let used = b < datastart || s.owner[b as usize] != 0;
+        if img.bitmap_get(b)? != used {
+            img.bitmap_set(b, used)?;
+        }
+    }
+    Ok(())
+}
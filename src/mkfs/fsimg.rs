@@ -0,0 +1,511 @@
+// Access to a file system image from the host,
+// shared by mkfs and the other host tools.
+#![allow(dead_code)]
+
+use mkfs::{defs::*, fs::*, stat::*};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub struct FsImg {
+    pub sb: SuperBlock,
+    img: File,
+    pub freeinode: usize,
+    pub freeblock: usize,
+    // An existing image allocates from the bitmap and the inode
+    // table; a new one counts up, and balloc() writes the bitmap.
+    mapped: bool,
+    // Blocks of a committed transaction found in the log,
+    // read in place of their home copies until installed.
+    logged: HashMap<u32, Vec<u8>>,
+}
+
+// What load_log() found in the log.
+pub enum LogState {
+    Empty,
+    Committed { seq: u64, n: u32 },
+    Torn(String),
+}
+
+impl FsImg {
+    // Create an image laid out as sb describes.
+    pub fn new<P: AsRef<Path>>(sb: SuperBlock, path: P) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            sb,
+            img: OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .read(true)
+                .open(path)?,
+            freeinode: 1,
+            // the first free block that we can allocate
+            freeblock: (u32::from_le(sb.size) - u32::from_le(sb.nblocks)) as usize,
+            mapped: false,
+            logged: HashMap::new(),
+        })
+    }
+
+    // Open an existing image; writable if write is true.
+    pub fn open<P: AsRef<Path>>(path: P, write: bool) -> Result<Self, std::io::Error> {
+        let img = OpenOptions::new().read(true).write(write).open(path)?;
+        let mut buf = [0u8; BSIZE];
+        let mut fsimg = Self {
+            sb: SuperBlock {
+                magic: 0,
+                size: 0,
+                nblocks: 0,
+                ninodes: 0,
+                nlog: 0,
+                logstart: 0,
+                inodestart: 0,
+                bmapstart: 0,
+            },
+            img,
+            freeinode: 0,
+            freeblock: 0,
+            mapped: true,
+            logged: HashMap::new(),
+        };
+        fsimg.rsect(1, &mut buf)?;
+        let (head, sb_slice, _tail) = unsafe { buf.align_to::<SuperBlock>() };
+        assert!(head.is_empty(), "Data was not aligned");
+        fsimg.sb = sb_slice[0];
+        if u32::from_le(fsimg.sb.magic) != FSMAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not a file system image",
+            ));
+        }
+        Ok(fsimg)
+    }
+
+    pub fn wsect(&mut self, sec: u32, buf: &[u8]) -> Result<(), std::io::Error> {
+        self.logged.remove(&sec);
+        let mut writer = BufWriter::new(&mut self.img);
+        if writer.seek(SeekFrom::Start((sec as usize * BSIZE) as u64))?
+            != (sec as usize * BSIZE) as u64
+        {
+            die("seek");
+        }
+        if writer.write(buf)? != BSIZE {
+            die("write");
+        }
+        writer.flush()
+    }
+
+    pub fn winode(&mut self, inum: u32, ip: &DInode) -> Result<(), std::io::Error> {
+        let mut buf = [0u8; BSIZE];
+        let bn = self.sb.iblock(inum);
+        self.rsect(bn, &mut buf)?;
+        let (head, dinode_slice, _tail) = unsafe { buf.align_to_mut::<DInode>() };
+        assert!(head.is_empty(), "Data was not aligned");
+        *dinode_slice.get_mut(inum as usize % IPB).unwrap() = *ip;
+        self.wsect(bn, &buf)
+    }
+
+    pub fn rinode(&self, inum: u32, ip: &mut DInode) -> Result<(), std::io::Error> {
+        let mut buf = [0u8; BSIZE];
+        let bn = self.sb.iblock(inum);
+        self.rsect(bn, &mut buf)?;
+        let (head, dinode_slice, _tail) = unsafe { buf.align_to::<DInode>() };
+        assert!(head.is_empty(), "Data was not aligned");
+        *ip = *dinode_slice.get(inum as usize % IPB).unwrap();
+        Ok(())
+    }
+
+    pub fn rsect(&self, sec: u32, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        if let Some(data) = self.logged.get(&sec) {
+            buf.copy_from_slice(data);
+            return Ok(());
+        }
+        let mut reader = BufReader::new(&self.img);
+        if reader.seek(SeekFrom::Start((sec as usize * BSIZE) as u64))?
+            != (sec as usize * BSIZE) as u64
+        {
+            die("seek");
+        }
+        if reader.read(buf)? != BSIZE {
+            die("read");
+        }
+        Ok(())
+    }
+
+    pub fn ialloc(&mut self, itype: IType) -> Result<u32, std::io::Error> {
+        let inum = if self.mapped {
+            self.ifree_find()?
+        } else if self.freeinode < u32::from_le(self.sb.ninodes) as usize {
+            self.freeinode += 1;
+            self.freeinode as u32 - 1
+        } else {
+            return Err(std::io::Error::other("out of inodes"));
+        };
+        let din = DInode {
+            itype: (itype as u16).to_le(),
+            nlink: 1u16.to_le(),
+            size: 0,
+            ..Default::default()
+        };
+        self.winode(inum, &din)?;
+        Ok(inum)
+    }
+
+    pub fn balloc(&mut self, used: usize) -> Result<(), std::io::Error> {
+        println!("balloc: first {} blocks have benen allocated", used);
+        assert!(used <= u32::from_le(self.sb.size) as usize);
+        for start in (0..used).step_by(BSIZE * 8) {
+            let mut buf = [0u8; BSIZE];
+            for i in 0..std::cmp::min(used - start, BSIZE * 8) {
+                buf[i / 8] |= 0x1 << (i % 8);
+            }
+            let bn = self.sb.bblock(start as u32);
+            println!("balloc: write bitmap block at sector {}", bn);
+            self.wsect(bn, &buf)?;
+        }
+        Ok(())
+    }
+
+    pub fn iappend(&mut self, inum: u32, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut din: DInode = Default::default();
+        self.rinode(inum, &mut din)?;
+        println!(
+            "append inum {} at off {} sz {}",
+            inum,
+            u32::from_le(din.size),
+            data.len()
+        );
+        self.iwrite(inum, u32::from_le(din.size) as usize, data)
+    }
+
+    // Write data to the inode at off, allocating blocks as needed.
+    pub fn iwrite(&mut self, inum: u32, mut off: usize, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut din: DInode = Default::default();
+        let mut buf = [0u8; BSIZE];
+        let mut indirect = [0u32; NINDIRECT];
+        let mut x: u32;
+        let mut p: usize = 0;
+        let mut n = data.len();
+
+        self.rinode(inum, &mut din)?;
+        while n > 0 {
+            let fbn = off / BSIZE;
+            assert!(fbn < MAXFILE);
+            if fbn < NDIRECT {
+                if u32::from_le(din.addrs[fbn]) == 0 {
+                    din.addrs[fbn] = self.alloc_block()?.to_le();
+                }
+                x = u32::from_le(din.addrs[fbn]);
+            } else {
+                if u32::from_le(din.addrs[NDIRECT]) == 0 {
+                    din.addrs[NDIRECT] = self.alloc_block()?.to_le();
+                }
+                self.rsect(
+                    u32::from_le(din.addrs[NDIRECT]),
+                    mkfs_as_bytes_mut(&mut indirect),
+                )?;
+                if u32::from_le(indirect[fbn - NDIRECT]) == 0 {
+                    indirect[fbn - NDIRECT] = self.alloc_block()?.to_le();
+                    self.wsect(u32::from_le(din.addrs[NDIRECT]), mkfs_as_bytes(&indirect))?;
+                }
+                x = u32::from_le(indirect[fbn - NDIRECT]);
+            }
+            let n1 = std::cmp::min(n, (fbn + 1) * BSIZE - off);
+            self.rsect(x, &mut buf)?;
+            buf[off - (fbn * BSIZE)..(off - (fbn * BSIZE) + n1)]
+                .copy_from_slice(&data[p..(p + n1)]);
+            self.wsect(x, &buf)?;
+            n -= n1;
+            off += n1;
+            p += n1;
+        }
+        if off > u32::from_le(din.size) as usize {
+            din.size = (off as u32).to_le();
+        }
+        self.winode(inum, &din)
+    }
+
+    // Allocate a data block.
+    pub fn alloc_block(&mut self) -> Result<u32, std::io::Error> {
+        if !self.mapped {
+            if self.freeblock >= u32::from_le(self.sb.size) as usize {
+                return Err(std::io::Error::other("out of blocks"));
+            }
+            self.freeblock += 1;
+            return Ok(self.freeblock as u32 - 1);
+        }
+        let size = u32::from_le(self.sb.size);
+        for b in size - u32::from_le(self.sb.nblocks)..size {
+            if !self.bitmap_get(b)? {
+                self.bitmap_set(b, true)?;
+                self.wsect(b, &[0u8; BSIZE])?;
+                return Ok(b);
+            }
+        }
+        Err(std::io::Error::other("out of blocks"))
+    }
+
+    // Find a free inode in the inode table.
+    fn ifree_find(&self) -> Result<u32, std::io::Error> {
+        let mut din: DInode = Default::default();
+        for inum in 1..u32::from_le(self.sb.ninodes) {
+            self.rinode(inum, &mut din)?;
+            if din.itype == 0 {
+                return Ok(inum);
+            }
+        }
+        Err(std::io::Error::other("out of inodes"))
+    }
+
+    // Disk address of the nth block of the inode, 0 for a hole.
+    pub fn bmap(&self, din: &DInode, bn: usize) -> Result<u32, std::io::Error> {
+        if bn < NDIRECT {
+            return Ok(u32::from_le(din.addrs[bn]));
+        }
+        let ind = u32::from_le(din.addrs[NDIRECT]);
+        if bn >= MAXFILE || ind == 0 || ind >= u32::from_le(self.sb.size) {
+            return Ok(0);
+        }
+        let mut indirect = [0u32; NINDIRECT];
+        self.rsect(ind, mkfs_as_bytes_mut(&mut indirect))?;
+        Ok(u32::from_le(indirect[bn - NDIRECT]))
+    }
+
+    // Contents of the inode; holes read as zeros.
+    pub fn read_data(&self, din: &DInode) -> Result<Vec<u8>, std::io::Error> {
+        let size = std::cmp::min(u32::from_le(din.size) as usize, MAXFILE * BSIZE);
+        let mut data = vec![0u8; size.div_ceil(BSIZE) * BSIZE];
+        for (bn, chunk) in data.chunks_mut(BSIZE).enumerate() {
+            let addr = self.bmap(din, bn)?;
+            if addr != 0 && addr < u32::from_le(self.sb.size) {
+                self.rsect(addr, chunk)?;
+            }
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    // Entries of the directory inode, with their byte offsets.
+    pub fn dirents(&self, din: &DInode) -> Result<Vec<(u32, DirEnt)>, std::io::Error> {
+        let data = self.read_data(din)?;
+        const DESZ: usize = core::mem::size_of::<DirEnt>();
+        Ok(data
+            .as_chunks::<DESZ>()
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, raw)| {
+                let mut de: DirEnt = Default::default();
+                mkfs_as_bytes_mut(&mut de).copy_from_slice(raw);
+                ((i * DESZ) as u32, de)
+            })
+            .collect())
+    }
+
+    // Add an entry for inum to the directory dir, in the first
+    // free slot or at the end.
+    pub fn dirlink(&mut self, dir: u32, name: &str, inum: u32) -> Result<(), std::io::Error> {
+        assert!(name.len() <= DIRSIZ);
+        let mut din: DInode = Default::default();
+        self.rinode(dir, &mut din)?;
+        let off = self
+            .dirents(&din)?
+            .into_iter()
+            .find(|(_, de)| de.inum == 0)
+            .map_or(u32::from_le(din.size), |(off, _)| off);
+        let mut de = DirEnt {
+            inum: (inum as u16).to_le(),
+            ..Default::default()
+        };
+        de.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.iwrite(dir, off as usize, mkfs_as_bytes(&de))
+    }
+
+    // Look name up in the directory dir.
+    pub fn dirlookup(&self, dir: u32, name: &str) -> Result<Option<u32>, std::io::Error> {
+        let mut din: DInode = Default::default();
+        self.rinode(dir, &mut din)?;
+        Ok(self
+            .dirents(&din)?
+            .into_iter()
+            .find(|(_, de)| de.inum != 0 && dirent_name(de) == name)
+            .map(|(_, de)| u16::from_le(de.inum) as u32))
+    }
+
+    // Remove the entry for name from the directory dir.
+    pub fn dirunlink(&mut self, dir: u32, name: &str) -> Result<(), std::io::Error> {
+        let mut din: DInode = Default::default();
+        self.rinode(dir, &mut din)?;
+        let off = self
+            .dirents(&din)?
+            .into_iter()
+            .find(|(_, de)| de.inum != 0 && dirent_name(de) == name)
+            .map(|(off, _)| off)
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        let de: DirEnt = Default::default();
+        self.iwrite(dir, off as usize, mkfs_as_bytes(&de))
+    }
+
+    // Look up an absolute path.
+    pub fn namei(&self, path: &str) -> Result<Option<u32>, std::io::Error> {
+        let mut inum = ROOTINO;
+        let mut din: DInode = Default::default();
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            self.rinode(inum, &mut din)?;
+            if u16::from_le(din.itype) != IType::Dir as u16 {
+                return Ok(None);
+            }
+            match self.dirlookup(inum, name)? {
+                Some(next) => inum = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(inum))
+    }
+
+    // Look up the parent directory of an absolute path,
+    // and return it with the last element of the path.
+    pub fn nameiparent<'a>(&self, path: &'a str) -> Result<Option<(u32, &'a str)>, std::io::Error> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name.len() > DIRSIZ || name == "." || name == ".." {
+            return Ok(None);
+        }
+        Ok(self.namei(dir)?.map(|inum| (inum, name)))
+    }
+
+    // Free the blocks of the inode and set its size to 0.
+    pub fn itrunc(&mut self, inum: u32) -> Result<(), std::io::Error> {
+        let mut din: DInode = Default::default();
+        self.rinode(inum, &mut din)?;
+        let ind = u32::from_le(din.addrs[NDIRECT]);
+        if ind != 0 {
+            let mut indirect = [0u32; NINDIRECT];
+            self.rsect(ind, mkfs_as_bytes_mut(&mut indirect))?;
+            for &b in indirect.iter().filter(|&&b| b != 0) {
+                self.bitmap_set(u32::from_le(b), false)?;
+            }
+        }
+        for &b in din.addrs.iter().filter(|&&b| b != 0) {
+            self.bitmap_set(u32::from_le(b), false)?;
+        }
+        din.addrs = [0; NDIRECT + 1];
+        din.size = 0;
+        self.winode(inum, &din)
+    }
+
+    // Read the log header and, if it holds a committed transaction,
+    // read its blocks in place of their home copies.
+    pub fn load_log(&mut self) -> Result<LogState, std::io::Error> {
+        let mut buf = [0u8; BSIZE];
+        let start = u32::from_le(self.sb.logstart);
+        self.rsect(start, &mut buf)?;
+        let (head, lh_slice, _tail) = unsafe { buf.align_to::<LogHeader>() };
+        assert!(head.is_empty(), "Data was not aligned");
+        let lh = lh_slice[0];
+        if lh.n == 0 {
+            return Ok(LogState::Empty);
+        }
+        let cap = std::cmp::min(u32::from_le(self.sb.nlog) as usize - 1, LOGMAXBLOCKS);
+        if lh.n as usize > cap {
+            return Ok(LogState::Torn(format!("bad header (n = {})", lh.n)));
+        }
+        if lh.checksum != lh.checksum() {
+            return Ok(LogState::Torn(format!(
+                "torn header of transaction {}",
+                lh.seq
+            )));
+        }
+        let mut logged = HashMap::new();
+        for tail in 0..lh.n {
+            let mut data = vec![0u8; BSIZE];
+            self.rsect(start + tail + 1, &mut data)?;
+            if lh.sums[tail as usize] != lh.block_sum(tail as usize, &data) {
+                return Ok(LogState::Torn(format!(
+                    "torn block {} of transaction {}",
+                    lh.block[tail as usize], lh.seq
+                )));
+            }
+            logged.insert(lh.block[tail as usize], data);
+        }
+        self.logged = logged;
+        Ok(LogState::Committed {
+            seq: lh.seq,
+            n: lh.n,
+        })
+    }
+
+    // Write the blocks loaded by load_log() to their home
+    // locations and clear the log, as the kernel's recovery does.
+    pub fn install_log(&mut self) -> Result<(), std::io::Error> {
+        let logged = std::mem::take(&mut self.logged);
+        for (&b, data) in logged.iter() {
+            self.wsect(b, data)?;
+        }
+        let mut buf = [0u8; BSIZE];
+        let start = u32::from_le(self.sb.logstart);
+        self.rsect(start, &mut buf)?;
+        let (head, lh_slice, _tail) = unsafe { buf.align_to_mut::<LogHeader>() };
+        assert!(head.is_empty(), "Data was not aligned");
+        let lh = &mut lh_slice[0];
+        if lh.n != 0 {
+            lh.n = 0;
+            lh.checksum = lh.checksum();
+            self.wsect(start, &buf)?;
+        }
+        Ok(())
+    }
+
+    // Is block b marked in use in the free bit map?
+    pub fn bitmap_get(&self, b: u32) -> Result<bool, std::io::Error> {
+        let mut buf = [0u8; BSIZE];
+        self.rsect(self.sb.bblock(b), &mut buf)?;
+        let bi = (b % BPB) as usize;
+        Ok(buf[bi / 8] & (1 << (bi % 8)) != 0)
+    }
+
+    // Mark block b in use or free in the free bit map.
+    pub fn bitmap_set(&mut self, b: u32, used: bool) -> Result<(), std::io::Error> {
+        let mut buf = [0u8; BSIZE];
+        let bn = self.sb.bblock(b);
+        self.rsect(bn, &mut buf)?;
+        let bi = (b % BPB) as usize;
+        if used {
+            buf[bi / 8] |= 1 << (bi % 8);
+        } else {
+            buf[bi / 8] &= !(1 << (bi % 8));
+        }
+        self.wsect(bn, &buf)
+    }
+}
+
+pub fn die(str: &str) -> ! {
+    eprintln!("{}", str);
+    std::process::exit(1);
+}
+
+// Name of a directory entry.
+pub fn dirent_name(de: &DirEnt) -> String {
+    let len = de.name.iter().position(|&c| c == 0).unwrap_or(DIRSIZ);
+    String::from_utf8_lossy(&de.name[..len]).into_owned()
+}
+
+// On-disk inode structure for mkfs
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DInode {
+    pub itype: u16,                // File type
+    pub major: u16,                // Major Device Number (T_DEVICE only)
+    pub minor: u16,                // Minor Device Number (T_DEVICE only)
+    pub nlink: u16,                // Number of links to inode in file system
+    pub size: u32,                 // Size of data (bytes)
+    pub addrs: [u32; NDIRECT + 1], // Data block address
+}
+
+pub fn mkfs_as_bytes<T: ?Sized>(refs: &T) -> &[u8] {
+    unsafe { as_bytes(refs) }
+}
+
+pub fn mkfs_as_bytes_mut<T: ?Sized>(refs: &mut T) -> &mut [u8] {
+    unsafe { as_bytes_mut(refs) }
+}
@@ -0,0 +1,213 @@
+// Look at and change a file system image from the host.
+//
+// Usage: fstool fs.img command args...
+//
+//   ls [path]           list a directory
+//   cat path            print a file
+//   get path hostfile   copy a file out of the image
+//   put hostfile path   copy a file into the image, replacing path
+//   rm path             remove a file or an empty directory
+//   mkdir path          create a directory
+//   stat path           print an inode
+//
+// Paths in the image are absolute. A committed transaction left
+// in the log is read in place of the blocks it replaces, and is
+// installed before the image is changed, as the kernel would do.
+use mkfs::{fs::*, stat::*};
+use std::env;
+use std::fs;
+use std::io::Write;
+
+mod fsimg;
+use fsimg::*;
+
+fn usage() -> ! {
+    die("Usage: fstool fs.img ls [path] | cat path | get path hostfile | put hostfile path | rm path | mkdir path | stat path")
+}
+
+fn type_name(itype: u16) -> &'static str {
+    match itype {
+        t if t == IType::Dir as u16 => "dir",
+        t if t == IType::File as u16 => "file",
+        t if t == IType::Device as u16 => "device",
+        t if t == IType::Socket as u16 => "socket",
+        t if t == IType::Fifo as u16 => "fifo",
+        _ => "?",
+    }
+}
+
+fn dinode(img: &FsImg, inum: u32) -> std::io::Result<DInode> {
+    let mut din: DInode = Default::default();
+    img.rinode(inum, &mut din)?;
+    Ok(din)
+}
+
+fn lookup(img: &FsImg, path: &str) -> std::io::Result<(u32, DInode)> {
+    let inum = img
+        .namei(path)?
+        .unwrap_or_else(|| die(&format!("fstool: {}: not found", path)));
+    Ok((inum, dinode(img, inum)?))
+}
+
+fn parent<'a>(img: &FsImg, path: &'a str) -> std::io::Result<(u32, &'a str)> {
+    let (dir, name) = img
+        .nameiparent(path)?
+        .unwrap_or_else(|| die(&format!("fstool: {}: bad path", path)));
+    if u16::from_le(dinode(img, dir)?.itype) != IType::Dir as u16 {
+        die(&format!("fstool: {}: not a directory", path));
+    }
+    Ok((dir, name))
+}
+
+fn ls(img: &FsImg, path: &str) -> std::io::Result<()> {
+    let (inum, din) = lookup(img, path)?;
+    if u16::from_le(din.itype) != IType::Dir as u16 {
+        println!(
+            "{:<14} {:>6} {:>4} {:>8}",
+            path,
+            type_name(u16::from_le(din.itype)),
+            inum,
+            u32::from_le(din.size)
+        );
+        return Ok(());
+    }
+    for (_, de) in img.dirents(&din)? {
+        let inum = u16::from_le(de.inum) as u32;
+        if inum == 0 {
+            continue;
+        }
+        let din = dinode(img, inum)?;
+        println!(
+            "{:<14} {:>6} {:>4} {:>8}",
+            dirent_name(&de),
+            type_name(u16::from_le(din.itype)),
+            inum,
+            u32::from_le(din.size)
+        );
+    }
+    Ok(())
+}
+
+fn read_file(img: &FsImg, path: &str) -> std::io::Result<Vec<u8>> {
+    let (_, din) = lookup(img, path)?;
+    if u16::from_le(din.itype) != IType::File as u16 {
+        die(&format!("fstool: {}: not a file", path));
+    }
+    img.read_data(&din)
+}
+
+fn put(img: &mut FsImg, host: &str, path: &str) -> std::io::Result<()> {
+    let data = fs::read(host)?;
+    if data.len() > MAXFILE * BSIZE {
+        die(&format!("fstool: {}: file too large", host));
+    }
+    let (dir, name) = parent(img, path)?;
+    let inum = match img.dirlookup(dir, name)? {
+        Some(inum) => {
+            if u16::from_le(dinode(img, inum)?.itype) != IType::File as u16 {
+                die(&format!("fstool: {}: not a file", path));
+            }
+            img.itrunc(inum)?;
+            inum
+        }
+        None => {
+            let inum = img.ialloc(IType::File)?;
+            img.dirlink(dir, name, inum)?;
+            inum
+        }
+    };
+    img.iwrite(inum, 0, &data)
+}
+
+fn rm(img: &mut FsImg, path: &str) -> std::io::Result<()> {
+    let (dir, name) = parent(img, path)?;
+    let inum = img
+        .dirlookup(dir, name)?
+        .unwrap_or_else(|| die(&format!("fstool: {}: not found", path)));
+    let mut din = dinode(img, inum)?;
+    if u16::from_le(din.itype) == IType::Dir as u16 {
+        let empty = img
+            .dirents(&din)?
+            .iter()
+            .all(|(_, de)| de.inum == 0 || dirent_name(de) == "." || dirent_name(de) == "..");
+        if !empty {
+            die(&format!("fstool: {}: directory not empty", path));
+        }
+        // drop the ".." link to the parent, and the entry's link.
+        let mut dp = dinode(img, dir)?;
+        dp.nlink = (u16::from_le(dp.nlink) - 1).to_le();
+        img.winode(dir, &dp)?;
+        din.nlink = 1u16.to_le();
+    }
+    img.dirunlink(dir, name)?;
+    din.nlink = (u16::from_le(din.nlink) - 1).to_le();
+    img.winode(inum, &din)?;
+    if din.nlink == 0 {
+        img.itrunc(inum)?;
+        img.winode(inum, &Default::default())?;
+    }
+    Ok(())
+}
+
+fn mkdir(img: &mut FsImg, path: &str) -> std::io::Result<()> {
+    let (dir, name) = parent(img, path)?;
+    if img.dirlookup(dir, name)?.is_some() {
+        die(&format!("fstool: {}: already exists", path));
+    }
+    let inum = img.ialloc(IType::Dir)?;
+    img.dirlink(inum, ".", inum)?;
+    img.dirlink(inum, "..", dir)?;
+    img.dirlink(dir, name, inum)?;
+    let mut dp = dinode(img, dir)?;
+    dp.nlink = (u16::from_le(dp.nlink) + 1).to_le();
+    img.winode(dir, &dp)
+}
+
+fn stat(img: &FsImg, path: &str) -> std::io::Result<()> {
+    let (inum, din) = lookup(img, path)?;
+    let blocks: Vec<u32> = (0..(u32::from_le(din.size) as usize).div_ceil(BSIZE))
+        .map(|bn| img.bmap(&din, bn))
+        .collect::<Result<_, _>>()?;
+    println!("inode:  {}", inum);
+    println!("type:   {}", type_name(u16::from_le(din.itype)));
+    println!("nlink:  {}", u16::from_le(din.nlink));
+    println!("size:   {}", u32::from_le(din.size));
+    if u16::from_le(din.itype) == IType::Device as u16 {
+        println!(
+            "device: {}, {}",
+            u16::from_le(din.major),
+            u16::from_le(din.minor)
+        );
+    }
+    println!("blocks: {:?}", blocks);
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        usage();
+    }
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let write = matches!(args[2], "put" | "rm" | "mkdir");
+    let mut img =
+        FsImg::open(args[1], write).unwrap_or_else(|e| die(&format!("fstool: {}: {}", args[1], e)));
+    if let LogState::Torn(why) = img.load_log()? {
+        eprintln!("fstool: log: {}, not replaying", why);
+    }
+    if write {
+        img.install_log()?;
+    }
+
+    match args[2..] {
+        ["ls"] => ls(&img, "/"),
+        ["ls", path] => ls(&img, path),
+        ["cat", path] => std::io::stdout().write_all(&read_file(&img, path)?),
+        ["get", path, host] => fs::write(host, read_file(&img, path)?),
+        ["put", host, path] => put(&mut img, host, path),
+        ["rm", path] => rm(&mut img, path),
+        ["mkdir", path] => mkdir(&mut img, path),
+        ["stat", path] => stat(&img, path),
+        _ => usage(),
+    }
+}
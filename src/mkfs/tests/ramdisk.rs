@@ -0,0 +1,89 @@
+// The kernel's block device layer and RAM disk, run on the host:
+// blocks of a file system laid out as mkfs does go to the disk
+// and come back through BlockDevice::rw.
+
+use mkfs::{bio::Data, blockdev::BlockDevice, fs::*, ramdisk::RamDisk};
+
+const NBLOCKS: usize = 3 * BPB as usize;
+
+fn ramdisk() -> RamDisk {
+    RamDisk::new(vec![0u8; NBLOCKS * BSIZE].leak())
+}
+
+fn superblock() -> SuperBlock {
+    SuperBlock {
+        magic: FSMAGIC,
+        size: NBLOCKS as u32,
+        nblocks: NBLOCKS as u32 - 20,
+        ninodes: 200,
+        nlog: 10,
+        logstart: 2,
+        inodestart: 12,
+        bmapstart: 17,
+    }
+}
+
+#[test]
+fn blocks_read_back() {
+    let disk = ramdisk();
+    assert_eq!(disk.capacity() as usize, NBLOCKS);
+    for blockno in 0..NBLOCKS as u32 {
+        let mut b = Data::new(1, blockno);
+        b.fill(blockno as u8);
+        disk.rw(&mut b, true).unwrap();
+    }
+    for blockno in (0..NBLOCKS as u32).rev() {
+        let mut b = Data::new(1, blockno);
+        disk.rw(&mut b, false).unwrap();
+        assert!(b.iter().all(|&x| x == blockno as u8), "block {}", blockno);
+    }
+}
+
+#[test]
+fn past_the_end_fails() {
+    let disk = ramdisk();
+    let mut b = Data::new(1, NBLOCKS as u32);
+    assert!(disk.rw(&mut b, false).is_err());
+    assert!(disk.rw(&mut b, true).is_err());
+}
+
+#[test]
+fn superblock_read_back() {
+    let disk = ramdisk();
+    let mut b = Data::new(1, 1);
+    let (head, sbs, _) = unsafe { b.data.align_to_mut::<SuperBlock>() };
+    assert!(head.is_empty());
+    sbs[0] = superblock();
+    disk.rw(&mut b, true).unwrap();
+
+    let mut b = Data::new(1, 1);
+    disk.rw(&mut b, false).unwrap();
+    let (_, sbs, _) = unsafe { b.data.align_to::<SuperBlock>() };
+    assert_eq!(sbs[0].magic, FSMAGIC);
+    assert_eq!(sbs[0].size, NBLOCKS as u32);
+    assert_eq!(sbs[0].bmapstart, 17);
+}
+
+// Each block's bit lands in its own place in the bitmap, with the
+// bitmap spread over several blocks.
+#[test]
+fn bitmap_bits() {
+    let disk = ramdisk();
+    let sb = superblock();
+    let used = [0, 7, 8, BPB - 1, BPB, BPB + 3, 2 * BPB + 100];
+    for &blockno in used.iter() {
+        let mut b = Data::new(1, sb.bblock(blockno));
+        disk.rw(&mut b, false).unwrap();
+        let bi = blockno % BPB;
+        b[(bi / 8) as usize] |= 1 << (bi % 8);
+        disk.rw(&mut b, true).unwrap();
+    }
+    assert_eq!(sb.bblock(BPB + 3), sb.bmapstart + 1);
+    for blockno in 0..NBLOCKS as u32 {
+        let mut b = Data::new(1, sb.bblock(blockno));
+        disk.rw(&mut b, false).unwrap();
+        let bi = blockno % BPB;
+        let set = b[(bi / 8) as usize] & (1 << (bi % 8)) != 0;
+        assert_eq!(set, used.contains(&blockno), "block {}", blockno);
+    }
+}
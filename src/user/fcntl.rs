@@ -0,0 +1 @@
+include!("../kernel/fcntl.rs");
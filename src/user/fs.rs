@@ -0,0 +1,86 @@
+use crate::fcntl::omode;
+use crate::stat::{Dirent, IType};
+use crate::usys::{close, getdents, open};
+use core::mem::size_of;
+
+// Longest name a ReadDir entry can hold.
+pub const MAXNAME: usize = 255;
+
+// An entry of a directory, as returned by ReadDir.
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    ino: u32,
+    itype: IType,
+    len: usize,
+    name: [u8; MAXNAME],
+}
+
+impl DirEntry {
+    pub fn ino(&self) -> u32 {
+        self.ino
+    }
+    pub fn itype(&self) -> IType {
+        self.itype
+    }
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+// Iterator over the entries of a directory.
+// Reads the records of getdents() a buffer at a time,
+// so callers never see the on-disk directory format.
+pub struct ReadDir {
+    fd: usize,
+    buf: [u8; 512],
+    pos: usize,
+    len: usize,
+}
+
+// Open the directory at path for reading its entries.
+pub fn read_dir(path: &str) -> Result<ReadDir, ()> {
+    let fd = open(path, (omode::RDONLY | omode::DIRECTORY) as isize);
+    if fd < 0 {
+        return Err(());
+    }
+    Ok(ReadDir {
+        fd: fd as usize,
+        buf: [0; 512],
+        pos: 0,
+        len: 0,
+    })
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.len {
+            let n = getdents(self.fd, &mut self.buf);
+            if n <= 0 {
+                return None;
+            }
+            self.pos = 0;
+            self.len = n as usize;
+        }
+
+        let rec = unsafe { (self.buf[self.pos..].as_ptr() as *const Dirent).read_unaligned() };
+        let name = self.pos + size_of::<Dirent>();
+        let len = core::cmp::min(rec.namelen as usize, MAXNAME);
+        let mut entry = DirEntry {
+            ino: rec.ino,
+            itype: rec.itype,
+            len,
+            name: [0; MAXNAME],
+        };
+        entry.name[..len].copy_from_slice(&self.buf[name..name + len]);
+        self.pos += rec.reclen as usize;
+        Some(entry)
+    }
+}
+
+impl Drop for ReadDir {
+    fn drop(&mut self) {
+        close(self.fd);
+    }
+}
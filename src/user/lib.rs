@@ -2,6 +2,9 @@
 
 use core::panic;
 
+pub mod fcntl;
+pub mod fs;
+pub mod socket;
 pub mod stat;
 pub mod usys;
 
@@ -0,0 +1,638 @@
+// Created by build.rs
+use crate::socket::*;
+use crate::stat::*;
+use core::arch::asm;
+
+
+pub fn fork() -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") 1,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn exit(xstatus: i32) -> ! {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") xstatus,
+            in("a7") 2,
+            lateout("a0") _ret,
+        );
+    }
+    unreachable!()
+}
+pub fn wait(xstatus: &mut i32) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") xstatus as *mut _ as usize,
+            in("a7") 3,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn pipe(p: &mut [usize]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &p as *const _ as usize,
+            in("a7") 4,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn read(fd: usize, buf: &mut [u8]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a7") 5,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn kill(pid: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") pid,
+            in("a7") 6,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn exec(filename: &str, argv: &[&str]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &filename as *const _ as usize,
+            in("a1") &argv as *const _ as usize,
+            in("a7") 7,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn fstat(fd: usize, st: &mut Stat) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") st as *mut _ as usize,
+            in("a7") 8,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn chdir(dirname: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &dirname as *const _ as usize,
+            in("a7") 9,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn dup(fd: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a7") 10,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn getpid() -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") 11,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn sbrk(n: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") n,
+            in("a7") 12,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn sleep(n: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") n,
+            in("a7") 13,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn uptime() -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") 14,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn open(filename: &str, flags: isize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &filename as *const _ as usize,
+            in("a1") flags,
+            in("a7") 15,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn write(fd: usize, b: &[u8]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &b as *const _ as usize,
+            in("a7") 16,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn mknod(file: &str, mj: usize, mi: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &file as *const _ as usize,
+            in("a1") mj,
+            in("a2") mi,
+            in("a7") 17,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn unlink(file: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &file as *const _ as usize,
+            in("a7") 18,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn link(file1: &str, file2: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &file1 as *const _ as usize,
+            in("a1") &file2 as *const _ as usize,
+            in("a7") 19,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn mkdir(dir: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &dir as *const _ as usize,
+            in("a7") 20,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn close(fd: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a7") 21,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn rename(old: &str, new: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &old as *const _ as usize,
+            in("a1") &new as *const _ as usize,
+            in("a7") 22,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn getdents(fd: usize, buf: &mut [u8]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a7") 23,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &buf as *const _ as usize,
+            in("a7") 24,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn truncate(path: &str, len: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &path as *const _ as usize,
+            in("a1") len,
+            in("a7") 25,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn ftruncate(fd: usize, len: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") len,
+            in("a7") 26,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn fsync(fd: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a7") 27,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn fdatasync(fd: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a7") 28,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn sync() -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") 29,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn fadvise(fd: usize, off: usize, len: usize, advice: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") off,
+            in("a2") len,
+            in("a3") advice,
+            in("a7") 30,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn ioctl(fd: usize, req: usize, arg: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") req,
+            in("a2") arg,
+            in("a7") 31,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn socket(domain: usize, stype: usize, protocol: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") domain,
+            in("a1") stype,
+            in("a2") protocol,
+            in("a7") 32,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn bind(fd: usize, addr: &SockAddr) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") addr as *const _ as usize,
+            in("a7") 33,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn connect(fd: usize, addr: &SockAddr) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") addr as *const _ as usize,
+            in("a7") 34,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn listen(fd: usize, backlog: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") backlog,
+            in("a7") 35,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn accept(fd: usize, addr: &mut SockAddr) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") addr as *mut _ as usize,
+            in("a7") 36,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn send(fd: usize, buf: &[u8], flags: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a2") flags,
+            in("a7") 37,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn sendto(fd: usize, buf: &[u8], flags: usize, addr: &SockAddr) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a2") flags,
+            in("a3") addr as *const _ as usize,
+            in("a7") 38,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn recv(fd: usize, buf: &mut [u8], flags: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a2") flags,
+            in("a7") 39,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn recvfrom(fd: usize, buf: &mut [u8], flags: usize, addr: &mut SockAddr) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a2") flags,
+            in("a3") addr as *mut _ as usize,
+            in("a7") 40,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn shutdown(fd: usize, how: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") how,
+            in("a7") 41,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn setsockopt(fd: usize, level: usize, name: usize, val: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") level,
+            in("a2") name,
+            in("a3") val,
+            in("a7") 42,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn socketpair(domain: usize, stype: usize, sv: &mut [usize]) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") domain,
+            in("a1") stype,
+            in("a2") &sv as *const _ as usize,
+            in("a7") 43,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn sendmsg(fd: usize, buf: &[u8], fds: &[usize], flags: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a2") &fds as *const _ as usize,
+            in("a3") flags,
+            in("a7") 44,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn recvmsg(fd: usize, buf: &mut [u8], fds: &mut [usize], flags: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") &buf as *const _ as usize,
+            in("a2") &fds as *const _ as usize,
+            in("a3") flags,
+            in("a7") 45,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn mkfifo(path: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &path as *const _ as usize,
+            in("a7") 46,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") fd,
+            in("a1") cmd,
+            in("a2") arg,
+            in("a7") 47,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn mount(dev: usize, path: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") dev,
+            in("a1") &path as *const _ as usize,
+            in("a7") 48,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
+pub fn umount(path: &str) -> isize {
+    let _ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") &path as *const _ as usize,
+            in("a7") 49,
+            lateout("a0") _ret,
+        );
+    }
+    _ret
+}
\ No newline at end of file